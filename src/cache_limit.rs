@@ -0,0 +1,298 @@
+//! Crate files cache size limit helpers
+
+use std::fs::{read_dir, remove_file, Metadata};
+use std::io::{ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+use log::{debug, error, info};
+
+use super::file_cache::{crate_file_path_with_suffix, CRATE_MARKER_SUFFIX, CRATE_ZSTD_SUFFIX};
+
+/// Minimum age of the crate files without the completion marker to be evicted
+///
+/// The crate files being written are modified continuously,
+/// so they are never this old.
+const UNMARKED_FILE_GRACE_PERIOD: Duration = Duration::from_secs(3600);
+
+/// Eviction pass request channel (optional)
+static EVICTION_REQUESTS: OnceLock<SyncSender<()>> = OnceLock::new();
+
+/// Cached crate file eligible for eviction
+#[derive(Debug)]
+struct CachedCrateFile {
+    /// Crate file path without the format suffix, or the unmarked file path
+    path: PathBuf,
+    /// Last access time
+    atime: SystemTime,
+    /// Crate file size in bytes
+    size: u64,
+    /// Set for the complete crate files with the completion marker
+    marked: bool,
+}
+
+/// Enables the crate files cache size limit.
+///
+/// Starts the background thread evicting the least recently accessed
+/// crate files when the crates directory size exceeds the limit.
+/// The crate file completion marker modification time is used as
+/// the last access time.
+pub fn cache_limit_init(dir: PathBuf, max_size: u64) {
+    // Eviction pass requests are coalesced while the pass is running.
+    let (sender, receiver) = sync_channel(1);
+
+    if EVICTION_REQUESTS.set(sender).is_err() {
+        return;
+    }
+
+    let thread_proc = move || {
+        for () in receiver {
+            match cache_evict(&dir, max_size) {
+                Ok(0) => (),
+                Ok(count) => info!("cache: evicted {count} least recently used crate files"),
+                Err(e) => error!("cache: failed to evict crate files: {e}"),
+            }
+        }
+    };
+
+    std::thread::Builder::new()
+        .name("cache-eviction".to_owned())
+        .spawn(thread_proc)
+        .expect("failed to spawn the cache eviction thread");
+
+    // Trim the existing cache directory contents at startup.
+    cache_limit_notify();
+}
+
+/// Requests an eviction pass after a new crate file is cached.
+///
+/// Does not block the caller.
+pub fn cache_limit_notify() {
+    if let Some(sender) = EVICTION_REQUESTS.get() {
+        // A full channel means an eviction pass is already pending.
+        let _ = sender.try_send(());
+    }
+}
+
+/// Checks if the crate files cache size limit is enabled.
+#[must_use]
+pub fn cache_limit_enabled() -> bool {
+    EVICTION_REQUESTS.get().is_some()
+}
+
+/// Walks the directory tree collecting the complete crate files,
+/// and the files left without the completion marker for longer than
+/// the grace period, e.g. by crashed writes or older versions.
+///
+/// The files and directories removed concurrently during the walk are skipped.
+/// Returns the total size of all files found.
+fn collect_crate_files(dir: &Path, files: &mut Vec<CachedCrateFile>) -> Result<u64> {
+    let mut total_size = 0;
+
+    let entries = match read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        if metadata.is_dir() {
+            total_size += collect_crate_files(&entry.path(), files)?;
+            continue;
+        }
+
+        total_size += metadata.len();
+
+        let marker_path = entry.path();
+        let Some(path) = marker_path
+            .to_str()
+            .and_then(|path| path.strip_suffix(CRATE_MARKER_SUFFIX))
+            .map(PathBuf::from)
+        else {
+            if let Some(file) = unmarked_crate_file(entry.path(), &metadata) {
+                files.push(file);
+            }
+            continue;
+        };
+
+        let size = [
+            path.clone(),
+            crate_file_path_with_suffix(&path, CRATE_ZSTD_SUFFIX),
+        ]
+        .iter()
+        .filter_map(|path| path.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum();
+
+        files.push(CachedCrateFile {
+            path,
+            atime: metadata.modified()?,
+            size,
+            marked: true,
+        });
+    }
+
+    Ok(total_size)
+}
+
+/// Checks if the file without the completion marker can be evicted.
+///
+/// The crate files with the completion marker are evicted together with it,
+/// while the unmarked ones only after the grace period.
+fn unmarked_crate_file(path: PathBuf, metadata: &Metadata) -> Option<CachedCrateFile> {
+    let crate_path = path
+        .to_str()
+        .and_then(|path| path.strip_suffix(CRATE_ZSTD_SUFFIX))
+        .map_or_else(|| path.clone(), PathBuf::from);
+
+    if crate_file_path_with_suffix(&crate_path, CRATE_MARKER_SUFFIX).exists() {
+        return None;
+    }
+
+    let mtime = metadata.modified().ok()?;
+
+    if mtime.elapsed().unwrap_or_default() < UNMARKED_FILE_GRACE_PERIOD {
+        return None;
+    }
+
+    Some(CachedCrateFile {
+        path,
+        atime: mtime,
+        size: metadata.len(),
+        marked: false,
+    })
+}
+
+/// Removes the file, unless it is already missing.
+fn remove_file_if_exists(path: &Path) -> Result<()> {
+    match remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Evicts the least recently accessed crate files until the total size
+/// of the crates directory is within `max_size` bytes.
+///
+/// Returns the number of crate files evicted.
+fn cache_evict(dir: &Path, max_size: u64) -> Result<usize> {
+    let mut files = Vec::new();
+    let mut total_size = collect_crate_files(dir, &mut files)?;
+
+    if total_size <= max_size {
+        return Ok(0);
+    }
+
+    debug!("cache: crates directory size {total_size} exceeds the limit {max_size}");
+    files.sort_by_key(|file| file.atime);
+
+    let mut count = 0;
+
+    for file in files {
+        if total_size <= max_size {
+            break;
+        }
+
+        if file.marked {
+            // Invalidate the crate file first, so it is never served partially removed.
+            remove_file_if_exists(&crate_file_path_with_suffix(
+                &file.path,
+                CRATE_MARKER_SUFFIX,
+            ))?;
+            remove_file_if_exists(&file.path)?;
+            remove_file_if_exists(&crate_file_path_with_suffix(&file.path, CRATE_ZSTD_SUFFIX))?;
+        } else {
+            remove_file_if_exists(&file.path)?;
+        }
+
+        debug!("cache: evicted crate file {}", file.path.to_string_lossy());
+        total_size = total_size.saturating_sub(file.size);
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, write, File};
+    use std::time::Duration;
+
+    use super::super::test_dir::TestDir;
+    use super::*;
+
+    #[test]
+    fn test_cache_evict() {
+        let dir = TestDir::new("evict");
+        let now = SystemTime::now();
+
+        for (name, age) in [("a", 30), ("b", 10), ("c", 20)] {
+            let path = dir.join(name).join(format!("{name}-1.0.0.crate"));
+            let marker_path = crate_file_path_with_suffix(&path, CRATE_MARKER_SUFFIX);
+
+            create_dir_all(path.parent().unwrap()).unwrap();
+            write(&path, [0; 100]).unwrap();
+            File::create(&marker_path)
+                .unwrap()
+                .set_modified(now - Duration::from_secs(age))
+                .unwrap();
+        }
+
+        // Crate files being written are counted, but not evicted.
+        write(dir.join("d-1.0.0.crate"), [0; 100]).unwrap();
+
+        assert_eq!(cache_evict(&dir, 400).unwrap(), 0);
+        assert_eq!(cache_evict(&dir, 250).unwrap(), 2);
+
+        assert!(!dir.join("a/a-1.0.0.crate").exists());
+        assert!(dir.join("b/b-1.0.0.crate").exists());
+        assert!(!dir.join("c/c-1.0.0.crate.ok").exists());
+        assert!(dir.join("d-1.0.0.crate").exists());
+
+        // The directories removed concurrently are skipped.
+        assert_eq!(cache_evict(&dir.join("missing"), 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_cache_evict_unmarked() {
+        let dir = TestDir::new("evict-unmarked");
+        let now = SystemTime::now();
+
+        // Crate files left without the completion markers alone exceed the limit.
+        for (name, age) in [("a", 7200), ("b", 3), ("c", 5400)] {
+            let path = dir.join(name).join(format!("{name}-1.0.0.crate"));
+            create_dir_all(path.parent().unwrap()).unwrap();
+            let file = File::create(&path).unwrap();
+            file.set_len(100).unwrap();
+            file.set_modified(now - Duration::from_secs(age)).unwrap();
+        }
+
+        let path = dir.join("d").join("d-1.0.0.crate");
+        create_dir_all(path.parent().unwrap()).unwrap();
+        write(&path, [0; 100]).unwrap();
+        File::create(crate_file_path_with_suffix(&path, CRATE_MARKER_SUFFIX))
+            .unwrap()
+            .set_modified(now - Duration::from_secs(10))
+            .unwrap();
+
+        // The oldest unmarked files go first, and the recent ones are kept.
+        assert_eq!(cache_evict(&dir, 200).unwrap(), 2);
+
+        assert!(!dir.join("a/a-1.0.0.crate").exists());
+        assert!(dir.join("b/b-1.0.0.crate").exists());
+        assert!(!dir.join("c/c-1.0.0.crate").exists());
+        assert!(dir.join("d/d-1.0.0.crate").exists());
+
+        assert_eq!(cache_evict(&dir, 200).unwrap(), 0);
+    }
+}
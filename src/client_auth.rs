@@ -0,0 +1,158 @@
+//! Client token authentication helpers
+
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::io::Result;
+use std::path::Path;
+use std::sync::RwLock;
+
+use log::{info, warn};
+
+/// Client token → client label map
+type ClientTokens = HashMap<String, String>;
+
+/// Known client tokens loaded from the tokens file
+///
+/// Not set if the tokens file failed to load.
+static CLIENT_TOKENS: RwLock<Option<ClientTokens>> = RwLock::new(None);
+
+/// Client authentication policy when the client tokens are unavailable
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFailPolicy {
+    /// Reject all requests
+    #[default]
+    Closed,
+    /// Let all requests through unauthenticated
+    Open,
+}
+
+impl AuthFailPolicy {
+    /// Gets the policy name used in the command line options.
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuthFailPolicy::Closed => "closed",
+            AuthFailPolicy::Open => "open",
+        }
+    }
+}
+
+/// Parses the client tokens file contents.
+///
+/// Each non-empty line contains a token and a label separated by whitespace.
+/// Lines starting with `#` are comments.
+#[must_use]
+fn parse_client_tokens(text: &str) -> ClientTokens {
+    let mut tokens = ClientTokens::new();
+
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some((token, label)) = line.split_once(char::is_whitespace) else {
+            warn!("auth: missing client label at line {}", n + 1);
+            continue;
+        };
+
+        tokens.insert(token.to_owned(), label.trim().to_owned());
+    }
+
+    tokens
+}
+
+/// Loads (or reloads) the client tokens from a file.
+///
+/// The previously loaded tokens are discarded if the file can not be read,
+/// leaving the client tokens unavailable.
+pub fn client_tokens_load(path: &Path) -> Result<()> {
+    let tokens = match read_to_string(path) {
+        Ok(text) => parse_client_tokens(&text),
+        Err(e) => {
+            *CLIENT_TOKENS.write().unwrap() = None;
+            return Err(e);
+        }
+    };
+
+    info!("auth: loaded {} client tokens", tokens.len());
+
+    *CLIENT_TOKENS.write().unwrap() = Some(tokens);
+    Ok(())
+}
+
+/// Checks if the client tokens are loaded.
+#[must_use]
+pub fn client_tokens_available() -> bool {
+    CLIENT_TOKENS.read().unwrap().is_some()
+}
+
+/// Compares two byte strings in time independent of their contents.
+#[must_use]
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Looks up the client label by the client token presented.
+///
+/// All known tokens are always compared to avoid leaking timing information.
+#[must_use]
+pub fn client_token_label(token: &str) -> Option<String> {
+    let guard = CLIENT_TOKENS.read().unwrap();
+
+    let mut label = None;
+
+    for (known, known_label) in guard.as_ref()? {
+        if constant_time_eq(known.as_bytes(), token.as_bytes()) {
+            label = Some(known_label.clone());
+        }
+    }
+
+    label
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use super::super::test_dir::TestDir;
+    use super::*;
+
+    #[test]
+    fn test_parse_tokens() {
+        let tokens = parse_client_tokens("# comment\n\nabc team-a\n  def   team b \nghi\n");
+
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens["abc"], "team-a");
+        assert_eq!(tokens["def"], "team b");
+    }
+
+    #[test]
+    fn test_tokens_load() {
+        let dir = TestDir::new("tokens");
+        let path = dir.join("tokens");
+        write(&path, "abc team-a\n").unwrap();
+
+        client_tokens_load(&path).unwrap();
+        assert!(client_tokens_available());
+        assert_eq!(client_token_label("abc").as_deref(), Some("team-a"));
+        assert_eq!(client_token_label("abd"), None);
+
+        // The old tokens are not used after a failed reload.
+        client_tokens_load(&dir.join("missing")).unwrap_err();
+        assert!(!client_tokens_available());
+        assert_eq!(client_token_label("abc"), None);
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+}
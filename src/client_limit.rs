@@ -0,0 +1,74 @@
+//! Per-client concurrent request limit helpers
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+/// Number of requests in progress per client IP address
+///
+/// Only the clients with requests in progress are tracked,
+/// so the map size is bounded by the number of concurrent requests.
+static CLIENT_REQUESTS: Mutex<BTreeMap<IpAddr, usize>> = Mutex::new(BTreeMap::new());
+
+/// Client request slot held while the request is in progress
+#[derive(Debug)]
+pub struct ClientSlot {
+    /// Client IP address
+    addr: IpAddr,
+}
+
+impl ClientSlot {
+    /// Tries to take a request slot for the client, unless it already has
+    /// `max` requests in progress.
+    #[must_use]
+    pub fn acquire(addr: IpAddr, max: usize) -> Option<Self> {
+        let mut requests = CLIENT_REQUESTS.lock().unwrap_or_else(|e| e.into_inner());
+        let count = requests.entry(addr).or_default();
+
+        if *count >= max {
+            return None;
+        }
+
+        *count += 1;
+        Some(ClientSlot { addr })
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        let mut requests = CLIENT_REQUESTS.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some(count) = requests.get_mut(&self.addr) {
+            *count -= 1;
+
+            if *count == 0 {
+                requests.remove(&self.addr);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_client_slots() {
+        let addr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+        let first = ClientSlot::acquire(addr, 2);
+        let second = ClientSlot::acquire(addr, 2);
+
+        assert!(first.is_some());
+        assert!(second.is_some());
+        assert!(ClientSlot::acquire(addr, 2).is_none());
+
+        drop(first);
+        assert!(ClientSlot::acquire(addr, 2).is_some());
+
+        drop(second);
+        assert!(!CLIENT_REQUESTS.lock().unwrap().contains_key(&addr));
+    }
+}
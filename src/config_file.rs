@@ -0,0 +1,220 @@
+//! Configuration file parsing helpers
+//!
+//! The configuration file is a flat TOML table with the keys mirroring
+//! the long command line options, e.g. `cache_dir = "/var/cache/proxy"`
+//! for `--cache-dir /var/cache/proxy`.
+
+use std::ffi::OsString;
+use std::fs::read_to_string;
+use std::path::Path;
+
+/// Configuration file value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigValue {
+    /// Boolean value for a flag option
+    Flag(bool),
+    /// String or number value for an option taking an argument
+    Value(String),
+}
+
+/// Configuration file entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigEntry {
+    /// Key mirroring the long option name
+    pub key: String,
+    /// Option value
+    pub value: ConfigValue,
+}
+
+impl ConfigEntry {
+    /// Gets the long command line option name for the key.
+    #[must_use]
+    pub fn option_name(&self) -> String {
+        format!("--{}", self.key.replace('_', "-"))
+    }
+
+    /// Converts the entry into the equivalent command line arguments.
+    #[must_use]
+    pub fn to_args(&self) -> Vec<OsString> {
+        match &self.value {
+            ConfigValue::Flag(true) => vec![self.option_name().into()],
+            ConfigValue::Flag(false) => Vec::new(),
+            ConfigValue::Value(value) => vec![self.option_name().into(), value.into()],
+        }
+    }
+}
+
+/// Strips the trailing comment from the configuration file line.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+
+    line
+}
+
+/// Parses the TOML basic string contents with escape sequences.
+fn parse_basic_string(s: &str) -> Option<String> {
+    let mut value = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+
+        match chars.next()? {
+            '"' => value.push('"'),
+            '\\' => value.push('\\'),
+            'n' => value.push('\n'),
+            't' => value.push('\t'),
+            'r' => value.push('\r'),
+            _ => return None,
+        }
+    }
+
+    Some(value)
+}
+
+/// Parses the configuration file value.
+fn parse_value(s: &str) -> Result<ConfigValue, String> {
+    if let Some(s) = s.strip_prefix('"') {
+        return s
+            .strip_suffix('"')
+            .and_then(parse_basic_string)
+            .map(ConfigValue::Value)
+            .ok_or_else(|| format!("bad string value: \"{s}"));
+    }
+
+    if let Some(s) = s.strip_prefix('\'') {
+        return s
+            .strip_suffix('\'')
+            .map(|s| ConfigValue::Value(s.to_owned()))
+            .ok_or_else(|| format!("bad string value: '{s}"));
+    }
+
+    match s {
+        "true" => return Ok(ConfigValue::Flag(true)),
+        "false" => return Ok(ConfigValue::Flag(false)),
+        _ => {}
+    }
+
+    // TOML integers may use underscores as digit separators.
+    let number = s.strip_prefix('+').unwrap_or(s).replace('_', "");
+
+    if number.parse::<i64>().is_ok() {
+        Ok(ConfigValue::Value(number))
+    } else {
+        Err(format!("unsupported value: {s}"))
+    }
+}
+
+/// Parses the configuration file contents.
+///
+/// Only the top level keys with string, integer and boolean values
+/// are supported.
+pub fn parse_config(text: &str) -> Result<Vec<ConfigEntry>, String> {
+    let mut entries: Vec<ConfigEntry> = Vec::new();
+
+    for (n, line) in text.lines().enumerate() {
+        let line = strip_comment(line).trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let error = |e: String| format!("line {}: {e}", n + 1);
+
+        if line.starts_with('[') {
+            return Err(error(format!("tables are not supported: {line}")));
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(error(format!("expected key = value: {line}")));
+        };
+
+        let key = key.trim();
+
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(error(format!("bad key: {key}")));
+        }
+
+        if entries.iter().any(|entry| entry.key == key) {
+            return Err(error(format!("duplicate key: {key}")));
+        }
+
+        entries.push(ConfigEntry {
+            key: key.to_owned(),
+            value: parse_value(value.trim()).map_err(error)?,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Reads and parses the configuration file.
+pub fn load_config(path: &Path) -> Result<Vec<ConfigEntry>, String> {
+    let text = read_to_string(path).map_err(|e| e.to_string())?;
+    parse_config(&text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let text = r#"
+            # Proxy server configuration
+            listen = "0.0.0.0:3080"   # all interfaces
+            cache_dir = '/var/cache/crates-io-proxy'
+            cache_ttl = 3_600
+            offline = false
+            emit-sri = true
+            admin_token = "a#b\"c"
+        "#;
+
+        let entries = parse_config(text).unwrap();
+        let args: Vec<OsString> = entries.iter().flat_map(ConfigEntry::to_args).collect();
+
+        assert_eq!(
+            args,
+            [
+                "--listen",
+                "0.0.0.0:3080",
+                "--cache-dir",
+                "/var/cache/crates-io-proxy",
+                "--cache-ttl",
+                "3600",
+                "--emit-sri",
+                "--admin-token",
+                "a#b\"c",
+            ]
+        );
+
+        assert!(parse_config("[server]").is_err());
+        assert!(parse_config("listen").is_err());
+        assert!(parse_config("listen = 0.0.0.0").is_err());
+        assert!(parse_config("listen = \"a\"\nlisten = \"b\"").is_err());
+        assert!(parse_config("cache dir = \"a\"").is_err());
+        assert!(parse_config("admin_token = \"\\x\"").is_err());
+    }
+}
@@ -1,9 +1,32 @@
 //! Sparse registry configuration file helpers
 
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use log::warn;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use super::util::hex;
 use super::{ProxyConfig, CRATES_API_PATH};
 
 /// Registry configuration file endpoint path
-const CONFIG_JSON_ENDPOINT: &str = "config.json";
+pub const CONFIG_JSON_ENDPOINT: &str = "config.json";
+
+/// Initial delay before fetching the upstream registry configuration file
+/// again after a failure
+const UPSTREAM_CONFIG_RETRY_BASE_DELAY: Duration = Duration::from_secs(10);
+
+/// Maximum delay before fetching the upstream registry configuration file
+/// again after repeated failures
+const UPSTREAM_CONFIG_RETRY_MAX_DELAY: Duration = Duration::from_secs(600);
+
+/// Upstream registry configuration file fields
+static UPSTREAM_CONFIG: OnceLock<Map<String, Value>> = OnceLock::new();
+
+/// Last upstream registry configuration file fetch failure time
+/// and the number of consecutive failures
+static UPSTREAM_CONFIG_FAILURES: Mutex<Option<(Instant, u32)>> = Mutex::new(None);
 
 /// Checks for the registry configuration file download endpoint.
 #[must_use]
@@ -11,9 +34,87 @@ pub fn is_config_json_url(index_url: &str) -> bool {
     index_url == CONFIG_JSON_ENDPOINT
 }
 
+/// Computes the delay before fetching the upstream registry configuration file
+/// again after the given number of consecutive failures.
+#[must_use]
+fn upstream_config_retry_delay(failures: u32) -> Duration {
+    UPSTREAM_CONFIG_RETRY_BASE_DELAY
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(UPSTREAM_CONFIG_RETRY_MAX_DELAY)
+}
+
+/// Checks if the upstream registry configuration file should be fetched.
+///
+/// Returns `false` if the file is already cached, or if the last
+/// fetch attempt failed recently.
+#[must_use]
+pub fn upstream_config_json_needs_fetch() -> bool {
+    if UPSTREAM_CONFIG.get().is_some() {
+        return false;
+    }
+
+    let failures = *UPSTREAM_CONFIG_FAILURES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+
+    failures.is_none_or(|(failed_at, failures)| {
+        failed_at.elapsed() >= upstream_config_retry_delay(failures)
+    })
+}
+
+/// Records the failure to fetch the upstream registry configuration file.
+pub fn upstream_config_json_fetch_failed() {
+    let mut failures = UPSTREAM_CONFIG_FAILURES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+
+    let count = failures.map_or(1, |(_, count)| count.saturating_add(1));
+    *failures = Some((Instant::now(), count));
+}
+
+/// Checks if the cached upstream registry configuration file requires authentication.
+#[must_use]
+pub fn upstream_config_json_auth_required() -> bool {
+    UPSTREAM_CONFIG.get().is_some_and(is_auth_required)
+}
+
+/// Checks if the registry configuration file fields declare `auth-required`.
+#[must_use]
+fn is_auth_required(fields: &Map<String, Value>) -> bool {
+    fields.get("auth-required") == Some(&Value::Bool(true))
+}
+
+/// Caches the upstream registry configuration file fields in memory.
+pub fn upstream_config_json_store(json: &str) {
+    match serde_json::from_str(json) {
+        Ok(fields) => {
+            UPSTREAM_CONFIG.get_or_init(|| fields);
+        }
+        Err(e) => {
+            warn!("fetch: invalid upstream registry config file: {e}");
+            upstream_config_json_fetch_failed();
+        }
+    }
+}
+
 /// Dynamically generates the registry configuration file contents.
+///
+/// All the upstream registry configuration file fields are passed
+/// through, except for the crate download URL.
 #[must_use]
 pub(super) fn gen_config_json_file(config: &ProxyConfig) -> String {
+    gen_config_json_with_upstream(config, UPSTREAM_CONFIG.get())
+}
+
+/// Generates the registry configuration file on top of the upstream fields.
+///
+/// The JSON object keys are always emitted in the sorted order,
+/// so that the file contents are stable for the same configuration.
+#[must_use]
+fn gen_config_json_with_upstream(
+    config: &ProxyConfig,
+    upstream: Option<&Map<String, Value>>,
+) -> String {
     // Generate the crate download API URL pointing to this same proxy server.
     let dl_url = config
         .proxy_url
@@ -24,5 +125,97 @@ pub(super) fn gen_config_json_file(config: &ProxyConfig) -> String {
     let dl = dl_url.as_str().trim_end_matches('/');
     let api = config.upstream_url.as_str().trim_end_matches('/');
 
-    format!(r#"{{"dl":"{dl}","api":"{api}"}}"#)
+    // NOTE: `serde_json::Map` keeps the keys sorted.
+    let mut fields = upstream.cloned().unwrap_or_default();
+
+    fields.insert("dl".to_owned(), dl.into());
+    fields.entry("api").or_insert_with(|| api.into());
+
+    // Make Cargo send the client tokens with all requests, if required.
+    if config.client_tokens_file.is_some() {
+        fields.insert("auth-required".to_owned(), true.into());
+    }
+
+    Value::Object(fields).to_string()
+}
+
+/// Computes the HTTP entity tag for the generated registry configuration file.
+#[must_use]
+pub fn config_json_etag(json: &str) -> String {
+    let hash = Sha256::digest(json.as_bytes());
+
+    format!(r#""{}""#, hex(&hash[..16]))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_config_json_stable() {
+        let mut config = ProxyConfig::default();
+
+        let json = gen_config_json_file(&config);
+        assert_eq!(
+            json,
+            r#"{"api":"https://crates.io","dl":"http://localhost:3080/api/v1/crates"}"#
+        );
+        assert_eq!(gen_config_json_file(&config), json);
+
+        config.client_tokens_file = Some(PathBuf::from("tokens"));
+
+        let json = gen_config_json_file(&config);
+        assert_eq!(
+            json,
+            r#"{"api":"https://crates.io","auth-required":true,"dl":"http://localhost:3080/api/v1/crates"}"#
+        );
+        assert_eq!(gen_config_json_file(&config), json);
+    }
+
+    #[test]
+    fn test_config_json_upstream() {
+        let config = ProxyConfig::default();
+
+        let upstream = r#"{"dl":"https://static.example.com/crates","api":"https://example.com",
+            "auth-required":true,"x-extra":[1,2]}"#;
+        let upstream = serde_json::from_str(upstream).unwrap();
+
+        assert_eq!(
+            gen_config_json_with_upstream(&config, Some(&upstream)),
+            r#"{"api":"https://example.com","auth-required":true,"dl":"http://localhost:3080/api/v1/crates","x-extra":[1,2]}"#
+        );
+        assert!(is_auth_required(&upstream));
+
+        let upstream = serde_json::from_str(r#"{"auth-required":false}"#).unwrap();
+        assert!(!is_auth_required(&upstream));
+        assert!(!is_auth_required(&Map::new()));
+    }
+
+    #[test]
+    fn test_upstream_config_retry_delay() {
+        assert_eq!(
+            upstream_config_retry_delay(1),
+            UPSTREAM_CONFIG_RETRY_BASE_DELAY
+        );
+        assert_eq!(
+            upstream_config_retry_delay(3),
+            UPSTREAM_CONFIG_RETRY_BASE_DELAY * 4
+        );
+        assert_eq!(
+            upstream_config_retry_delay(100),
+            UPSTREAM_CONFIG_RETRY_MAX_DELAY
+        );
+    }
+
+    #[test]
+    fn test_config_json_etag() {
+        let etag = config_json_etag(r#"{"dl":"a"}"#);
+
+        assert_eq!(etag.len(), 34);
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+        assert_eq!(config_json_etag(r#"{"dl":"a"}"#), etag);
+        assert_ne!(config_json_etag(r#"{"dl":"b"}"#), etag);
+    }
 }
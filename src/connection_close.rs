@@ -0,0 +1,95 @@
+//! HTTP connection closing response helpers
+
+use std::io::{ErrorKind, Read, Result, Write};
+
+use tiny_http::{Method, Request, Response};
+
+/// HTTP response header asking the client to close the connection
+const CONNECTION_CLOSE_HEADER: &[u8] = b"Connection: close\r\n";
+
+/// Response writer inserting the `Connection: close` header
+/// right after the HTTP status line
+///
+/// NOTE: tiny_http drops the `Connection` headers added to the responses.
+#[derive(Debug)]
+struct ConnectionCloseWriter<W: Write> {
+    /// Raw client connection writer
+    inner: W,
+    /// Whether the header has been written already
+    inserted: bool,
+}
+
+impl<W: Write> ConnectionCloseWriter<W> {
+    /// Wraps the raw client connection writer.
+    fn new(inner: W) -> Self {
+        ConnectionCloseWriter {
+            inner,
+            inserted: false,
+        }
+    }
+}
+
+impl<W: Write> Write for ConnectionCloseWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.inserted {
+            return self.inner.write(buf);
+        }
+
+        // The status line is the first line of the response.
+        let Some(pos) = buf.iter().position(|&b| b == b'\n') else {
+            return self.inner.write(buf);
+        };
+
+        self.inner.write_all(&buf[..=pos])?;
+        self.inner.write_all(CONNECTION_CLOSE_HEADER)?;
+        self.inserted = true;
+
+        Ok(pos + 1)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Sends the response asking the client to close the connection afterwards.
+///
+/// Ignores the errors caused by the client closing the connection early,
+/// same as `Request::respond()` does.
+pub fn respond_and_close<R: Read>(request: Request, response: Response<R>) -> Result<()> {
+    let http_version = request.http_version().clone();
+    let headers = request.headers().to_vec();
+    let is_head = *request.method() == Method::Head;
+
+    let mut writer = ConnectionCloseWriter::new(request.into_writer());
+
+    response
+        .raw_print(&mut writer, http_version, &headers, is_head, None)
+        .and_then(|()| writer.flush())
+        .or_else(|e| match e.kind() {
+            ErrorKind::BrokenPipe
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset => Ok(()),
+            _ => Err(e),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_close_writer() {
+        let mut writer = ConnectionCloseWriter::new(Vec::new());
+
+        write!(writer, "HTTP/1.1 {} OK", 200).unwrap();
+        writer.write_all(b"\r\nContent-Length: 2\r\n\r\n").unwrap();
+        writer.write_all(b"\n\n").unwrap();
+
+        assert_eq!(
+            writer.inner,
+            b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\n\n\n"
+        );
+    }
+}
@@ -0,0 +1,272 @@
+//! Crate file cache storage backends
+
+use std::fmt::Debug;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::PathBuf;
+
+use log::{error, warn};
+use sha2::{Digest, Sha256};
+
+use super::cache_limit::{cache_limit_enabled, cache_limit_notify};
+use super::file_cache::{
+    cache_crate_size, cache_dir_is_writable, cache_fetch_crate, cache_remove_crate,
+    cache_store_crate, cache_touch_crate, CrateFileWriter,
+};
+use super::util::hex;
+use super::CrateInfo;
+
+/// Crate file cache storage backend
+pub trait CrateCache: Debug + Send + Sync {
+    /// Caches the crate package file.
+    fn store(&self, crate_info: &CrateInfo, data: &[u8]);
+
+    /// Fetches the cached crate package file, if present.
+    fn fetch(&self, crate_info: &CrateInfo) -> Option<Vec<u8>>;
+
+    /// Starts caching the crate package file written in parts.
+    fn writer(&self, crate_info: &CrateInfo) -> Option<Box<dyn CrateWriter + '_>>;
+
+    /// Gets the cached crate package file size, if present.
+    fn size(&self, crate_info: &CrateInfo) -> Option<u64> {
+        self.fetch(crate_info).map(|data| data.len() as u64)
+    }
+
+    /// Checks if the cache storage accepts new crate files.
+    fn is_writable(&self) -> bool {
+        true
+    }
+
+    /// Removes all cached package files of the crate.
+    ///
+    /// Returns the file names of the removed crate versions.
+    /// The storage backends unable to list the crate files remove nothing.
+    fn remove(&self, _name: &str) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// Crate package file being cached while it is downloaded
+///
+/// Crate writers dropped without committing discard the written data.
+pub trait CrateWriter: Write {
+    /// Completes caching the crate file after all its data is written.
+    fn commit(self: Box<Self>);
+}
+
+/// Crate file writer buffering the whole file in memory
+///
+/// Used by the storage backends which can not store the crate files in parts.
+#[derive(Debug)]
+pub struct BufferedCrateWriter<'a> {
+    /// Crate file cache storage backend
+    cache: &'a dyn CrateCache,
+    /// Crate being cached
+    crate_info: CrateInfo,
+    /// Crate file data written so far
+    data: Vec<u8>,
+}
+
+impl<'a> BufferedCrateWriter<'a> {
+    /// Creates a new crate file writer storing the crate file in the cache.
+    #[must_use]
+    pub fn new(cache: &'a dyn CrateCache, crate_info: &CrateInfo) -> Self {
+        BufferedCrateWriter {
+            cache,
+            crate_info: crate_info.clone(),
+            data: Vec::new(),
+        }
+    }
+}
+
+impl Write for BufferedCrateWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.data.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl CrateWriter for BufferedCrateWriter<'_> {
+    fn commit(self: Box<Self>) {
+        self.cache.store(&self.crate_info, &self.data);
+    }
+}
+
+impl CrateWriter for CrateFileWriter {
+    fn commit(self: Box<Self>) {
+        match CrateFileWriter::commit(*self) {
+            Ok(()) => cache_limit_notify(),
+            Err(e) => error!("cache: failed to complete crate file: {e}"),
+        }
+    }
+}
+
+/// Crate file download reader caching the data as it is read
+///
+/// The crate file is cached only after all of its `len` bytes are read.
+/// If the expected SHA-256 checksum is known, the last chunk of data is
+/// withheld unless the crate file matches it.
+pub struct CachingReader<'a, R> {
+    /// Crate file data source
+    reader: R,
+    /// Crate file cache writer (dropped on errors)
+    writer: Option<Box<dyn CrateWriter + 'a>>,
+    /// Crate file bytes left to read
+    remaining: usize,
+    /// Crate file data hasher and the expected checksum (optional)
+    checksum: Option<(Sha256, String)>,
+}
+
+impl<'a, R: Read> CachingReader<'a, R> {
+    /// Creates a new caching reader for the crate file of `len` bytes.
+    pub fn new(
+        reader: R,
+        writer: Option<Box<dyn CrateWriter + 'a>>,
+        len: usize,
+        checksum: Option<String>,
+    ) -> Self {
+        CachingReader {
+            reader,
+            writer,
+            remaining: len,
+            checksum: checksum.map(|cksum| (Sha256::new(), cksum)),
+        }
+    }
+}
+
+impl<R: Read> Read for CachingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = self.reader.read(buf).inspect_err(|_| self.writer = None)?;
+
+        if let Some((hasher, _)) = &mut self.checksum {
+            hasher.update(&buf[..len]);
+        }
+
+        self.remaining = self.remaining.saturating_sub(len);
+
+        if self.remaining == 0 {
+            if let Some((hasher, expected)) = self.checksum.take() {
+                let actual = hex(&hasher.finalize());
+
+                if !actual.eq_ignore_ascii_case(&expected) {
+                    self.writer = None;
+                    let msg = format!("crate file checksum mismatch: {actual} != {expected}");
+                    return Err(Error::new(ErrorKind::InvalidData, msg));
+                }
+            }
+        }
+
+        if let Some(writer) = &mut self.writer {
+            if let Err(e) = writer.write_all(&buf[..len]) {
+                warn!("cache: failed to write crate file: {e}");
+                self.writer = None;
+            }
+        }
+
+        if self.remaining == 0 {
+            if let Some(writer) = self.writer.take() {
+                writer.commit();
+            }
+        } else if len == 0 {
+            // Truncated crate files must not be cached.
+            self.writer = None;
+        }
+
+        Ok(len)
+    }
+}
+
+/// Local filesystem crate files cache
+#[derive(Debug)]
+pub struct FileCrateCache {
+    /// Crate files cache directory
+    dir: PathBuf,
+    /// Flush the cached files to the storage device after writing
+    fsync: bool,
+    /// Store the crate files recompressed with zstd
+    recompress: bool,
+}
+
+impl FileCrateCache {
+    /// Creates a new crate files cache in the directory.
+    #[must_use]
+    pub fn new(dir: PathBuf, fsync: bool, recompress: bool) -> Self {
+        FileCrateCache {
+            dir,
+            fsync,
+            recompress,
+        }
+    }
+}
+
+impl CrateCache for FileCrateCache {
+    fn store(&self, crate_info: &CrateInfo, data: &[u8]) {
+        cache_store_crate(&self.dir, crate_info, data, self.fsync, self.recompress);
+        cache_limit_notify();
+    }
+
+    fn fetch(&self, crate_info: &CrateInfo) -> Option<Vec<u8>> {
+        let data = cache_fetch_crate(&self.dir, crate_info)?;
+
+        if cache_limit_enabled() {
+            cache_touch_crate(&self.dir, crate_info);
+        }
+
+        Some(data)
+    }
+
+    fn writer(&self, crate_info: &CrateInfo) -> Option<Box<dyn CrateWriter + '_>> {
+        // The recompression ratio is only known after the whole file is read.
+        if self.recompress {
+            return Some(Box::new(BufferedCrateWriter::new(self, crate_info)));
+        }
+
+        match CrateFileWriter::create(&self.dir, crate_info, self.fsync) {
+            Ok(writer) => Some(Box::new(writer)),
+            Err(e) => {
+                error!("cache: failed to create crate file: {e}");
+                None
+            }
+        }
+    }
+
+    fn size(&self, crate_info: &CrateInfo) -> Option<u64> {
+        cache_crate_size(&self.dir, crate_info)
+    }
+
+    fn is_writable(&self) -> bool {
+        cache_dir_is_writable(&self.dir)
+    }
+
+    fn remove(&self, name: &str) -> Vec<String> {
+        cache_remove_crate(&self.dir, name).unwrap_or_else(|e| {
+            error!("cache: failed to remove crate files: {e}");
+            Vec::new()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{copy, sink};
+
+    use super::*;
+
+    #[test]
+    fn test_caching_reader_checksum() {
+        let data = b"data";
+        let cksum = hex(&Sha256::digest(data));
+
+        let mut reader = CachingReader::new(&data[..], None, data.len(), Some(cksum));
+        assert_eq!(copy(&mut reader, &mut sink()).unwrap(), 4);
+
+        let cksum = hex(&Sha256::digest(b"other"));
+        let mut reader = CachingReader::new(&data[..], None, data.len(), Some(cksum));
+        assert_eq!(
+            copy(&mut reader, &mut sink()).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+}
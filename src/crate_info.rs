@@ -6,6 +6,41 @@ use std::path::PathBuf;
 /// Crate download API endpoint suffix
 const DOWNLOAD_API_ENDPOINT: &str = "/download";
 
+/// Maximum crate name length allowed by crates.io
+const MAX_CRATE_NAME_LEN: usize = 64;
+
+/// Checks if the crate name is made of the characters allowed by the registry.
+///
+/// The crate names become cache file path components, so this also
+/// rules out the path traversal attempts.
+#[must_use]
+pub fn is_valid_crate_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_CRATE_NAME_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Checks if the crate version looks like a semantic version,
+/// e.g. `1.2.3`, `1.0.0-rc.1` or `0.1.0+build.5`.
+#[must_use]
+fn is_valid_crate_version(version: &str) -> bool {
+    let (core, suffix) = version.split_at(version.find(['-', '+']).unwrap_or(version.len()));
+
+    let mut numbers = core.split('.');
+    let core_ok = (0..3).all(|_| {
+        numbers
+            .next()
+            .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+    }) && numbers.next().is_none();
+
+    core_ok
+        && suffix
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'+' | b'.'))
+}
+
 /// Rust crate information structure
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct CrateInfo {
@@ -29,12 +64,32 @@ impl CrateInfo {
         }
     }
 
+    /// Creates a new crate information object, if the crate name
+    /// and version are valid.
+    #[must_use]
+    pub fn try_new(name: &str, version: &str) -> Option<Self> {
+        (is_valid_crate_name(name) && is_valid_crate_version(version))
+            .then(|| CrateInfo::new(name, version))
+    }
+
     /// Gets the crate name.
     #[must_use]
     pub fn name(&self) -> &str {
         &self.name
     }
 
+    /// Gets the crate version.
+    #[must_use]
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Gets the registry index entry name, which is always lowercase.
+    #[must_use]
+    pub fn index_name(&self) -> String {
+        self.name.to_ascii_lowercase()
+    }
+
     /// Extracts crate information from the download API URL path.
     #[must_use]
     pub fn try_from_download_url(url: &str) -> Option<Self> {
@@ -42,7 +97,7 @@ impl CrateInfo {
 
         let mut i = name_version.split('/');
         match (i.next(), i.next(), i.next()) {
-            (Some(name), Some(version), None) => Some(CrateInfo::new(name, version)),
+            (Some(name), Some(version), None) => CrateInfo::try_new(name, version),
             _ => None,
         }
     }
@@ -57,6 +112,16 @@ impl CrateInfo {
         )
     }
 
+    /// Builds the crate download URL (relative) from a template.
+    ///
+    /// The `{crate}` and `{version}` markers are replaced with the crate name and version.
+    #[must_use]
+    pub fn to_templated_url(&self, template: &str) -> String {
+        template
+            .replace("{crate}", &self.name)
+            .replace("{version}", &self.version)
+    }
+
     /// Builds the crate file name for cache storage.
     #[must_use]
     pub fn to_file_name(&self) -> String {
@@ -69,3 +134,51 @@ impl CrateInfo {
         PathBuf::from(self.name()).join(self.to_file_name())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_download_url() {
+        assert_eq!(CrateInfo::try_from_download_url("abc/1.0.0"), None);
+        assert_eq!(CrateInfo::try_from_download_url("a/b/1.0.0/download"), None);
+
+        assert_eq!(
+            CrateInfo::try_from_download_url("abc/1.0.0/download"),
+            Some(CrateInfo::new("abc", "1.0.0"))
+        );
+
+        assert_eq!(
+            CrateInfo::try_from_download_url("a_b-C/1.0.0-rc.1+build.5/download"),
+            Some(CrateInfo::new("a_b-C", "1.0.0-rc.1+build.5"))
+        );
+
+        // Over-long crate names are rejected before touching the filesystem.
+        let url = format!("{}/1.0.0/download", "a".repeat(100));
+        assert_eq!(CrateInfo::try_from_download_url(&url), None);
+
+        // So are the path traversal attempts.
+        for url in [
+            "../1.0.0/download",
+            "..%2F..%2Fetc%2Fpasswd/1.0.0/download",
+            "abc/../download",
+            "abc/..%2F..%2Fetc%2Fpasswd/download",
+            "abc/1.0.0%2F..%2F../download",
+            "a.b/1.0.0/download",
+            "/1.0.0/download",
+            "abc//download",
+            "abc/1.0/download",
+            "abc/1.0.0.0/download",
+            "abc/1.0.x/download",
+            "abc/1.0.0-rc/1/download",
+        ] {
+            assert_eq!(CrateInfo::try_from_download_url(url), None, "{url}");
+        }
+
+        assert_eq!(CrateInfo::try_new("..", "1.0.0"), None);
+        assert_eq!(CrateInfo::try_new("abc", "../1.0.0"), None);
+
+        assert_eq!(CrateInfo::new("SDL2", "0.1.0").index_name(), "sdl2");
+    }
+}
@@ -0,0 +1,328 @@
+//! In-memory crate file cache for the frequently downloaded crates
+
+use std::collections::BTreeMap;
+use std::io::{Result, Write};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use super::crate_cache::{CrateCache, CrateWriter};
+use super::CrateInfo;
+
+/// Least recently used crate files bounded by their total size
+#[derive(Debug, Default)]
+struct LruMap {
+    /// Cached crate file data and its last use tick by the crate file path
+    entries: BTreeMap<String, (u64, Arc<Vec<u8>>)>,
+    /// Cached crate file paths by their last use tick
+    order: BTreeMap<u64, String>,
+    /// Last use tick counter
+    tick: u64,
+    /// Total cached crate file data size in bytes
+    size: usize,
+}
+
+impl LruMap {
+    /// Gets the cached crate file data and marks it as recently used.
+    fn get(&mut self, key: &str) -> Option<Arc<Vec<u8>>> {
+        let (tick, data) = self.entries.get_mut(key)?;
+
+        self.order.remove(tick);
+        self.tick += 1;
+        *tick = self.tick;
+        self.order.insert(self.tick, key.to_owned());
+
+        Some(Arc::clone(data))
+    }
+
+    /// Caches the crate file data, evicting the least recently used
+    /// crate files to keep the total size within `max_size` bytes.
+    fn insert(&mut self, key: String, data: Arc<Vec<u8>>, max_size: usize) {
+        if data.len() > max_size {
+            return;
+        }
+
+        if let Some((tick, old_data)) = self.entries.remove(&key) {
+            self.order.remove(&tick);
+            self.size -= old_data.len();
+        }
+
+        while self.size + data.len() > max_size {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+
+            if let Some((_, old_data)) = self.entries.remove(&oldest) {
+                self.size -= old_data.len();
+            }
+        }
+
+        self.tick += 1;
+        self.size += data.len();
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (self.tick, data));
+    }
+
+    /// Removes all the cached crate files with the key prefix given.
+    fn remove_prefix(&mut self, prefix: &str) {
+        let keys: Vec<String> = self
+            .entries
+            .range(prefix.to_owned()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+
+        for key in keys {
+            if let Some((tick, data)) = self.entries.remove(&key) {
+                self.order.remove(&tick);
+                self.size -= data.len();
+            }
+        }
+    }
+}
+
+/// Gets the in-memory cache key for the crate file.
+///
+/// The crate file paths start with the crate name directory,
+/// so that all versions of the crate share the key prefix.
+fn cache_key(crate_info: &CrateInfo) -> String {
+    crate_info.to_file_path().to_string_lossy().into_owned()
+}
+
+/// Crate files cache keeping the hot crate files in memory
+/// in front of the storage backend
+#[derive(Debug)]
+pub struct MemCrateCache {
+    /// Crate file cache storage backend
+    inner: Arc<dyn CrateCache>,
+    /// Crate files cached in memory
+    entries: Mutex<LruMap>,
+    /// Maximum total size of the crate files cached in memory
+    max_size: usize,
+    /// Maximum size of a single crate file admitted to the memory cache
+    inline_threshold: usize,
+}
+
+/// Crate file writer caching the crate file in memory too
+struct MemCrateWriter<'a> {
+    /// In-memory crate files cache
+    cache: &'a MemCrateCache,
+    /// Crate being cached
+    crate_info: CrateInfo,
+    /// Storage backend crate file writer
+    inner: Box<dyn CrateWriter + 'a>,
+    /// Crate file data written so far (dropped if too large)
+    data: Option<Vec<u8>>,
+}
+
+impl MemCrateCache {
+    /// Creates a new in-memory crate files cache of up to `max_size` bytes
+    /// in front of the storage backend.
+    ///
+    /// Only the crate files of up to `inline_threshold` bytes are cached in memory,
+    /// the larger ones are always read from the storage backend.
+    #[must_use]
+    pub fn new(inner: Arc<dyn CrateCache>, max_size: usize, inline_threshold: usize) -> Self {
+        MemCrateCache {
+            inner,
+            entries: Mutex::new(LruMap::default()),
+            max_size,
+            inline_threshold,
+        }
+    }
+
+    /// Checks if the crate file of `size` bytes can be cached in memory.
+    fn is_admitted(&self, size: usize) -> bool {
+        size <= self.inline_threshold
+    }
+
+    /// Locks the crate files cached in memory.
+    ///
+    /// The cached entries are always inserted and removed as a whole,
+    /// so they remain consistent after a panic.
+    fn lock(&self) -> MutexGuard<'_, LruMap> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Caches the crate file data in memory.
+    fn insert(&self, crate_info: &CrateInfo, data: Vec<u8>) {
+        self.lock()
+            .insert(cache_key(crate_info), Arc::new(data), self.max_size);
+    }
+}
+
+impl CrateCache for MemCrateCache {
+    fn store(&self, crate_info: &CrateInfo, data: &[u8]) {
+        self.inner.store(crate_info, data);
+
+        if self.is_admitted(data.len()) {
+            self.insert(crate_info, data.to_vec());
+        }
+    }
+
+    fn fetch(&self, crate_info: &CrateInfo) -> Option<Vec<u8>> {
+        if let Some(data) = self.lock().get(&cache_key(crate_info)) {
+            return Some(data.to_vec());
+        }
+
+        let data = self.inner.fetch(crate_info)?;
+
+        if self.is_admitted(data.len()) {
+            self.insert(crate_info, data.clone());
+        }
+
+        Some(data)
+    }
+
+    fn writer(&self, crate_info: &CrateInfo) -> Option<Box<dyn CrateWriter + '_>> {
+        let inner = self.inner.writer(crate_info)?;
+
+        Some(Box::new(MemCrateWriter {
+            cache: self,
+            crate_info: crate_info.clone(),
+            inner,
+            data: Some(Vec::new()),
+        }))
+    }
+
+    fn size(&self, crate_info: &CrateInfo) -> Option<u64> {
+        match self.lock().get(&cache_key(crate_info)) {
+            Some(data) => Some(data.len() as u64),
+            None => self.inner.size(crate_info),
+        }
+    }
+
+    fn is_writable(&self) -> bool {
+        self.inner.is_writable()
+    }
+
+    fn remove(&self, name: &str) -> Vec<String> {
+        self.lock().remove_prefix(&format!("{name}/"));
+        self.inner.remove(name)
+    }
+}
+
+impl Write for MemCrateWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let len = self.inner.write(buf)?;
+
+        if let Some(data) = &mut self.data {
+            data.extend_from_slice(&buf[..len]);
+
+            // Stop buffering the crate files too large to be cached in memory.
+            if data.len() > self.cache.max_size || !self.cache.is_admitted(data.len()) {
+                self.data = None;
+            }
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl CrateWriter for MemCrateWriter<'_> {
+    fn commit(self: Box<Self>) {
+        self.inner.commit();
+
+        if let Some(data) = self.data {
+            self.cache.insert(&self.crate_info, data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_dir_all;
+
+    use super::super::crate_cache::FileCrateCache;
+    use super::super::test_dir::TestDir;
+    use super::*;
+
+    #[test]
+    fn test_lru_map() {
+        let mut map = LruMap::default();
+        let data = |len| Arc::new(vec![0; len]);
+
+        map.insert("a".to_owned(), data(4), 10);
+        map.insert("b".to_owned(), data(4), 10);
+        assert!(map.get("a").is_some());
+
+        // The least recently used entry is evicted first.
+        map.insert("c".to_owned(), data(4), 10);
+        assert!(map.get("b").is_none());
+        assert!(map.get("a").is_some());
+        assert_eq!(map.size, 8);
+
+        // Entries larger than the whole cache are never cached.
+        map.insert("d".to_owned(), data(11), 10);
+        assert!(map.get("d").is_none());
+        assert_eq!(map.size, 8);
+
+        map.insert("a/1".to_owned(), data(1), 10);
+        map.remove_prefix("a");
+        assert!(map.get("a").is_none());
+        assert!(map.get("a/1").is_none());
+        assert!(map.get("c").is_some());
+        assert_eq!(map.size, 4);
+    }
+
+    #[test]
+    fn test_mem_crate_cache() {
+        let dir = TestDir::new("mem");
+        let crate_info = CrateInfo::new("mem-cache-test", "0.1.0");
+
+        let inner = Arc::new(FileCrateCache::new(dir.to_path_buf(), false, false));
+        inner.store(&crate_info, b"data");
+
+        let cache = MemCrateCache::new(inner, 100, 100);
+        assert_eq!(cache.fetch(&crate_info).unwrap(), b"data");
+
+        // The crate file is served from memory after the first read.
+        remove_dir_all(&dir).unwrap();
+        assert_eq!(cache.fetch(&crate_info).unwrap(), b"data");
+        assert_eq!(cache.size(&crate_info), Some(4));
+
+        let other = CrateInfo::new("mem-cache-test", "0.2.0");
+        let mut writer = cache.writer(&other).unwrap();
+        writer.write_all(b"other").unwrap();
+        writer.commit();
+
+        remove_dir_all(&dir).unwrap();
+        assert_eq!(cache.fetch(&other).unwrap(), b"other");
+
+        // Removed crates are dropped from memory too.
+        cache.store(&other, b"other");
+        assert_eq!(
+            cache.remove("mem-cache-test"),
+            ["mem-cache-test-0.2.0.crate"]
+        );
+        assert!(cache.fetch(&crate_info).is_none());
+        assert!(cache.fetch(&other).is_none());
+    }
+
+    #[test]
+    fn test_inline_threshold() {
+        let dir = TestDir::new("mem-inline");
+        let small = CrateInfo::new("mem-inline-test", "0.1.0");
+        let large = CrateInfo::new("mem-inline-test", "0.2.0");
+
+        let inner = Arc::new(FileCrateCache::new(dir.to_path_buf(), false, false));
+        let cache = MemCrateCache::new(inner, 100, 4);
+
+        cache.store(&small, b"data");
+        cache.store(&large, b"large");
+
+        let mut writer = cache.writer(&large).unwrap();
+        writer.write_all(b"large").unwrap();
+        writer.commit();
+
+        assert_eq!(cache.fetch(&large).unwrap(), b"large");
+
+        // Only the crate files within the threshold are served from memory.
+        remove_dir_all(&dir).unwrap();
+        assert_eq!(cache.fetch(&small).unwrap(), b"data");
+        assert!(cache.fetch(&large).is_none());
+    }
+}
@@ -1,16 +1,239 @@
 //! Index entry and crate file cache helpers
 
-use std::fs::{create_dir_all, metadata, read, write, File};
-use std::io::Write;
-use std::path::Path;
+use std::fs::{
+    create_dir_all, metadata, read, read_dir, read_to_string, remove_dir_all, remove_file,
+    set_permissions, write, File, Permissions,
+};
+use std::io::{ErrorKind, Result, Write};
+use std::os::unix::fs::{chown, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
-use log::error;
+use log::{debug, error};
 
 use super::{CrateInfo, IndexEntry};
 
+/// Current cache directory format version
+///
+/// Must be incremented whenever the on-disk cache layout changes incompatibly.
+pub const CACHE_FORMAT_VERSION: u32 = 1;
+
+/// Cache directory format version marker file name
+const CACHE_VERSION_FILE_NAME: &str = "CACHE_VERSION";
+
+/// Server start counter file name
+const START_COUNT_FILE_NAME: &str = "START_COUNT";
+
+/// Cache directory write probe file name
+const WRITE_PROBE_FILE_NAME: &str = ".write-probe";
+
+/// Crate file write completion marker file name suffix
+pub const CRATE_MARKER_SUFFIX: &str = ".ok";
+
+/// Recompressed crate file name suffix
+pub const CRATE_ZSTD_SUFFIX: &str = ".zst";
+
+/// Crate file recompression level
+#[cfg(feature = "zstd")]
+const CRATE_ZSTD_LEVEL: i32 = 10;
+
+/// Minimum crate file size reduction required to keep the recompressed data (percent)
+#[cfg(feature = "zstd")]
+const CRATE_ZSTD_MIN_SAVINGS: usize = 5;
+
+/// Creates the cache directory with the given permissions and ownership.
+pub fn cache_create_dir(
+    dir: &Path,
+    mode: Option<u32>,
+    owner: Option<(u32, Option<u32>)>,
+) -> Result<()> {
+    create_dir_all(dir)?;
+
+    if let Some(mode) = mode {
+        set_permissions(dir, Permissions::from_mode(mode))?;
+    }
+
+    if let Some((uid, gid)) = owner {
+        chown(dir, Some(uid), gid)?;
+    }
+
+    Ok(())
+}
+
+/// Checks the cache directory format version marker file.
+///
+/// Returns the stored format version if it does not match the current one.
+/// Cache directories with files, but without the marker file, were written
+/// before the format was versioned, so they are reported as version 0.
+/// Missing and empty cache directories are assumed to be current.
+pub fn cache_version_mismatch(dir: &Path) -> Result<Option<String>> {
+    let version = match read_to_string(dir.join(CACHE_VERSION_FILE_NAME)) {
+        Ok(text) => text.trim().to_owned(),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Ok(dir_has_files(dir)?.then(|| "0".to_owned()));
+        }
+        Err(e) => return Err(e),
+    };
+
+    Ok((version != CACHE_FORMAT_VERSION.to_string()).then_some(version))
+}
+
+/// Checks if the directory tree contains any files.
+///
+/// The file system `lost+found` directory is ignored.
+fn dir_has_files(dir: &Path) -> Result<bool> {
+    let entries = match read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+
+        if !entry.file_type()?.is_dir() {
+            return Ok(true);
+        }
+
+        if entry.file_name() != "lost+found" && dir_has_files(&entry.path())? {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Writes the current format version into the cache directory marker file.
+pub fn cache_write_version(dir: &Path) -> Result<()> {
+    write(
+        dir.join(CACHE_VERSION_FILE_NAME),
+        format!("{CACHE_FORMAT_VERSION}\n"),
+    )
+}
+
+/// Increments the server start counter stored in the cache directory.
+///
+/// Returns the new start count. Malformed counter files are reset.
+pub fn cache_count_start(dir: &Path) -> Result<u64> {
+    let path = dir.join(START_COUNT_FILE_NAME);
+
+    let count = match read_to_string(&path) {
+        Ok(text) => text.trim().parse::<u64>().unwrap_or(0),
+        Err(e) if e.kind() == ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+
+    write(&path, format!("{}\n", count + 1))?;
+    Ok(count + 1)
+}
+
+/// Removes all files and subdirectories from the cache directory.
+pub fn cache_drain_dir(dir: &Path) -> Result<()> {
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+
+        if entry.file_type()?.is_dir() {
+            remove_dir_all(entry.path())?;
+        } else {
+            remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks if new files can be created in the cache directory.
+pub fn cache_dir_is_writable(dir: &Path) -> bool {
+    let probe_path = dir.join(WRITE_PROBE_FILE_NAME);
+
+    write(&probe_path, b"")
+        .and_then(|()| remove_file(&probe_path))
+        .inspect_err(|e| {
+            error!(
+                "cache: cache directory {} is not writable: {e}",
+                dir.display()
+            )
+        })
+        .is_ok()
+}
+
+/// Flushes the file data and its parent directory entry to the storage device.
+fn sync_file_and_dir(file: &File, file_path: &Path) -> Result<()> {
+    file.sync_all()?;
+    File::open(file_path.parent().unwrap())?.sync_all()
+}
+
+/// Appends the suffix to the crate file path.
+pub fn crate_file_path_with_suffix(crate_file_path: &Path, suffix: &str) -> PathBuf {
+    let mut path = crate_file_path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Recompresses the crate file data with zstd, unless it does not help.
+#[cfg(feature = "zstd")]
+fn crate_recompress(data: &[u8]) -> Option<Vec<u8>> {
+    let compressed = zstd::encode_all(data, CRATE_ZSTD_LEVEL)
+        .map_err(|e| error!("cache: failed to recompress crate file: {e}"))
+        .ok()?;
+
+    debug!(
+        "cache: recompressed crate file from {} to {} bytes",
+        data.len(),
+        compressed.len()
+    );
+
+    (compressed.len() * 100 <= data.len() * (100 - CRATE_ZSTD_MIN_SAVINGS)).then_some(compressed)
+}
+
+/// Recompressed crate files are not supported without the `zstd` feature.
+#[cfg(not(feature = "zstd"))]
+fn crate_recompress(_data: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+/// Restores the original crate file data.
+#[cfg(feature = "zstd")]
+fn crate_decompress(data: &[u8]) -> Option<Vec<u8>> {
+    zstd::decode_all(data)
+        .map_err(|e| error!("cache: failed to decompress crate file: {e}"))
+        .ok()
+}
+
+/// Recompressed crate files are not supported without the `zstd` feature.
+#[cfg(not(feature = "zstd"))]
+fn crate_decompress(_data: &[u8]) -> Option<Vec<u8>> {
+    debug!("cache: recompressed crate files are not supported");
+    None
+}
+
 /// Caches the crate package file on the local filesystem.
-pub fn cache_store_crate(dir: &Path, crate_info: &CrateInfo, data: &[u8]) {
-    let crate_file_path = dir.join(crate_info.to_file_path());
+///
+/// The crate file is stored zstd-compressed if `recompress` is set
+/// and it makes the file meaningfully smaller.
+/// The empty completion marker file is created after the crate file is fully written.
+/// Flushes the file to the storage device before returning if `fsync` is set.
+pub fn cache_store_crate(
+    dir: &Path,
+    crate_info: &CrateInfo,
+    data: &[u8],
+    fsync: bool,
+    recompress: bool,
+) {
+    let plain_file_path = dir.join(crate_info.to_file_path());
+    let zstd_file_path = crate_file_path_with_suffix(&plain_file_path, CRATE_ZSTD_SUFFIX);
+    let marker_path = crate_file_path_with_suffix(&plain_file_path, CRATE_MARKER_SUFFIX);
+
+    let compressed = if recompress {
+        crate_recompress(data)
+    } else {
+        None
+    };
+
+    let (crate_file_path, stale_file_path, data) = match &compressed {
+        Some(compressed) => (&zstd_file_path, &plain_file_path, compressed.as_slice()),
+        None => (&plain_file_path, &zstd_file_path, data),
+    };
 
     // Create all parent directories first.
     if let Err(e) = create_dir_all(crate_file_path.parent().unwrap()) {
@@ -18,17 +241,238 @@ pub fn cache_store_crate(dir: &Path, crate_info: &CrateInfo, data: &[u8]) {
         return;
     }
 
-    write(crate_file_path, data)
-        .unwrap_or_else(|e| error!("cache: failed to write crate file: {e}"));
+    // Invalidate the crate file being overwritten.
+    if let Err(e) = remove_file(&marker_path) {
+        if e.kind() != ErrorKind::NotFound {
+            error!("cache: failed to remove crate file marker: {e}");
+            return;
+        }
+    }
+
+    let mut file = match File::create(crate_file_path) {
+        Ok(f) => f,
+        Err(e) => {
+            error!("cache: failed to create crate file: {e}");
+            return;
+        }
+    };
+
+    if let Err(e) = file.write_all(data) {
+        error!("cache: failed to write crate file: {e}");
+        return;
+    }
+
+    if fsync {
+        if let Err(e) = sync_file_and_dir(&file, crate_file_path) {
+            error!("cache: failed to sync crate file: {e}");
+            return;
+        }
+    }
+
+    // Remove the crate file stored in the other format, if any.
+    if let Err(e) = remove_file(stale_file_path) {
+        if e.kind() != ErrorKind::NotFound {
+            error!("cache: failed to remove stale crate file: {e}");
+            return;
+        }
+    }
+
+    if let Err(e) = File::create(&marker_path) {
+        error!("cache: failed to create crate file marker: {e}");
+    }
+}
+
+/// Crate package file being written to the local filesystem
+///
+/// The incomplete crate file is removed if the writer is dropped
+/// without committing.
+#[derive(Debug)]
+pub struct CrateFileWriter {
+    /// Crate file being written
+    file: File,
+    /// Crate file path
+    crate_file_path: PathBuf,
+    /// Crate file write completion marker file path
+    marker_path: PathBuf,
+    /// Flush the crate file to the storage device before committing
+    fsync: bool,
+    /// Set after the crate file is complete
+    committed: bool,
+}
+
+impl CrateFileWriter {
+    /// Creates the crate package file in the cache directory for writing.
+    pub fn create(dir: &Path, crate_info: &CrateInfo, fsync: bool) -> Result<Self> {
+        let crate_file_path = dir.join(crate_info.to_file_path());
+        let marker_path = crate_file_path_with_suffix(&crate_file_path, CRATE_MARKER_SUFFIX);
+
+        create_dir_all(crate_file_path.parent().unwrap())?;
+
+        // Invalidate the crate file being overwritten.
+        if let Err(e) = remove_file(&marker_path) {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+
+        let file = File::create(&crate_file_path)?;
+
+        Ok(CrateFileWriter {
+            file,
+            crate_file_path,
+            marker_path,
+            fsync,
+            committed: false,
+        })
+    }
+
+    /// Marks the crate file as complete after all its data is written.
+    pub fn commit(mut self) -> Result<()> {
+        if self.fsync {
+            sync_file_and_dir(&self.file, &self.crate_file_path)?;
+        }
+
+        // Remove the recompressed crate file stored previously, if any.
+        let zstd_file_path = crate_file_path_with_suffix(&self.crate_file_path, CRATE_ZSTD_SUFFIX);
+
+        if let Err(e) = remove_file(zstd_file_path) {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+
+        File::create(&self.marker_path)?;
+        self.committed = true;
+
+        Ok(())
+    }
+}
+
+impl Write for CrateFileWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for CrateFileWriter {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+
+        debug!(
+            "cache: removing incomplete crate file {}",
+            self.crate_file_path.to_string_lossy()
+        );
+
+        remove_file(&self.crate_file_path)
+            .unwrap_or_else(|e| error!("cache: failed to remove incomplete crate file: {e}"));
+    }
+}
+
+/// Records the cached crate package file access time.
+///
+/// The completion marker file modification time is updated.
+pub fn cache_touch_crate(dir: &Path, crate_info: &CrateInfo) {
+    let crate_file_path = dir.join(crate_info.to_file_path());
+    let marker_path = crate_file_path_with_suffix(&crate_file_path, CRATE_MARKER_SUFFIX);
+
+    File::options()
+        .write(true)
+        .open(marker_path)
+        .and_then(|file| file.set_modified(SystemTime::now()))
+        .unwrap_or_else(|e| error!("cache: failed to update crate file access time: {e}"));
 }
 
 /// Fetches the cached crate package file from the local filesystem, if present.
+///
+/// Crate files without the completion marker are treated as missing.
+/// Recompressed crate files are decompressed transparently.
 pub fn cache_fetch_crate(dir: &Path, crate_info: &CrateInfo) -> Option<Vec<u8>> {
+    let crate_file_path = dir.join(crate_info.to_file_path());
+
+    if !crate_file_path_with_suffix(&crate_file_path, CRATE_MARKER_SUFFIX).exists() {
+        return None;
+    }
+
+    if let Ok(data) = read(crate_file_path_with_suffix(
+        &crate_file_path,
+        CRATE_ZSTD_SUFFIX,
+    )) {
+        return crate_decompress(&data);
+    }
+
+    read(crate_file_path).ok()
+}
+
+/// Fetches the crate package file from a read-only mirror directory, if present.
+pub fn cache_fetch_static_crate(dir: &Path, crate_info: &CrateInfo) -> Option<Vec<u8>> {
     read(dir.join(crate_info.to_file_path())).ok()
 }
 
+/// Gets the cached crate package file size, if present.
+///
+/// Only the recompressed crate files have to be read for that.
+pub fn cache_crate_size(dir: &Path, crate_info: &CrateInfo) -> Option<u64> {
+    let crate_file_path = dir.join(crate_info.to_file_path());
+
+    if !crate_file_path_with_suffix(&crate_file_path, CRATE_MARKER_SUFFIX).exists() {
+        return None;
+    }
+
+    if crate_file_path_with_suffix(&crate_file_path, CRATE_ZSTD_SUFFIX).exists() {
+        return cache_fetch_crate(dir, crate_info).map(|data| data.len() as u64);
+    }
+
+    metadata(crate_file_path)
+        .ok()
+        .map(|metadata| metadata.len())
+}
+
+/// Gets the crate package file size in a read-only mirror directory, if present.
+pub fn cache_static_crate_size(dir: &Path, crate_info: &CrateInfo) -> Option<u64> {
+    metadata(dir.join(crate_info.to_file_path()))
+        .ok()
+        .map(|metadata| metadata.len())
+}
+
+/// Removes all cached package files of the crate from the local filesystem.
+///
+/// Returns the file names of the removed crate versions.
+pub fn cache_remove_crate(dir: &Path, name: &str) -> Result<Vec<String>> {
+    let crate_dir = dir.join(name);
+
+    let entries = match read_dir(&crate_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut file_names = Vec::new();
+
+    for entry in entries {
+        let file_name = entry?.file_name().to_string_lossy().into_owned();
+
+        // Count each crate version once regardless of its file format.
+        if file_name.ends_with(".crate") {
+            file_names.push(file_name);
+        }
+    }
+
+    remove_dir_all(crate_dir)?;
+    file_names.sort();
+
+    Ok(file_names)
+}
+
 /// Caches the index entry file on the local filesystem.
-pub fn cache_store_index_entry(dir: &Path, entry: &IndexEntry, data: &[u8]) {
+///
+/// Flushes the file to the storage device before returning if `fsync` is set.
+pub fn cache_store_index_entry(dir: &Path, entry: &IndexEntry, data: &[u8], fsync: bool) {
     let entry_file_path = dir.join(entry.to_file_path());
 
     if let Err(e) = create_dir_all(entry_file_path.parent().unwrap()) {
@@ -36,7 +480,7 @@ pub fn cache_store_index_entry(dir: &Path, entry: &IndexEntry, data: &[u8]) {
         return;
     }
 
-    let mut file = match File::create(entry_file_path) {
+    let mut file = match File::create(&entry_file_path) {
         Ok(f) => f,
         Err(e) => {
             error!("cache: failed to create index entry file: {e}");
@@ -54,6 +498,11 @@ pub fn cache_store_index_entry(dir: &Path, entry: &IndexEntry, data: &[u8]) {
         file.set_modified(mtime)
             .unwrap_or_else(|e| error!("cache: failed to set index entry file mtime: {e}"));
     }
+
+    if fsync {
+        sync_file_and_dir(&file, &entry_file_path)
+            .unwrap_or_else(|e| error!("cache: failed to sync index entry file: {e}"));
+    }
 }
 
 /// Fetches the cached index entry file from the local filesystem, if present.
@@ -61,6 +510,17 @@ pub fn cache_fetch_index_entry(dir: &Path, entry: &IndexEntry) -> Option<Vec<u8>
     read(dir.join(entry.to_file_path())).ok()
 }
 
+/// Removes the cached index entry file from the local filesystem.
+///
+/// Returns `false` if the index entry file was not cached.
+pub fn cache_remove_index_entry(dir: &Path, entry: &IndexEntry) -> Result<bool> {
+    match remove_file(dir.join(entry.to_file_path())) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
 /// Tries to recreate the missing index entry metadata from the cache file metadata.
 pub fn cache_try_find_index_entry(dir: &Path, name: &str) -> Option<IndexEntry> {
     let mut entry = IndexEntry::new(name);
@@ -74,3 +534,158 @@ pub fn cache_try_find_index_entry(dir: &Path, name: &str) -> Option<IndexEntry>
 
     Some(entry)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_dir::TestDir;
+    use super::*;
+
+    #[test]
+    fn test_crate_marker() {
+        let dir = TestDir::new("marker");
+        let crate_info = CrateInfo::new("serde", "1.0.0");
+
+        cache_store_crate(&dir, &crate_info, b"data", false, false);
+        assert_eq!(cache_fetch_crate(&dir, &crate_info).unwrap(), b"data");
+        assert_eq!(cache_crate_size(&dir, &crate_info), Some(4));
+
+        // Interrupted writes leave no marker behind.
+        let crate_file_path = dir.join(crate_info.to_file_path());
+        remove_file(crate_file_path_with_suffix(
+            &crate_file_path,
+            CRATE_MARKER_SUFFIX,
+        ))
+        .unwrap();
+        assert!(cache_fetch_crate(&dir, &crate_info).is_none());
+        assert_eq!(cache_crate_size(&dir, &crate_info), None);
+        assert_eq!(
+            cache_fetch_static_crate(&dir, &crate_info).unwrap(),
+            b"data"
+        );
+        assert_eq!(cache_static_crate_size(&dir, &crate_info), Some(4));
+    }
+
+    #[test]
+    fn test_cache_remove() {
+        let dir = TestDir::new("remove");
+
+        cache_store_crate(&dir, &CrateInfo::new("serde", "1.0.0"), b"1", false, false);
+        cache_store_crate(&dir, &CrateInfo::new("serde", "1.0.1"), b"2", false, false);
+        cache_store_crate(
+            &dir,
+            &CrateInfo::new("serde-json", "1.0.0"),
+            b"3",
+            false,
+            false,
+        );
+
+        assert_eq!(
+            cache_remove_crate(&dir, "serde").unwrap(),
+            ["serde-1.0.0.crate", "serde-1.0.1.crate"]
+        );
+        assert!(cache_remove_crate(&dir, "serde").unwrap().is_empty());
+        assert!(cache_fetch_crate(&dir, &CrateInfo::new("serde-json", "1.0.0")).is_some());
+
+        let entry = IndexEntry::new("serde");
+        cache_store_index_entry(&dir, &entry, b"{}", false);
+        assert!(cache_remove_index_entry(&dir, &entry).unwrap());
+        assert!(!cache_remove_index_entry(&dir, &entry).unwrap());
+        assert!(cache_fetch_index_entry(&dir, &entry).is_none());
+    }
+
+    #[test]
+    fn test_crate_writer() {
+        let dir = TestDir::new("writer");
+        let crate_info = CrateInfo::new("serde", "1.0.0");
+
+        // Incomplete crate files are removed.
+        let mut writer = CrateFileWriter::create(&dir, &crate_info, false).unwrap();
+        writer.write_all(b"da").unwrap();
+        drop(writer);
+        assert!(!dir.join(crate_info.to_file_path()).exists());
+        assert!(cache_fetch_crate(&dir, &crate_info).is_none());
+
+        let mut writer = CrateFileWriter::create(&dir, &crate_info, false).unwrap();
+        writer.write_all(b"da").unwrap();
+        writer.write_all(b"ta").unwrap();
+        writer.commit().unwrap();
+        assert_eq!(cache_fetch_crate(&dir, &crate_info).unwrap(), b"data");
+    }
+
+    #[test]
+    fn test_cache_dir_is_writable() {
+        let dir = TestDir::new("writable");
+
+        assert!(cache_dir_is_writable(&dir));
+        assert!(!dir.join(WRITE_PROBE_FILE_NAME).exists());
+        assert!(!cache_dir_is_writable(&dir.join("missing")));
+    }
+
+    #[test]
+    fn test_cache_version() {
+        let test_dir = TestDir::new("version");
+        let dir = test_dir.join("cache");
+
+        // Missing and empty cache directories are accepted as is.
+        assert_eq!(cache_version_mismatch(&dir).unwrap(), None);
+
+        create_dir_all(dir.join("index")).unwrap();
+        assert_eq!(cache_version_mismatch(&dir).unwrap(), None);
+
+        // Unversioned cache directories with files predate the format versioning.
+        create_dir_all(dir.join("crates/serde")).unwrap();
+        write(dir.join("crates/serde/serde-1.0.0.crate"), "data").unwrap();
+        assert_eq!(cache_version_mismatch(&dir).unwrap().as_deref(), Some("0"));
+
+        cache_write_version(&dir).unwrap();
+        assert_eq!(cache_version_mismatch(&dir).unwrap(), None);
+
+        write(dir.join(CACHE_VERSION_FILE_NAME), "0\n").unwrap();
+        assert_eq!(cache_version_mismatch(&dir).unwrap().as_deref(), Some("0"));
+
+        cache_drain_dir(&dir).unwrap();
+        assert_eq!(read_dir(&dir).unwrap().count(), 0);
+        assert_eq!(cache_version_mismatch(&dir).unwrap(), None);
+    }
+
+    #[test]
+    fn test_start_count() {
+        let dir = TestDir::new("start");
+
+        assert_eq!(cache_count_start(&dir).unwrap(), 1);
+        assert_eq!(cache_count_start(&dir).unwrap(), 2);
+
+        write(dir.join(START_COUNT_FILE_NAME), "garbage\n").unwrap();
+        assert_eq!(cache_count_start(&dir).unwrap(), 1);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_crate_recompress() {
+        let dir = TestDir::new("zstd");
+        let crate_info = CrateInfo::new("serde", "1.0.0");
+        let crate_file_path = dir.join(crate_info.to_file_path());
+        let zstd_file_path = crate_file_path_with_suffix(&crate_file_path, CRATE_ZSTD_SUFFIX);
+
+        // Compressible data is stored recompressed.
+        let data = vec![b'x'; 4096];
+        cache_store_crate(&dir, &crate_info, &data, false, true);
+        assert!(zstd_file_path.exists());
+        assert!(!crate_file_path.exists());
+        assert_eq!(cache_fetch_crate(&dir, &crate_info).unwrap(), data);
+
+        // Incompressible data is stored as is.
+        let mut state: u32 = 0x1234_5678;
+        let data: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state.to_le_bytes()[0]
+            })
+            .collect();
+        cache_store_crate(&dir, &crate_info, &data, false, true);
+        assert!(!zstd_file_path.exists());
+        assert_eq!(cache_fetch_crate(&dir, &crate_info).unwrap(), data);
+    }
+}
@@ -0,0 +1,74 @@
+//! Index entry cache storage backends
+
+use std::fmt::Debug;
+use std::path::PathBuf;
+
+use log::error;
+
+use super::file_cache::{
+    cache_dir_is_writable, cache_fetch_index_entry, cache_remove_index_entry,
+    cache_store_index_entry, cache_try_find_index_entry,
+};
+use super::IndexEntry;
+
+/// Index entry cache storage backend
+pub trait IndexCache: Debug + Send + Sync {
+    /// Caches the index entry data.
+    fn store(&self, entry: &IndexEntry, data: &[u8]);
+
+    /// Fetches the cached index entry data, if present.
+    fn fetch(&self, entry: &IndexEntry) -> Option<Vec<u8>>;
+
+    /// Tries to recreate the missing index entry metadata from the cache storage.
+    fn try_find(&self, name: &str) -> Option<IndexEntry>;
+
+    /// Removes the cached index entry data, returning `true` if it was present.
+    fn remove(&self, entry: &IndexEntry) -> bool;
+
+    /// Checks if the cache storage accepts new index entries.
+    fn is_writable(&self) -> bool {
+        true
+    }
+}
+
+/// Sharded index entry files cache
+#[derive(Debug)]
+pub struct FileIndexCache {
+    /// Registry index cache directory
+    dir: PathBuf,
+    /// Flush the cached files to the storage device after writing
+    fsync: bool,
+}
+
+impl FileIndexCache {
+    /// Creates a new index entry files cache in the directory.
+    #[must_use]
+    pub fn new(dir: PathBuf, fsync: bool) -> Self {
+        FileIndexCache { dir, fsync }
+    }
+}
+
+impl IndexCache for FileIndexCache {
+    fn store(&self, entry: &IndexEntry, data: &[u8]) {
+        cache_store_index_entry(&self.dir, entry, data, self.fsync);
+    }
+
+    fn fetch(&self, entry: &IndexEntry) -> Option<Vec<u8>> {
+        cache_fetch_index_entry(&self.dir, entry)
+    }
+
+    fn try_find(&self, name: &str) -> Option<IndexEntry> {
+        cache_try_find_index_entry(&self.dir, name)
+    }
+
+    fn remove(&self, entry: &IndexEntry) -> bool {
+        cache_remove_index_entry(&self.dir, entry).unwrap_or_else(|e| {
+            error!("cache: failed to remove index entry file: {e}");
+            false
+        })
+    }
+
+    fn is_writable(&self) -> bool {
+        cache_dir_is_writable(&self.dir)
+    }
+}
@@ -6,6 +6,16 @@ use std::time::{Duration, Instant, SystemTime};
 
 use httpdate::{fmt_http_date, parse_http_date};
 
+use crate::crate_info::is_valid_crate_name;
+
+/// Gets the HTTP entity tag without the weak validator prefix.
+///
+/// Comparing the results implements the weak entity tag comparison.
+#[must_use]
+pub fn etag_opaque_tag(etag: &str) -> &str {
+    etag.trim_start_matches("W/")
+}
+
 /// Registry index entry structure
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct IndexEntry {
@@ -17,6 +27,8 @@ pub struct IndexEntry {
     mtime: Option<SystemTime>,
     /// Last index entry update check time
     atime: Option<Instant>,
+    /// Last index entry update check was too long ago to keep its time
+    expired: bool,
 }
 
 impl Display for IndexEntry {
@@ -34,6 +46,7 @@ impl IndexEntry {
             etag: None,
             mtime: None,
             atime: None,
+            expired: false,
         }
     }
 
@@ -46,16 +59,18 @@ impl IndexEntry {
 
         let mut i = url.split('/');
 
-        match i.next() {
+        let name = match i.next() {
             Some("1" | "2") => match (i.next(), i.next()) {
-                (Some(name), None) => Some(IndexEntry::new(name)),
-                _ => None,
+                (Some(name), None) => name,
+                _ => return None,
             },
             _ => match (i.next(), i.next(), i.next()) {
-                (Some(_), Some(name), None) => Some(IndexEntry::new(name)),
-                _ => None,
+                (Some(_), Some(name), None) => name,
+                _ => return None,
             },
-        }
+        };
+
+        is_valid_crate_name(name).then(|| IndexEntry::new(name))
     }
 
     /// Gets the crate name.
@@ -68,14 +83,27 @@ impl IndexEntry {
     /// as `other` according to the associated metadata.
     #[must_use]
     pub fn is_equivalent(&self, other: &IndexEntry) -> bool {
-        (self.etag().is_some() && (self.etag() == other.etag()))
+        (self.etag().is_some()
+            && (self.etag().map(etag_opaque_tag) == other.etag().map(etag_opaque_tag)))
             || (self.last_modified().is_some() && (self.last_modified() == other.last_modified()))
     }
 
+    /// Checks if this index entry has the same HTTP cache validators as `other`.
+    #[must_use]
+    pub fn has_same_validators(&self, other: &IndexEntry) -> bool {
+        self.etag == other.etag && self.mtime == other.mtime
+    }
+
     /// Checks if this index entry is expired according for the TTL given.
     #[must_use]
     pub fn is_expired_with_ttl(&self, ttl: &Duration) -> bool {
-        self.atime.map_or(false, |atime| atime.elapsed() > *ttl)
+        self.expired || self.atime.is_some_and(|atime| atime.elapsed() > *ttl)
+    }
+
+    /// Checks if this index entry is expired regardless of the TTL.
+    #[must_use]
+    pub fn is_expired(&self) -> bool {
+        self.expired
     }
 
     /// Gets the HTTP entity tag metadata.
@@ -84,6 +112,19 @@ impl IndexEntry {
         self.etag.as_deref()
     }
 
+    /// Gets the HTTP entity tag metadata as a weak entity tag.
+    ///
+    /// The upstream index entries are requested with `Accept-Encoding: gzip`
+    /// and decoded transparently, so the upstream entity tag may belong
+    /// to a different content encoding than the one served to the clients.
+    #[must_use]
+    pub fn weak_etag(&self) -> Option<String> {
+        self.etag.as_ref().map(|etag| match etag.starts_with("W/") {
+            true => etag.clone(),
+            false => format!("W/{etag}"),
+        })
+    }
+
     /// Gets the HTTP Last-Modified metadata.
     #[must_use]
     pub fn last_modified(&self) -> Option<String> {
@@ -113,7 +154,32 @@ impl IndexEntry {
 
     /// Updates the last upstream server access time metadata.
     pub fn set_last_updated(&mut self) {
-        self.atime = Some(Instant::now());
+        self.set_last_updated_at(Instant::now());
+    }
+
+    /// Sets the last upstream server access time metadata.
+    pub fn set_last_updated_at(&mut self, atime: Instant) {
+        self.atime = Some(atime);
+        self.expired = false;
+    }
+
+    /// Marks the index entry as expired for any TTL
+    /// when the last upstream server access time is unknown.
+    pub fn set_expired(&mut self) {
+        self.atime = None;
+        self.expired = true;
+    }
+
+    /// Gets the last upstream server access time metadata.
+    #[must_use]
+    pub fn last_updated_at(&self) -> Option<Instant> {
+        self.atime
+    }
+
+    /// Gets the time elapsed since the last upstream server access.
+    #[must_use]
+    pub fn last_updated_age(&self) -> Option<Duration> {
+        self.atime.map(|atime| atime.elapsed())
     }
 
     /// Builds the index entry download URL (relative).
@@ -167,6 +233,55 @@ mod tests {
             IndexEntry::try_from_index_url("ab/cd/abcd"),
             Some(IndexEntry::new("abcd"))
         );
+
+        // Over-long crate names are rejected before touching the filesystem.
+        let url = format!("aa/aa/{}", "a".repeat(100));
+        assert_eq!(IndexEntry::try_from_index_url(&url), None);
+
+        // So are the path traversal attempts.
+        for url in [
+            "1/..",
+            "../../etc/passwd",
+            "2/%2E%2E",
+            "et/c%/..%2Fetc%2Fpasswd",
+            "ab/cd/a b",
+            "2/",
+        ] {
+            assert_eq!(IndexEntry::try_from_index_url(url), None, "{url}");
+        }
+    }
+
+    #[test]
+    fn test_etag() {
+        let mut entry = IndexEntry::new("etag");
+        assert_eq!(entry.weak_etag(), None);
+
+        entry.set_etag("\"abc\"");
+        assert_eq!(entry.weak_etag().as_deref(), Some("W/\"abc\""));
+
+        let mut other = IndexEntry::new("etag");
+        other.set_etag("W/\"abc\"");
+        assert_eq!(other.weak_etag().as_deref(), Some("W/\"abc\""));
+
+        // Weak and strong entity tags are equivalent for the conditional requests.
+        assert!(entry.is_equivalent(&other));
+        other.set_etag("\"abd\"");
+        assert!(!entry.is_equivalent(&other));
+    }
+
+    #[test]
+    fn test_expired() {
+        let ttl = Duration::from_secs(60);
+
+        let mut entry = IndexEntry::new("expired");
+        assert!(!entry.is_expired_with_ttl(&ttl));
+
+        entry.set_expired();
+        assert!(entry.is_expired_with_ttl(&ttl));
+        assert!(entry.is_expired_with_ttl(&Duration::MAX));
+
+        entry.set_last_updated();
+        assert!(!entry.is_expired_with_ttl(&ttl));
     }
 
     #[test]
@@ -0,0 +1,151 @@
+//! Concurrent upstream fetch coalescing helpers
+
+use std::collections::BTreeSet;
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// Upstream fetches in progress by key
+#[derive(Debug)]
+pub struct InflightFetches {
+    /// Keys of the upstream fetches in progress
+    keys: Mutex<BTreeSet<String>>,
+    /// Signaled when any upstream fetch finishes
+    finished: Condvar,
+}
+
+/// Crate file downloads in progress by the crate file path
+pub static CRATE_FETCHES: InflightFetches = InflightFetches::new();
+
+/// Index entry fetches in progress by the crate name
+pub static INDEX_FETCHES: InflightFetches = InflightFetches::new();
+
+/// Concurrent upstream fetch coalescing outcome
+#[derive(Debug)]
+pub enum Inflight {
+    /// This request is to fetch the data, while the others wait for it.
+    Lead(InflightLead),
+    /// The concurrent fetch of the same data has finished, either way.
+    Finished,
+    /// The concurrent fetch of the same data is still in progress.
+    TimedOut,
+}
+
+/// Lead upstream fetch guard
+///
+/// Wakes up the waiting requests when dropped, even if the fetch failed.
+#[derive(Debug)]
+pub struct InflightLead {
+    /// Upstream fetches set the lead fetch belongs to
+    fetches: &'static InflightFetches,
+    /// Upstream fetch key
+    key: String,
+}
+
+impl InflightFetches {
+    /// Creates an empty upstream fetches set.
+    #[must_use]
+    pub const fn new() -> Self {
+        InflightFetches {
+            keys: Mutex::new(BTreeSet::new()),
+            finished: Condvar::new(),
+        }
+    }
+
+    /// Locks the upstream fetch keys set.
+    ///
+    /// The keys are always inserted and removed as a whole,
+    /// so they remain consistent after a panic.
+    fn lock(&self) -> MutexGuard<'_, BTreeSet<String>> {
+        self.keys.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Becomes the lead upstream fetch for the key, unless there is one
+    /// in progress already.
+    ///
+    /// Otherwise, waits up to `timeout` for the current lead fetch to finish.
+    pub fn lead_or_wait(&'static self, key: &str, timeout: Duration) -> Inflight {
+        let mut keys = self.lock();
+
+        if keys.insert(key.to_owned()) {
+            return Inflight::Lead(InflightLead {
+                fetches: self,
+                key: key.to_owned(),
+            });
+        }
+
+        let (_keys, result) = self
+            .finished
+            .wait_timeout_while(keys, timeout, |keys| keys.contains(key))
+            .unwrap_or_else(PoisonError::into_inner);
+
+        if result.timed_out() {
+            Inflight::TimedOut
+        } else {
+            Inflight::Finished
+        }
+    }
+
+    /// Becomes the lead upstream fetch for the key without waiting,
+    /// unless there is one in progress already.
+    pub fn try_lead(&'static self, key: &str) -> Option<InflightLead> {
+        self.lock().insert(key.to_owned()).then(|| InflightLead {
+            fetches: self,
+            key: key.to_owned(),
+        })
+    }
+}
+
+impl Drop for InflightLead {
+    fn drop(&mut self) {
+        self.fetches.lock().remove(&self.key);
+        self.fetches.finished.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lead_or_wait() {
+        static FETCHES: InflightFetches = InflightFetches::new();
+        let timeout = Duration::from_secs(10);
+
+        let Inflight::Lead(lead) = FETCHES.lead_or_wait("a", timeout) else {
+            panic!("the first fetch must lead");
+        };
+
+        // Unrelated fetches do not wait.
+        assert!(matches!(
+            FETCHES.lead_or_wait("b", timeout),
+            Inflight::Lead(_)
+        ));
+
+        assert!(matches!(
+            FETCHES.lead_or_wait("a", Duration::from_millis(10)),
+            Inflight::TimedOut
+        ));
+
+        let waiter = std::thread::spawn(move || FETCHES.lead_or_wait("a", timeout));
+        std::thread::sleep(Duration::from_millis(50));
+        drop(lead);
+
+        assert!(matches!(waiter.join().unwrap(), Inflight::Finished));
+        assert!(matches!(
+            FETCHES.lead_or_wait("a", timeout),
+            Inflight::Lead(_)
+        ));
+    }
+
+    #[test]
+    fn test_try_lead() {
+        static FETCHES: InflightFetches = InflightFetches::new();
+
+        let lead = FETCHES.try_lead("a").unwrap();
+        assert!(FETCHES.try_lead("a").is_none());
+        assert!(FETCHES.try_lead("b").is_some());
+
+        drop(lead);
+        assert!(FETCHES.try_lead("a").is_some());
+    }
+}
@@ -0,0 +1,166 @@
+//! Cargo lock file parsing helpers
+//!
+//! Only the `[[package]]` tables of the lock file are read, using
+//! the simple line based format written by Cargo.
+
+use std::fs::read_to_string;
+use std::path::Path;
+
+use super::CrateInfo;
+
+/// Locked package fields read so far
+#[derive(Debug, Default)]
+struct LockedPackage {
+    /// Package name
+    name: Option<String>,
+    /// Package version
+    version: Option<String>,
+    /// Package source, missing for the path dependencies
+    source: Option<String>,
+    /// Crate file SHA-256 checksum in hex
+    checksum: Option<String>,
+}
+
+impl LockedPackage {
+    /// Converts the locked package into the registry crate with its checksum.
+    ///
+    /// Returns `None` for the path and git dependencies.
+    fn into_registry_crate(self) -> Option<(CrateInfo, Option<String>)> {
+        let source = self.source?;
+
+        if !source.starts_with("registry+") && !source.starts_with("sparse+") {
+            return None;
+        }
+
+        let crate_info = CrateInfo::try_new(self.name.as_deref()?, self.version.as_deref()?)?;
+
+        Some((crate_info, self.checksum))
+    }
+}
+
+/// Parses the lock file contents into the registry crates with their checksums.
+///
+/// The duplicate crates are listed once.
+#[must_use]
+pub fn parse_lockfile(text: &str) -> Vec<(CrateInfo, Option<String>)> {
+    let mut crates: Vec<(CrateInfo, Option<String>)> = Vec::new();
+    let mut package: Option<LockedPackage> = None;
+
+    let mut push = |package: Option<LockedPackage>| {
+        if let Some((crate_info, checksum)) = package.and_then(LockedPackage::into_registry_crate) {
+            if !crates.iter().any(|(other, _)| *other == crate_info) {
+                crates.push((crate_info, checksum));
+            }
+        }
+    };
+
+    for line in text.lines() {
+        let line = line.trim();
+
+        if line.starts_with('[') {
+            push(package.take());
+
+            if line == "[[package]]" {
+                package = Some(LockedPackage::default());
+            }
+            continue;
+        }
+
+        let Some(package) = &mut package else {
+            continue;
+        };
+
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+
+        let Some(value) = value
+            .trim()
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+        else {
+            continue;
+        };
+
+        let value = Some(value.to_owned());
+
+        match key.trim() {
+            "name" => package.name = value,
+            "version" => package.version = value,
+            "source" => package.source = value,
+            "checksum" => package.checksum = value,
+            _ => {}
+        }
+    }
+
+    push(package);
+
+    crates
+}
+
+/// Reads and parses the lock file.
+pub fn load_lockfile(path: &Path) -> Result<Vec<(CrateInfo, Option<String>)>, String> {
+    let text = read_to_string(path).map_err(|e| e.to_string())?;
+    Ok(parse_lockfile(&text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_lockfile() {
+        let text = r#"
+            # This file is automatically @generated by Cargo.
+            version = 3
+
+            [[package]]
+            name = "app"
+            version = "0.1.0"
+            dependencies = [
+             "serde",
+             "Inflector",
+            ]
+
+            [[package]]
+            name = "serde"
+            version = "1.0.200"
+            source = "registry+https://github.com/rust-lang/crates.io-index"
+            checksum = "ddc6f9cc94d67c0e21aaf7eda3a010fd3af78ebf6e096aa6e2e13c79749cce4f"
+
+            [[package]]
+            name = "Inflector"
+            version = "0.11.4"
+            source = "sparse+https://index.crates.io/"
+
+            [[package]]
+            name = "local"
+            version = "0.2.0"
+            source = "git+https://example.com/local.git#0123456789abcdef"
+
+            [[package]]
+            name = "serde"
+            version = "1.0.200"
+            source = "registry+https://github.com/rust-lang/crates.io-index"
+
+            [metadata]
+            "checksum app 0.1.0" = "x"
+        "#;
+
+        assert_eq!(
+            parse_lockfile(text),
+            [
+                (
+                    CrateInfo::new("serde", "1.0.200"),
+                    Some(
+                        "ddc6f9cc94d67c0e21aaf7eda3a010fd3af78ebf6e096aa6e2e13c79749cce4f"
+                            .to_owned()
+                    )
+                ),
+                (CrateInfo::new("Inflector", "0.11.4"), None),
+            ]
+        );
+
+        assert!(parse_lockfile("").is_empty());
+    }
+}
@@ -0,0 +1,140 @@
+//! Rotating log file helpers
+
+use std::fs::{remove_file, rename, File, OpenOptions};
+use std::io::{ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+
+/// Log file rotated when it grows too large
+///
+/// The logger writes every log record at once while holding its lock,
+/// so the log file is only ever rotated between the log records.
+#[derive(Debug)]
+pub struct RotatingLogFile {
+    /// Current log file path
+    path: PathBuf,
+    /// Current log file
+    file: File,
+    /// Current log file size in bytes
+    size: u64,
+    /// Log file size to rotate the log file at (optional)
+    max_size: Option<u64>,
+    /// Number of rotated log files to keep
+    keep: usize,
+}
+
+/// Opens the log file for appending.
+fn open_log_file(path: &Path) -> Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Renames the file, unless it is missing.
+fn rename_if_exists(from: &Path, to: &Path) -> Result<()> {
+    match rename(from, to) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+impl RotatingLogFile {
+    /// Opens the log file for appending.
+    ///
+    /// The log file is rotated when it grows over `max_size` bytes, if set,
+    /// keeping up to `keep` rotated log files named `PATH.1`, `PATH.2`, etc.
+    pub fn open(path: PathBuf, max_size: Option<u64>, keep: usize) -> Result<Self> {
+        let file = open_log_file(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(RotatingLogFile {
+            path,
+            file,
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    /// Builds the `n`-th rotated log file path.
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        PathBuf::from(path)
+    }
+
+    /// Replaces the current log file with an empty one.
+    ///
+    /// The oldest rotated log file is removed.
+    fn rotate(&mut self) -> Result<()> {
+        if self.keep == 0 {
+            remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                rename_if_exists(&self.rotated_path(n), &self.rotated_path(n + 1))?;
+            }
+
+            rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = open_log_file(&self.path)?;
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+impl Write for RotatingLogFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if let Some(max_size) = self.max_size {
+            if self.size > 0 && self.size + buf.len() as u64 > max_size {
+                // NOTE: The log file rotation errors can not be logged.
+                if let Err(e) = self.rotate() {
+                    eprintln!("crates-io-proxy: failed to rotate the log file: {e}");
+                }
+            }
+        }
+
+        let len = self.file.write(buf)?;
+        self.size += len as u64;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::read_to_string;
+
+    use super::super::test_dir::TestDir;
+    use super::*;
+
+    #[test]
+    fn test_log_file_rotation() {
+        let dir = TestDir::new("log");
+        let path = dir.join("proxy.log");
+
+        let mut log_file = RotatingLogFile::open(path.clone(), Some(10), 2).unwrap();
+
+        for record in ["one\n", "two\n", "three\n", "four\n", "five\n"] {
+            log_file.write_all(record.as_bytes()).unwrap();
+        }
+
+        assert_eq!(read_to_string(&path).unwrap(), "four\nfive\n");
+        assert_eq!(read_to_string(dir.join("proxy.log.1")).unwrap(), "three\n");
+        assert_eq!(
+            read_to_string(dir.join("proxy.log.2")).unwrap(),
+            "one\ntwo\n"
+        );
+        assert!(!dir.join("proxy.log.3").exists());
+
+        // Appending to the existing log file takes its size into account.
+        let mut log_file = RotatingLogFile::open(path.clone(), Some(10), 0).unwrap();
+        log_file.write_all(b"six\n").unwrap();
+        log_file.write_all(b"seven\n").unwrap();
+
+        assert_eq!(read_to_string(&path).unwrap(), "six\nseven\n");
+        assert_eq!(read_to_string(dir.join("proxy.log.1")).unwrap(), "three\n");
+    }
+}
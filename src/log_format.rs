@@ -0,0 +1,73 @@
+//! Structured log record formatting helpers
+
+use std::io::{Result, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::Record;
+
+use super::util::civil_from_days;
+
+/// Formats the log record timestamp in RFC 3339 format with milliseconds in UTC.
+#[must_use]
+fn format_log_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Writes the log record as a single line JSON object.
+pub fn write_json_record(out: &mut dyn Write, record: &Record) -> Result<()> {
+    let json = serde_json::json!({
+        "timestamp": format_log_timestamp(SystemTime::now()),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    });
+
+    writeln!(out, "{json}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use log::Level;
+
+    use super::*;
+
+    #[test]
+    fn test_format_log_timestamp() {
+        let time = UNIX_EPOCH + Duration::from_millis(951_868_799_123);
+        assert_eq!(format_log_timestamp(time), "2000-02-29T23:59:59.123Z");
+    }
+
+    #[test]
+    fn test_write_json_record() {
+        let mut out = Vec::new();
+        let record = Record::builder()
+            .level(Level::Warn)
+            .target("crates_io_proxy")
+            .args(format_args!("proxy: \"quoted\"\nmessage"))
+            .build();
+
+        write_json_record(&mut out, &record).unwrap();
+
+        let line = String::from_utf8(out).unwrap();
+        assert_eq!(line.lines().count(), 1);
+
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["level"], "WARN");
+        assert_eq!(json["target"], "crates_io_proxy");
+        assert_eq!(json["message"], "proxy: \"quoted\"\nmessage");
+        assert!(json["timestamp"].as_str().unwrap().ends_with('Z'));
+    }
+}
@@ -21,37 +21,115 @@
 //! As a convenience feature, the download requests for the `config.json` file
 //! found at the sparse index root are served with a replacement file,
 //! which changes the crate download URL to point to this same proxy server.
-
+//! All the other fields of the upstream `config.json` file, such as
+//! `auth-required`, are passed through unchanged.
+//! If the upstream registry requires authentication, the client `Authorization`
+//! header is forwarded with the index and crate download requests.
+//! Note that the cached files are served to any client allowed by the proxy.
+
+mod cache_limit;
+mod client_auth;
+mod client_limit;
+mod config_file;
 mod config_json;
+mod connection_close;
+mod crate_cache;
 mod crate_info;
+mod crate_mem_cache;
 mod file_cache;
+mod index_cache;
 mod index_entry;
+mod inflight;
+mod lockfile;
+mod log_file;
+mod log_format;
 mod metadata_cache;
+mod rate_limit;
+mod redis_metadata;
+mod request_context;
+mod s3_cache;
+#[cfg(feature = "sqlite")]
+mod sqlite_cache;
+mod stats;
+#[cfg(test)]
+mod test_dir;
+mod tls_config;
+mod util;
+mod worker_limit;
 
 use std::env;
+use std::error::Error;
+use std::ffi::OsString;
 use std::fmt::Display;
-use std::io::Read;
+use std::io::{copy, sink, BufReader, ErrorKind, Read, Write};
+use std::num::ParseIntError;
+use std::ops::{Deref, DerefMut, RangeInclusive};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
-use std::sync::OnceLock;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use pico_args::Arguments;
 
-use env_logger::{Builder as LogBuilder, Env as LogEnv};
-use log::{debug, error, info, warn};
+use env_logger::{Builder as LogBuilder, Env as LogEnv, Target as LogTarget};
+use log::{debug, error, info, warn, LevelFilter};
 
 use tiny_http::{Header, Method, Request, Response, Server};
 use url::Url;
 
-use crate::config_json::{gen_config_json_file, is_config_json_url};
-use crate::crate_info::CrateInfo;
+use crate::cache_limit::cache_limit_init;
+use crate::client_auth::{
+    client_token_label, client_tokens_available, client_tokens_load, constant_time_eq,
+    AuthFailPolicy,
+};
+use crate::client_limit::ClientSlot;
+use crate::config_file::{load_config, ConfigEntry};
+use crate::config_json::{
+    config_json_etag, gen_config_json_file, is_config_json_url, upstream_config_json_auth_required,
+    upstream_config_json_fetch_failed, upstream_config_json_needs_fetch,
+    upstream_config_json_store, CONFIG_JSON_ENDPOINT,
+};
+use crate::connection_close::respond_and_close;
+use crate::crate_cache::{CachingReader, CrateCache, FileCrateCache};
+use crate::crate_info::{is_valid_crate_name, CrateInfo};
+use crate::crate_mem_cache::MemCrateCache;
 use crate::file_cache::{
-    cache_fetch_crate, cache_fetch_index_entry, cache_store_crate, cache_store_index_entry,
-    cache_try_find_index_entry,
+    cache_count_start, cache_create_dir, cache_drain_dir, cache_fetch_index_entry,
+    cache_fetch_static_crate, cache_static_crate_size, cache_try_find_index_entry,
+    cache_version_mismatch, cache_write_version, CACHE_FORMAT_VERSION,
 };
-use crate::index_entry::IndexEntry;
+use crate::index_cache::{FileIndexCache, IndexCache};
+use crate::index_entry::{etag_opaque_tag, IndexEntry};
+use crate::inflight::{Inflight, InflightLead, CRATE_FETCHES, INDEX_FETCHES};
+use crate::lockfile::load_lockfile;
+use crate::log_file::RotatingLogFile;
+use crate::log_format::write_json_record;
 use crate::metadata_cache::{
-    metadata_fetch_index_entry, metadata_invalidate_index_entry, metadata_store_index_entry,
+    metadata_backend_init, metadata_cache_len, metadata_fetch_index_entry,
+    metadata_fetch_not_found, metadata_invalidate_index_entry, metadata_invalidate_not_found,
+    metadata_invalidate_not_found_matching, metadata_load, metadata_not_found_init, metadata_save,
+    metadata_store_index_entry, metadata_store_not_found,
+};
+use crate::rate_limit::{
+    upstream_concurrency_init, upstream_permit_acquire, upstream_rate_limit_acquire,
+    upstream_rate_limit_init, UpstreamPermit,
+};
+use crate::redis_metadata::RedisMetadata;
+use crate::request_context::RequestContext;
+use crate::s3_cache::{S3CrateCache, S3Credentials};
+use crate::stats::{
+    stats_count, stats_count_crate_download, stats_crate_downloads_enabled,
+    stats_crate_downloads_init, stats_observe_upstream_latency, stats_start_init, RequestKind,
+    STATS,
+};
+use crate::tls_config::tls_config_with_ca_cert;
+use crate::worker_limit::{
+    active_fetches, active_workers, fetch_limit_init, worker_limit_init, FetchKind, WorkerSlot,
 };
 
 /// Default listen address and port
@@ -72,33 +150,206 @@ const CRATES_INDEX_PATH: &str = "/index/";
 /// Crates download API path
 const CRATES_API_PATH: &str = "/api/v1/crates/";
 
+/// Crate publishing API path
+const CRATES_PUBLISH_PATH: &str = "/api/v1/crates/new";
+
+/// Crate file URL path template for the download redirects
+const REDIRECT_CRATE_URL_TEMPLATE: &str = "{crate}/{crate}-{version}.crate";
+
 /// Default crate files cache directory path
 const DEFAULT_CACHE_DIR: &str = "/var/cache/crates-io-proxy";
 
 /// Default index cache entry Time-to-Live in seconds
 const DEFAULT_CACHE_TTL_SECS: u64 = 3600;
 
+/// Default upstream HTTP 404 response cache Time-to-Live in seconds
+const DEFAULT_NEGATIVE_TTL_SECS: u64 = 60;
+
+/// Default maximum number of concurrent upstream index fetches
+const DEFAULT_MAX_INFLIGHT_INDEX: usize = 256;
+
+/// Default maximum number of concurrent upstream crate fetches
+const DEFAULT_MAX_INFLIGHT_CRATES: usize = 128;
+
+/// Default maximum time to wait for the worker threads on shutdown in seconds
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+
+/// Shutdown signal check interval while waiting for new requests or workers
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Delay before the first HTTP server restart attempt after an accept error
+const ACCEPT_RETRY_BASE_DELAY: Duration = Duration::from_millis(50);
+
+/// Maximum delay between the HTTP server restart attempts
+const ACCEPT_RETRY_MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// Default number of rotated log files to keep
+const DEFAULT_LOG_KEEP: usize = 5;
+
+/// Default delay before the first upstream request retry in milliseconds
+const DEFAULT_RETRY_BASE_DELAY_MILLIS: u64 = 100;
+
+/// Default maximum size of the crate files cached in memory (256 KiB)
+const DEFAULT_CACHE_INLINE_THRESHOLD: usize = 0x4_0000;
+
+/// Default streamed crate file download chunk size (64 KiB)
+const DEFAULT_STREAM_CHUNK_SIZE: usize = 0x1_0000;
+
+/// Streamed crate file download chunk size bounds (4 KiB to 4 MiB)
+const STREAM_CHUNK_SIZE_RANGE: RangeInclusive<usize> = 0x1000..=0x40_0000;
+
+/// Admin API endpoint path prefix
+const ADMIN_API_PATH: &str = "/admin/";
+
+/// Admin API index entry cache TTL endpoint path (relative)
+const ADMIN_CACHE_TTL_PATH: &str = "config/cache-ttl";
+
+/// Admin API crate refresh endpoint path prefix (relative)
+const ADMIN_REFRESH_PATH: &str = "refresh/";
+
+/// Admin API crate purge endpoint path prefix (relative)
+const ADMIN_PURGE_PATH: &str = "cache/";
+
+/// Liveness probe endpoint path
+const HEALTHZ_PATH: &str = "/healthz";
+
+/// Readiness probe endpoint path
+const READYZ_PATH: &str = "/readyz";
+
+/// Prometheus metrics endpoint path
+const METRICS_PATH: &str = "/metrics";
+
+/// Prometheus text exposition format content type
+const METRICS_HTTP_CTYPE: &str = "Content-Type: text/plain; version=0.0.4";
+
+/// Maximum admin API request body size
+const ADMIN_REQUEST_BODY_LIMIT: u64 = 0x10000;
+
+/// Maximum crate publishing request body size
+///
+/// Well above the default crates.io crate size limit, which can be raised per crate.
+const PUBLISH_REQUEST_BODY_LIMIT: u64 = 0x4000000;
+
+/// Suggested client retry delay in the maintenance mode
+const MAINTENANCE_RETRY_AFTER_SECS: u64 = 300;
+
+/// Index entry metadata cache file name
+const METADATA_FILE_NAME: &str = "index-metadata";
+
+/// Default S3 bucket region name
+const DEFAULT_S3_REGION: &str = "us-east-1";
+
+/// Index entry cache database file name
+const INDEX_DATABASE_FILE_NAME: &str = "index.sqlite3";
+
+/// Live Unix domain socket polling interval while waiting for its takeover
+const SOCKET_TAKEOVER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Maximum time to wait for an upstream request rate limit token
+const UPSTREAM_RATE_LIMIT_WAIT: Duration = Duration::from_millis(500);
+
+/// Maximum time to wait in the queue for an upstream request permit
+const UPSTREAM_CONCURRENCY_WAIT: Duration = Duration::from_secs(60);
+
+/// Maximum time to wait for the concurrent upstream fetch of the same data
+const COALESCED_FETCH_WAIT: Duration = Duration::from_secs(30);
+
 /// Default index entry download buffer capacity
 const INDEX_ENTRY_CAPACITY: usize = 0x10000;
 
+/// Default index entry size to warn about (1 MiB)
+const DEFAULT_LARGE_INDEX_ENTRY_SIZE: usize = 0x10_0000;
+
 /// Limit the download item size to 16 MiB
 const MAX_CRATE_SIZE: usize = 0x100_0000;
 
+/// Index entries smaller than this are sent to the clients uncompressed
+const GZIP_MIN_INDEX_ENTRY_SIZE: usize = 1024;
+
 /// HTTP Content-Type of the registry index entry JSON file
 const INDEX_HTTP_CTYPE: &str = "Content-Type: text/plain";
 
 /// HTTP Content-Type of the crate package file
 const CRATE_HTTP_CTYPE: &str = "Content-Type: application/x-tar";
 
+/// HTTP header carrying the SRI-style crate file integrity metadata
+const CONTENT_INTEGRITY_HEADER: &str = "X-Content-Integrity";
+
 /// HTTP Content-Type of the crates API JSON response
 const JSON_HTTP_CTYPE: &str = "Content-Type: application/json; charset=utf-8";
 
+/// HTTP headers never written to the logs (lowercase)
+const REDACTED_HTTP_HEADERS: [&str; 5] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "www-authenticate",
+];
+
+/// Log target for the index cache decision log records
+const CACHE_DECISION_LOG_TARGET: &str = "cache_decision";
+
+/// Program exit code for runtime errors
+const EXIT_RUNTIME_ERROR: i32 = 1;
+
+/// Program exit code for invalid command line or environment
+const EXIT_USAGE_ERROR: i32 = 2;
+
+/// Long command line options with their short aliases
+const SHORT_OPTIONS: &[(&str, char)] = &[
+    ("--listen", 'L'),
+    ("--index-url", 'I'),
+    ("--upstream-url", 'U'),
+    ("--proxy-url", 'S'),
+    ("--cache-dir", 'C'),
+    ("--cache-ttl", 'T'),
+    ("--verbose", 'v'),
+];
+
+/// Long command line options with their default value environment variables
+const ENV_OPTIONS: &[(&str, &str)] = &[
+    ("--index-url", "INDEX_CRATES_IO_URL"),
+    ("--upstream-url", "CRATES_IO_URL"),
+    ("--proxy-url", "CRATES_IO_PROXY_URL"),
+    ("--cache-dir", "CRATES_IO_PROXY_CACHE_DIR"),
+    ("--cache-ttl", "CRATES_IO_PROXY_CACHE_TTL"),
+    ("--admin-token", "CRATES_IO_PROXY_ADMIN_TOKEN"),
+    ("--egress-proxy", "HTTPS_PROXY"),
+    ("--egress-proxy", "https_proxy"),
+];
+
 /// Program version tag: `"<major>.<minor>.<patch>"`
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 /// HTTP client User Agent string
 const HTTP_USER_AGENT: &str = concat!("crates-io-proxy/", env!("CARGO_PKG_VERSION"));
 
+/// Planned upstream maintenance mode flag
+///
+/// Cache misses are refused instead of being forwarded to the upstream servers.
+static MAINTENANCE_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Index entry cache TTL in seconds set via the admin API
+///
+/// Overrides the configured TTL unless set to [`u64::MAX`].
+static RUNTIME_CACHE_TTL_SECS: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Egress proxy server for all upstream requests (optional)
+static EGRESS_PROXY: OnceLock<ureq::Proxy> = OnceLock::new();
+
+/// Upstream TLS client configuration (optional)
+static TLS_CONFIG: OnceLock<Arc<rustls::ClientConfig>> = OnceLock::new();
+
+/// Index entry responses are gzip-compressed for the clients accepting it.
+static GZIP_INDEX_RESPONSES: AtomicBool = AtomicBool::new(false);
+
+/// Crate download size to close the client connection after (optional)
+static CLOSE_AFTER_DOWNLOAD_BYTES: OnceLock<u64> = OnceLock::new();
+
+/// Upstream request retry count and the first retry delay (optional)
+static UPSTREAM_RETRIES: OnceLock<(u32, Duration)> = OnceLock::new();
+
 /// Proxy server configuration
 #[derive(Debug, Clone)]
 struct ProxyConfig {
@@ -111,14 +362,138 @@ struct ProxyConfig {
     /// External URL of this proxy server (defaults to [`DEFAULT_PROXY_URL`])
     proxy_url: Url,
 
-    /// Registry index cache directory (defaults to [`DEFAULT_CACHE_DIR`])
-    index_dir: PathBuf,
+    /// Registry index cache storage backend (defaults to sharded files)
+    index_cache: Arc<dyn IndexCache>,
+
+    /// Crate files cache storage backend (defaults to [`DEFAULT_CACHE_DIR`])
+    crate_cache: Arc<dyn CrateCache>,
+
+    /// Upstream crate download URL path template (optional)
+    dl_upstream_template: Option<String>,
+
+    /// Crate download redirect base URL used on crate cache misses (optional)
+    redirect_downloads_url: Option<Url>,
+
+    /// Read-only crate files mirror directory (optional)
+    static_crates_dir: Option<PathBuf>,
 
-    /// Crate files cache directory (defaults to [`DEFAULT_CACHE_DIR`])
-    crates_dir: PathBuf,
+    /// Git registry index working tree replacing the upstream index (optional)
+    git_index_dir: Option<PathBuf>,
+
+    /// Serve only the cached data without ever contacting the upstream servers
+    offline: bool,
 
     /// Index entry cache Time-to-Live (defaults to [`DEFAULT_CACHE_TTL_SECS`])
     cache_ttl: Duration,
+
+    /// Grace period for serving expired index entries while revalidating (optional)
+    stale_ttl: Option<Duration>,
+
+    /// Download all crate versions listed in the fetched index entries
+    prefetch_crates: bool,
+
+    /// Serve empty index entries instead of HTTP 404 for the missing crates
+    empty_index_for_missing: bool,
+
+    /// Maximum index entry metadata age for serving HTTP 304 (optional)
+    max_metadata_age: Option<Duration>,
+
+    /// Honor the client `Cache-Control: no-cache` and `no-store` directives
+    respect_client_cache_control: bool,
+
+    /// Send the SRI-style crate file integrity metadata header
+    emit_sri: bool,
+
+    /// Client authentication tokens file (optional)
+    client_tokens_file: Option<PathBuf>,
+
+    /// Client authentication policy when the client tokens are unavailable
+    auth_fail_policy: AuthFailPolicy,
+
+    /// Admin API bearer token (optional, disables the admin API if not set)
+    admin_token: Option<String>,
+
+    /// Admin API Unix socket path (optional, disables the admin API at the public listener)
+    admin_socket: Option<String>,
+
+    /// Maximum number of concurrent requests per client IP address (optional)
+    max_requests_per_client: Option<usize>,
+
+    /// Log the upstream response headers on errors
+    verbose_upstream_errors: bool,
+
+    /// Log requests taking longer than this threshold (optional)
+    slow_request_threshold: Option<Duration>,
+
+    /// Warn about index entries larger than this size in bytes
+    large_index_entry_size: usize,
+
+    /// Streamed crate file download chunk size in bytes
+    stream_chunk_size: usize,
+
+    /// Index snapshot time to pin the cached index entries to (optional)
+    snapshot_time: Option<SystemTime>,
+
+    /// Index entry metadata cache file to load on startup and save on shutdown
+    /// (not used with the shared metadata backend)
+    metadata_file: Option<PathBuf>,
+
+    /// Maximum time to wait for the worker threads on shutdown
+    shutdown_timeout: Duration,
+}
+
+impl ProxyConfig {
+    /// Gets the effective index entry cache TTL, possibly updated at runtime.
+    fn cache_ttl(&self) -> Duration {
+        match RUNTIME_CACHE_TTL_SECS.load(Ordering::Relaxed) {
+            u64::MAX => self.cache_ttl,
+            secs => Duration::from_secs(secs),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            index_url: Url::parse(INDEX_CRATES_IO_URL).unwrap(),
+            upstream_url: Url::parse(CRATES_IO_URL).unwrap(),
+            proxy_url: Url::parse(DEFAULT_PROXY_URL).unwrap(),
+            index_cache: Arc::new(FileIndexCache::new(
+                PathBuf::from(DEFAULT_CACHE_DIR).join("index"),
+                false,
+            )),
+            crate_cache: Arc::new(FileCrateCache::new(
+                PathBuf::from(DEFAULT_CACHE_DIR).join("crates"),
+                false,
+                false,
+            )),
+            dl_upstream_template: None,
+            redirect_downloads_url: None,
+            static_crates_dir: None,
+            git_index_dir: None,
+            offline: false,
+            cache_ttl: Duration::from_secs(DEFAULT_CACHE_TTL_SECS),
+            stale_ttl: None,
+            prefetch_crates: false,
+            empty_index_for_missing: false,
+            max_metadata_age: None,
+            respect_client_cache_control: false,
+            emit_sri: false,
+            client_tokens_file: None,
+            auth_fail_policy: AuthFailPolicy::Closed,
+            admin_token: None,
+            admin_socket: None,
+            max_requests_per_client: None,
+            verbose_upstream_errors: false,
+            slow_request_threshold: None,
+            large_index_entry_size: DEFAULT_LARGE_INDEX_ENTRY_SIZE,
+            stream_chunk_size: DEFAULT_STREAM_CHUNK_SIZE,
+            snapshot_time: None,
+            metadata_file: None,
+            shutdown_timeout: Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+        }
+    }
 }
 
 /// Registry index entry download response
@@ -133,6 +508,19 @@ struct IndexResponse {
     data: Vec<u8>,
 }
 
+/// Inserts a fixed path prefix into the upstream registry index URL.
+///
+/// The index entry paths are resolved relative to the prefixed URL.
+fn apply_index_prefix(index_url: &Url, prefix: &str) -> Result<Url, url::ParseError> {
+    let prefix = prefix.trim_matches('/');
+
+    if prefix.is_empty() {
+        Ok(index_url.clone())
+    } else {
+        index_url.join(&format!("{prefix}/"))
+    }
+}
+
 /// Gets the server-global ureq client instance.
 ///
 /// The global agent instance is required to use HTTP request pipelining.
@@ -140,23 +528,112 @@ fn ureq_agent() -> ureq::Agent {
     static AGENT: OnceLock<ureq::Agent> = OnceLock::new();
 
     AGENT
-        .get_or_init(|| ureq::builder().user_agent(HTTP_USER_AGENT).build())
+        .get_or_init(|| {
+            let mut builder = ureq::builder().user_agent(HTTP_USER_AGENT);
+
+            if let Some(proxy) = EGRESS_PROXY.get() {
+                builder = builder.proxy(proxy.clone());
+            }
+
+            if let Some(tls_config) = TLS_CONFIG.get() {
+                builder = builder.tls_config(Arc::clone(tls_config));
+            }
+
+            builder.build()
+        })
         .clone()
 }
 
-/// Downloads the crate file from the upstream download server
-/// (usually <https://crates.io/>).
-fn download_crate(site_url: &Url, crate_info: &CrateInfo) -> Result<Vec<u8>, Box<ureq::Error>> {
-    let url = site_url
-        .join(CRATES_API_PATH)
-        .unwrap()
-        .join(&crate_info.to_download_url())
-        .unwrap();
+/// Checks if the failed upstream request is worth retrying.
+///
+/// The HTTP 4xx statuses are never retried.
+fn is_transient_error(error: &ureq::Error) -> bool {
+    match error {
+        ureq::Error::Status(code, _) => *code >= 500,
+        ureq::Error::Transport(_) => true,
+    }
+}
 
-    let response = ureq_agent()
-        .request_url("GET", &url)
-        .call()
-        .map_err(Box::new)?;
+/// Sends the upstream request, retrying the transient failures.
+///
+/// Retries up to the configured number of times with exponential backoff,
+/// returning the last error if all attempts fail.
+fn call_with_retries(request: ureq::Request) -> Result<ureq::Response, Box<ureq::Error>> {
+    let (max_retries, base_delay) = UPSTREAM_RETRIES.get().copied().unwrap_or_default();
+
+    for attempt in 0..max_retries {
+        match request.clone().call() {
+            Err(e) if is_transient_error(&e) => {
+                let delay = base_delay.saturating_mul(1 << attempt.min(16));
+                debug!(
+                    "fetch: attempt {} failed: {e}, retrying in {} ms",
+                    attempt + 1,
+                    delay.as_millis()
+                );
+                std::thread::sleep(delay);
+            }
+            result => return result.map_err(Box::new),
+        }
+    }
+
+    request.call().map_err(Box::new)
+}
+
+/// Returns the client credentials to forward to the upstream registry, if any.
+///
+/// The client `Authorization` header is only forwarded if the upstream
+/// registry configuration file declares `auth-required`.
+fn upstream_authorization(request: &Request) -> Option<String> {
+    if !upstream_config_json_auth_required() {
+        return None;
+    }
+
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .map(|header| header.value.as_str().to_owned())
+}
+
+/// Adds the forwarded client credentials to the upstream request, if any.
+fn with_authorization(request: ureq::Request, auth: Option<&str>) -> ureq::Request {
+    match auth {
+        Some(auth) => request.set("Authorization", auth),
+        None => request,
+    }
+}
+
+/// Builds the upstream crate file download URL.
+///
+/// Uses the crates.io download API layout unless a URL path template is given.
+fn crate_download_url(site_url: &Url, crate_info: &CrateInfo, template: Option<&str>) -> Url {
+    match template {
+        Some(template) => site_url.join(&crate_info.to_templated_url(template)),
+        None => site_url
+            .join(CRATES_API_PATH)
+            .and_then(|url| url.join(&crate_info.to_download_url())),
+    }
+    .unwrap()
+}
+
+/// Starts downloading the crate file from the upstream download server
+/// (usually <https://crates.io/>).
+///
+/// Returns the crate file size and the crate file data reader.
+fn download_crate(
+    site_url: &Url,
+    crate_info: &CrateInfo,
+    template: Option<&str>,
+    auth: Option<&str>,
+) -> Result<(usize, impl Read), Box<ureq::Error>> {
+    let url = crate_download_url(site_url, crate_info, template);
+    let request = with_authorization(ureq_agent().request_url("GET", &url), auth);
+
+    let start = Instant::now();
+    let response = call_with_retries(request);
+    stats_observe_upstream_latency(start.elapsed());
+
+    let response = response?;
 
     if let Some(content_len) = response.header("Content-Length") {
         let Ok(len) = content_len.parse::<usize>() else {
@@ -169,37 +646,92 @@ fn download_crate(site_url: &Url, crate_info: &CrateInfo) -> Result<Vec<u8>, Box
             return Err(Box::new(ureq::Error::Status(507, response)));
         }
 
-        let mut data: Vec<u8> = Vec::with_capacity(len);
-        response
-            .into_reader()
-            .read_to_end(&mut data)
-            .map_err(|e| Box::new(e.into()))?;
-
-        Ok(data)
+        stats_count(&STATS.bytes_fetched, len as u64);
+        Ok((len, response.into_reader()))
     } else {
         // HTTP 502 Bad Gateway
         Err(Box::new(ureq::Error::Status(502, response)))
     }
 }
 
-/// Downloads the sparse index entry from the upstream registry.
-/// (usually <https://index.crates.io/>).
-fn download_index_entry(
-    index_url: &Url,
-    mut entry: IndexEntry,
-) -> Result<IndexResponse, Box<ureq::Error>> {
-    let url = index_url.join(&entry.to_index_url()).unwrap();
+/// Checks the crate file on the upstream server without downloading it.
+///
+/// Returns the crate file size and the upstream cache validator headers.
+fn head_crate(
+    site_url: &Url,
+    crate_info: &CrateInfo,
+    template: Option<&str>,
+    auth: Option<&str>,
+) -> Result<(u64, Vec<Header>), Box<ureq::Error>> {
+    let url = crate_download_url(site_url, crate_info, template);
+    let request = with_authorization(ureq_agent().request_url("HEAD", &url), auth);
+
+    let start = Instant::now();
+    let response = call_with_retries(request);
+    stats_observe_upstream_latency(start.elapsed());
+
+    let response = response?;
+
+    let Some(len) = response
+        .header("Content-Length")
+        .and_then(|len| len.parse().ok())
+    else {
+        // HTTP 502 Bad Gateway
+        return Err(Box::new(ureq::Error::Status(502, response)));
+    };
+
+    let headers = ["ETag", "Last-Modified"]
+        .into_iter()
+        .filter_map(|name| Header::from_bytes(name, response.header(name)?).ok())
+        .collect();
 
-    let mut request = ureq_agent().request_url("GET", &url);
+    Ok((len, headers))
+}
+
+/// Builds the conditional upstream request for the index entry.
+///
+/// Both validators are sent if known, letting the upstream server choose.
+fn index_entry_request(index_url: &Url, entry: &IndexEntry, auth: Option<&str>) -> ureq::Request {
+    let url = index_entry_url(index_url, entry);
+
+    // The gzip-compressed index entries are decoded transparently,
+    // and the uncompressed ones are accepted as well.
+    let request = ureq_agent()
+        .request_url("GET", &url)
+        .set("Accept-Encoding", "gzip");
+    let mut request = with_authorization(request, auth);
 
     // Add cache control headers to all index requests.
     if let Some(etag) = entry.etag() {
         request = request.set("If-None-Match", etag);
-    } else if let Some(last_modified) = entry.last_modified() {
+    }
+    if let Some(last_modified) = entry.last_modified() {
         request = request.set("If-Modified-Since", &last_modified);
     }
 
-    let response = request.call().map_err(Box::new)?;
+    request
+}
+
+/// Builds the upstream sparse index entry URL.
+fn index_entry_url(index_url: &Url, entry: &IndexEntry) -> Url {
+    index_url.join(&entry.to_index_url()).unwrap()
+}
+
+/// Downloads the sparse index entry from the upstream registry.
+/// (usually <https://index.crates.io/>).
+///
+/// Warns about the index entries larger than `warn_size` bytes.
+fn download_index_entry(
+    index_url: &Url,
+    mut entry: IndexEntry,
+    warn_size: usize,
+    auth: Option<&str>,
+) -> Result<IndexResponse, Box<ureq::Error>> {
+    let start = Instant::now();
+    let response = call_with_retries(index_entry_request(index_url, &entry, auth));
+    stats_observe_upstream_latency(start.elapsed());
+
+    let response = response?;
 
     let status = response.status();
 
@@ -220,6 +752,15 @@ fn download_index_entry(
         .read_to_end(&mut data)
         .map_err(|e| Box::new(e.into()))?;
 
+    stats_count(&STATS.bytes_fetched, data.len() as u64);
+
+    if data.len() > warn_size {
+        warn!(
+            "fetch: unusually large index entry for {entry}: {} bytes",
+            data.len()
+        );
+    }
+
     Ok(IndexResponse {
         entry,
         status,
@@ -227,6 +768,18 @@ fn download_index_entry(
     })
 }
 
+/// Downloads the registry configuration file from the upstream registry.
+fn download_config_json(index_url: &Url) -> Result<String, Box<ureq::Error>> {
+    let url = index_url.join(CONFIG_JSON_ENDPOINT).unwrap();
+
+    let response = ureq_agent()
+        .request_url("GET", &url)
+        .call()
+        .map_err(Box::new)?;
+
+    response.into_string().map_err(|e| Box::new(e.into()))
+}
+
 /// Logs network errors when sending HTTP responses.
 fn log_send_error(error: std::io::Error) {
     error!("proxy: sending response failed: {error}");
@@ -244,17 +797,130 @@ fn send_error_response(request: Request, code: u16) {
 fn send_json_response(request: Request, code: u16, json: String) {
     let content_type = JSON_HTTP_CTYPE.parse::<Header>().unwrap();
 
+    // Never send the error bodies forwarded from upstream chunked.
     let response = Response::from_string(json)
         .with_status_code(code)
-        .with_header(content_type);
+        .with_header(content_type)
+        .with_chunked_threshold(usize::MAX);
+
+    request.respond(response).unwrap_or_else(log_send_error);
+}
+
+/// Checks if the `If-None-Match` request header matches the entity tag given.
+fn request_etag_matches(request: &Request, etag: &str) -> bool {
+    request
+        .headers()
+        .iter()
+        .filter(|header| header.field.equiv("If-None-Match"))
+        .flat_map(|header| header.value.as_str().split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || etag_opaque_tag(tag) == etag_opaque_tag(etag))
+}
+
+/// Sends the generated registry configuration file response.
+///
+/// Supports conditional requests using the content hash as the entity tag.
+fn send_config_json_response(request: Request, config: &ProxyConfig) {
+    let json = gen_config_json_file(config);
+    let etag = config_json_etag(&json);
+    let etag_header = Header::from_bytes("ETag", etag.as_bytes()).unwrap();
+
+    if request_etag_matches(&request, &etag) {
+        debug!("proxy: registry config file is up to date");
+        let response = Response::empty(304).with_header(etag_header);
+        request.respond(response).unwrap_or_else(log_send_error);
+        return;
+    }
+
+    debug!("proxy: sending registry config file");
+
+    let content_type = JSON_HTTP_CTYPE.parse::<Header>().unwrap();
+    let response = Response::from_string(json)
+        .with_header(content_type)
+        .with_header(etag_header)
+        .with_chunked_threshold(usize::MAX);
 
     request.respond(response).unwrap_or_else(log_send_error);
 }
 
+/// Sends the crate download response.
+///
+/// Asks the client to close the connection after large crate downloads,
+/// if configured.
+fn send_crate_response<R: Read>(request: Request, response: Response<R>, len: usize) {
+    let close = CLOSE_AFTER_DOWNLOAD_BYTES
+        .get()
+        .is_some_and(|&max| len as u64 > max);
+
+    if close {
+        respond_and_close(request, response).unwrap_or_else(log_send_error);
+    } else {
+        request.respond(response).unwrap_or_else(log_send_error);
+    }
+}
+
 /// Sends the crate data download response.
-fn send_crate_data_response(request: Request, data: Vec<u8>) {
+fn send_crate_data_response(request: Request, data: Vec<u8>, integrity: Option<Header>) {
+    let len = data.len();
+    stats_count(&STATS.bytes_served, len as u64);
+
+    let content_type = CRATE_HTTP_CTYPE.parse::<Header>().unwrap();
+
+    // Always send Content-Length to let the client detect truncated crate files.
+    let mut response = Response::from_data(data)
+        .with_header(content_type)
+        .with_chunked_threshold(usize::MAX);
+
+    if let Some(integrity) = integrity {
+        response = response.with_header(integrity);
+    }
+
+    send_crate_response(request, response, len);
+}
+
+/// Sends HTTP 302 redirecting the client to the given URL.
+fn send_redirect_response(request: Request, url: &Url) {
+    let location = Header::from_bytes("Location", url.as_str()).unwrap();
+    let response = Response::empty(302).with_header(location);
+
+    request.respond(response).unwrap_or_else(log_send_error);
+}
+
+/// Sends the crate file data streamed from the reader.
+fn send_crate_stream_response(
+    request: Request,
+    reader: impl Read,
+    len: usize,
+    integrity: Option<Header>,
+) {
+    stats_count(&STATS.bytes_served, len as u64);
+
     let content_type = CRATE_HTTP_CTYPE.parse::<Header>().unwrap();
-    let response = Response::from_data(data).with_header(content_type);
+    let headers = [Some(content_type), integrity]
+        .into_iter()
+        .flatten()
+        .collect();
+
+    // Always send Content-Length to let the client detect truncated crate files.
+    let response = Response::new(200.into(), headers, reader, Some(len), None)
+        .with_chunked_threshold(usize::MAX);
+
+    send_crate_response(request, response, len);
+}
+
+/// Sends the crate file download response headers without the body.
+fn send_crate_head_response(request: Request, len: u64, mut headers: Vec<Header>) {
+    headers.push(CRATE_HTTP_CTYPE.parse::<Header>().unwrap());
+
+    // The response body is never sent to the HEAD requests, but its length is.
+    let response = Response::new(
+        200.into(),
+        headers,
+        std::io::empty(),
+        Some(len as usize),
+        None,
+    )
+    .with_chunked_threshold(usize::MAX);
 
     request.respond(response).unwrap_or_else(log_send_error);
 }
@@ -264,7 +930,7 @@ fn set_index_response_headers<R: Read>(
     mut response: Response<R>,
     entry: &IndexEntry,
 ) -> Response<R> {
-    if let Some(etag) = entry.etag() {
+    if let Some(etag) = entry.weak_etag() {
         let etag = Header::from_bytes("ETag", etag).unwrap();
         response = response.with_header(etag);
     };
@@ -274,15 +940,80 @@ fn set_index_response_headers<R: Read>(
         response = response.with_header(last_modified);
     };
 
+    // The shared caches must not serve the compressed responses to other clients.
+    if GZIP_INDEX_RESPONSES.load(Ordering::Relaxed) {
+        let vary = Header::from_bytes("Vary", "Accept-Encoding").unwrap();
+        response = response.with_header(vary);
+    }
+
     response
 }
 
+/// Checks if the `Accept-Encoding` header value allows the gzip content encoding.
+fn accepts_gzip(value: &str) -> bool {
+    value.split(',').any(|coding| {
+        let mut params = coding.split(';');
+        let name = params.next().unwrap_or_default().trim();
+
+        // The `q=0` weight explicitly refuses the content encoding.
+        let refused = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .any(|weight| weight.parse::<f32>() == Ok(0.0));
+
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+    })
+}
+
+/// Checks if the client request allows the gzip-compressed index entry response.
+fn request_accepts_gzip(request: &Request) -> bool {
+    request
+        .headers()
+        .iter()
+        .filter(|header| header.field.equiv("Accept-Encoding"))
+        .any(|header| accepts_gzip(header.value.as_str()))
+}
+
+/// Compresses the index entry data using the gzip content encoding.
+fn gzip_encode(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(data.len() / 4), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
 /// Sends the registry index entry download response.
+///
+/// The index entry data is gzip-compressed for the clients accepting it,
+/// if enabled.
 fn send_index_entry_data_response(request: Request, index_response: IndexResponse) {
+    let mut data = index_response.data;
+    let mut content_encoding = None;
+
+    if GZIP_INDEX_RESPONSES.load(Ordering::Relaxed)
+        && data.len() >= GZIP_MIN_INDEX_ENTRY_SIZE
+        && request_accepts_gzip(&request)
+    {
+        match gzip_encode(&data) {
+            Ok(gzip_data) => {
+                data = gzip_data;
+                content_encoding = Some(Header::from_bytes("Content-Encoding", "gzip").unwrap());
+            }
+            Err(e) => error!("proxy: failed to compress index entry data: {e}"),
+        }
+    }
+
+    stats_count(&STATS.bytes_served, data.len() as u64);
+
     let content_type = INDEX_HTTP_CTYPE.parse::<Header>().unwrap();
-    let mut response = Response::from_data(index_response.data)
+
+    // Always send Content-Length to let the HEAD requests learn the size.
+    let mut response = Response::from_data(data)
         .with_status_code(index_response.status)
-        .with_header(content_type);
+        .with_header(content_type)
+        .with_chunked_threshold(usize::MAX);
+
+    if let Some(content_encoding) = content_encoding {
+        response = response.with_header(content_encoding);
+    }
 
     response = set_index_response_headers(response, &index_response.entry);
     request.respond(response).unwrap_or_else(log_send_error);
@@ -317,13 +1048,53 @@ fn format_json_error(error: impl Display) -> String {
     format!(r#"{{"errors":[{{"detail":"{error}"}}]}}"#)
 }
 
+/// Logs the upstream server response headers from an ureq client error.
+///
+/// The values of the headers carrying credentials are redacted.
+fn log_upstream_error_headers(error: &ureq::Error) {
+    let ureq::Error::Status(code, response) = error else {
+        return;
+    };
+
+    let headers: Vec<String> = response
+        .headers_names()
+        .into_iter()
+        .map(|name| {
+            let value = if REDACTED_HTTP_HEADERS.contains(&name.as_str()) {
+                "<redacted>"
+            } else {
+                response.header(&name).unwrap_or_default()
+            };
+            format!("{name}: {value}")
+        })
+        .collect();
+
+    warn!(
+        "fetch: upstream HTTP status {code} response headers: {}",
+        headers.join(", ")
+    );
+}
+
 /// Sends the HTTP error response from an ureq client error.
 fn send_fetch_error_response(request: Request, error: Box<ureq::Error>) {
+    stats_count(&STATS.upstream_errors, 1);
+
     match *error {
         // Forward the HTTP error status received from the upstream server.
         ureq::Error::Status(code, response) => {
-            let json = response.into_string().unwrap_or_else(format_json_error);
+            let mut json = response.into_string().unwrap_or_else(format_json_error);
+
+            // Upstream responses to the HEAD requests have no body to forward.
+            if json.is_empty() {
+                json = format_json_error(format!("upstream returned HTTP status {code}"));
+            }
+
             warn!("fetch: upstream returned HTTP status {code}: {json}");
+
+            if code == 404 {
+                metadata_store_not_found(request.url(), &json);
+            }
+
             send_json_response(request, code, json);
         }
 
@@ -335,402 +1106,5016 @@ fn send_fetch_error_response(request: Request, error: Box<ureq::Error>) {
     };
 }
 
-/// Forwards the crate download request to the upstream server.
-///
-/// Processes the download request in a dedicated thread.
-fn forward_download_request(request: Request, crate_info: CrateInfo, config: ProxyConfig) {
-    let thread_name = format!("worker-fetch-crate-{}", crate_info.name());
+/// Checks if the server is in the planned maintenance mode.
+fn is_maintenance_mode() -> bool {
+    MAINTENANCE_MODE.load(Ordering::Relaxed)
+}
 
-    let thread_proc = move || match download_crate(&config.upstream_url, &crate_info) {
-        Ok(data) => {
-            info!("fetch: successfully downloaded {crate_info}");
-            cache_store_crate(&config.crates_dir, &crate_info, &data);
-            send_crate_data_response(request, data);
-        }
-        Err(err) => send_fetch_error_response(request, err),
+/// Waits for the upstream request concurrency and rate limits.
+///
+/// Returns the permit to hold until the upstream response is read,
+/// or the reason to refuse the request.
+fn upstream_request_acquire() -> Result<UpstreamPermit, &'static str> {
+    let Some(permit) = upstream_permit_acquire(UPSTREAM_CONCURRENCY_WAIT) else {
+        stats_count(&STATS.upstream_throttled, 1);
+        return Err("upstream request concurrency limit exceeded");
     };
 
-    std::thread::Builder::new()
-        .name(thread_name)
-        .spawn(thread_proc)
-        .expect("failed to spawn the crate download thread");
+    if !upstream_rate_limit_acquire(UPSTREAM_RATE_LIMIT_WAIT) {
+        stats_count(&STATS.upstream_throttled, 1);
+        return Err("upstream request rate limit exceeded");
+    }
+
+    stats_count(&STATS.upstream_requests, 1);
+    Ok(permit)
 }
 
-/// Forwards the registry index entry download request to the upstream server.
-///
-/// Processes the download request in a dedicated thread.
+/// Sends HTTP 404 for the cache miss in the offline mode.
+fn send_offline_miss_response(request: Request, subject: impl Display) {
+    info!("proxy: {subject} is not cached, not fetching it in the offline mode");
+
+    let json = format_json_error("not cached and the proxy server is offline");
+    send_json_response(request, 404, json);
+}
+
+/// Sends the upstream HTTP 404 response for the missing index entry.
 ///
-/// If the requested index entry file already exists in the cache,
-/// attempts to reduce the amount of data transferred on both sides.
-fn forward_index_request(
+/// Sends an empty index entry listing no versions instead, if configured,
+/// to stop the clients retrying the HTTP 404 responses.
+fn send_index_entry_not_found_response(
     request: Request,
-    entry: IndexEntry,
-    cached_entry: Option<IndexEntry>,
-    config: ProxyConfig,
+    entry: &IndexEntry,
+    json: String,
+    config: &ProxyConfig,
 ) {
-    let thread_name = format!("worker-fetch-index-{entry}");
-
-    // Select where the new HTTP request headers will come from.
-    let req_entry = cached_entry.unwrap_or_else(|| entry.clone());
-
-    let thread_proc = move || match download_index_entry(&config.index_url, req_entry) {
-        Ok(response) => {
-            // Check for HTTP 200 or HTTP 304 statuses.
-            if response.status == 200 {
-                info!("fetch: successfully got index entry for {entry}");
-                cache_store_index_entry(&config.index_dir, &response.entry, &response.data);
-            } else {
-                debug!("fetch: cached index entry for {entry} is up to date");
-            }
+    if config.empty_index_for_missing {
+        debug!("proxy: serving an empty index entry for missing {entry}");
+        send_index_entry_file_response(request, IndexEntry::new(entry.name()), Vec::new());
+    } else {
+        send_json_response(request, 404, json);
+    }
+}
 
-            metadata_store_index_entry(&response.entry);
+/// Sends HTTP 503 refusing the cache miss in the maintenance mode.
+fn send_maintenance_response(request: Request, subject: impl Display) {
+    info!("proxy: refusing cache miss for {subject} during maintenance");
 
-            if response.entry.is_equivalent(&entry) {
-                // Updated index entry file metadata matches that of the client request.
-                debug!("proxy: forwarding the up to date status for {entry}");
-                send_index_entry_not_modified_response(request, &response.entry);
-            } else if response.status == 200 {
-                // Upstream registry sent us updated index entry data.
-                debug!("proxy: forwarding new index data for {entry}");
-                send_index_entry_data_response(request, response);
-            } else if let Some(data) = cache_fetch_index_entry(&config.index_dir, &entry) {
-                // Upstream registry sent us 304 Not Modified,
-                // but the client does not have this file cached.
-                // Fetch the index entry file from the local filesystem cache.
-                debug!("proxy: forwarding cached index data for {entry}");
-                send_index_entry_file_response(request, response.entry, data);
-            } else {
-                // Something went very wrong with the local filesystem cache.
-                error!("cache: lost index cache file for {entry}");
-                // Invalidate the volatile metadata cache and ask the client to retry.
-                metadata_invalidate_index_entry(&entry);
-                send_error_response(request, 503);
-            }
-        }
-        Err(err) => {
-            if let ureq::Error::Transport(err) = err.as_ref() {
-                if let Some(data) = cache_fetch_index_entry(&config.index_dir, &entry) {
-                    error!("fetch: index connection failed: {err}");
+    let json = format_json_error("upstream registry maintenance in progress");
+    let content_type = JSON_HTTP_CTYPE.parse::<Header>().unwrap();
+    let retry_after =
+        Header::from_bytes("Retry-After", MAINTENANCE_RETRY_AFTER_SECS.to_string()).unwrap();
 
-                    // The upstream registry can not be reached at the moment, likely
-                    // due to an intermittent network failure.
-                    // Serve a possibly stale index entry file from the local filesystem
-                    // cache anyway to keep the clients running.
-                    warn!("proxy: forwarding possibly stale cached index data for {entry}");
+    let response = Response::from_string(json)
+        .with_status_code(503)
+        .with_header(content_type)
+        .with_header(retry_after)
+        .with_chunked_threshold(usize::MAX);
 
-                    send_index_entry_file_response(request, entry, data);
-                    return;
-                }
-            }
+    request.respond(response).unwrap_or_else(log_send_error);
+}
 
-            // Forward non-recoverable download errors back to the clients.
-            send_fetch_error_response(request, err);
-        }
-    };
+/// Client request processed by a worker thread
+///
+/// The unanswered request is handed back to the worker thread when dropped,
+/// e.g. during unwinding, so that the client still gets an error response.
+struct WorkerRequest {
+    /// Client request, until taken out to send the response
+    request: Option<Request>,
+    /// Shared slot to hand the unanswered request back to
+    pending: Arc<Mutex<Option<Request>>>,
+}
 
-    std::thread::Builder::new()
-        .name(thread_name)
-        .spawn(thread_proc)
-        .expect("failed to spawn the index download thread");
+impl WorkerRequest {
+    /// Takes the client request out to send the response.
+    fn into_request(mut self) -> Request {
+        self.request.take().expect("request already taken")
+    }
 }
 
-/// Processes one crate download API request.
-fn handle_download_request(request: Request, crate_url: &str, config: &ProxyConfig) {
-    let Some(crate_info) = CrateInfo::try_from_download_url(crate_url) else {
-        warn!("proxy: unrecognized download API endpoint: {crate_url}");
-        send_error_response(request, 404);
-        return;
-    };
+impl Deref for WorkerRequest {
+    type Target = Request;
 
-    debug!("proxy: download API endpoint hit: {crate_url}");
+    fn deref(&self) -> &Request {
+        self.request.as_ref().expect("request already taken")
+    }
+}
 
-    if let Some(data) = cache_fetch_crate(&config.crates_dir, &crate_info) {
-        debug!("proxy: local cache hit for {crate_info}");
-        send_crate_data_response(request, data);
-    } else {
-        forward_download_request(request, crate_info, config.clone());
+impl DerefMut for WorkerRequest {
+    fn deref_mut(&mut self) -> &mut Request {
+        self.request.as_mut().expect("request already taken")
     }
 }
 
-/// Processes one sparse registry index API request.
-fn handle_index_request(request: Request, index_url: &str, config: &ProxyConfig) {
-    if is_config_json_url(index_url) {
-        debug!("proxy: sending registry config file");
-        send_json_response(request, 200, gen_config_json_file(config));
-        return;
+impl Drop for WorkerRequest {
+    fn drop(&mut self) {
+        if let Some(request) = self.request.take() {
+            *self.pending.lock().unwrap_or_else(|e| e.into_inner()) = Some(request);
+        }
     }
+}
 
-    let Some(mut index_entry) = IndexEntry::try_from_index_url(index_url) else {
-        warn!("proxy: malformed registry index path: {index_url}");
-        send_error_response(request, 404);
+/// Spawns a named worker thread processing one client request.
+///
+/// Responds with HTTP 503 if the worker thread limit is reached
+/// or the thread can not be spawned.
+///
+/// Worker thread panics are logged with the request subject.
+/// The client receives HTTP 500 if the request is left unanswered.
+fn spawn_worker(
+    request: Request,
+    thread_name: String,
+    subject: String,
+    proc: impl FnOnce(WorkerRequest) + Send + 'static,
+) {
+    let Some(slot) = WorkerSlot::acquire() else {
+        warn!("proxy: worker thread limit reached, refusing request for {subject}");
+        let json = format_json_error("too many requests in progress");
+        send_json_response(request, 503, json);
         return;
     };
 
-    debug!("proxy: requesting index entry for {index_entry}");
+    // The request is taken back if the worker thread fails to start.
+    let pending = Arc::new(Mutex::new(Some(request)));
+    let worker_pending = Arc::clone(&pending);
+    let worker_subject = subject.clone();
 
-    // Extract cache control headers from all index requests.
-    for header in request.headers() {
-        if header.field.equiv("If-None-Match") {
-            let etag = header.value.as_str();
-            debug!("proxy: checking known index entry {index_entry} with ETag: {etag}");
-            index_entry.set_etag(etag);
-        }
-        if header.field.equiv("If-Modified-Since") {
-            let last_modified = header.value.as_str();
-            debug!("proxy: checking known index entry {index_entry} with Last-Modified: {last_modified}");
-            index_entry.set_last_modified(last_modified);
-        }
-    }
+    let thread_proc = move || {
+        let _slot = slot;
 
-    // Try to serve the request from the local index cache first.
-    // NOTE: The index file cache can not be used without matching metadata.
-    if let Some(cached_entry) = metadata_fetch_index_entry(index_entry.name()) {
-        // Expired cache entries require a new request to the upstream registry.
-        if cached_entry.is_expired_with_ttl(&config.cache_ttl) {
-            info!("proxy: index cache expired for {index_entry}, refreshing...");
-            forward_index_request(request, index_entry, Some(cached_entry), config.clone());
+        let Some(request) = worker_pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+        else {
             return;
+        };
+
+        let request = WorkerRequest {
+            request: Some(request),
+            pending: Arc::clone(&worker_pending),
+        };
+
+        if let Err(panic) = catch_unwind(AssertUnwindSafe(|| proc(request))) {
+            let message = panic
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+
+            error!("proxy: worker thread panicked while processing {worker_subject}: {message}");
         }
 
-        // Check for the index metadata cache hit via ETag and Last-Modified fields.
-        if cached_entry.is_equivalent(&index_entry) {
-            debug!("proxy: index metadata cache hit for {index_entry}");
-            send_index_entry_not_modified_response(request, &cached_entry);
-            return;
+        let unanswered = worker_pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+
+        if let Some(request) = unanswered {
+            let json = format_json_error("internal error while processing the request");
+            send_json_response(request, 500, json);
         }
+    };
 
-        // Check for the index file cache hit next.
-        if let Some(data) = cache_fetch_index_entry(&config.index_dir, &index_entry) {
-            debug!("proxy: index data cache hit for {index_entry}");
-            send_index_entry_file_response(request, cached_entry, data);
-            return;
+    if let Err(e) = std::thread::Builder::new()
+        .name(thread_name)
+        .spawn(thread_proc)
+    {
+        error!("proxy: failed to spawn a worker thread for {subject}: {e}");
+
+        if let Some(request) = pending.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            let json = format_json_error("failed to start request processing");
+            send_json_response(request, 503, json);
         }
     }
+}
 
-    // Try to recreate the index entry metadata from the cached file mtime.
-    let mtimed_entry = cache_try_find_index_entry(&config.index_dir, index_entry.name());
+/// Takes the upstream fetch slot of the kind given for the worker request
+/// about to be forwarded to the upstream server.
+///
+/// Responds with HTTP 503 if the fetch limit of the kind is reached.
+fn acquire_fetch_slot(
+    request: WorkerRequest,
+    kind: FetchKind,
+    subject: impl Display,
+) -> Option<(WorkerRequest, WorkerSlot)> {
+    let Some(fetch_slot) = WorkerSlot::acquire_fetch(kind) else {
+        warn!("proxy: {kind} fetch limit reached, refusing request for {subject}");
+        let json = format_json_error("too many requests in progress");
+        send_json_response(request.into_request(), 503, json);
+        return None;
+    };
 
-    if let Some(entry) = &mtimed_entry {
-        let last_modified = entry.last_modified().unwrap();
+    Some((request, fetch_slot))
+}
 
-        info!(
-            "proxy: recreated index cache metadata for {entry} with Last-Modified: {last_modified}"
-        );
+/// Fetches the upstream registry configuration file before sending
+/// the generated registry configuration file.
+///
+/// Processes the download request in a dedicated thread.
+fn forward_config_json_request(request: Request, ctx: RequestContext, config: ProxyConfig) {
+    if is_maintenance_mode() {
+        // The generated config file is good enough without the upstream one.
+        send_config_json_response(request, &config);
+        return;
     }
 
-    // Fall back to forwarding the request to the upstream registry.
-    forward_index_request(request, index_entry, mtimed_entry, config.clone());
-}
+    let thread_name = "worker-fetch-config".to_owned();
 
-/// Processes one HTTP GET request.
-///
-/// Only registry index and download API requests are supported.
-fn handle_get_request(request: Request, config: &ProxyConfig) {
-    let url = request.url().to_owned();
+    let thread_proc = move |request: WorkerRequest| {
+        // Keep the request context alive until the response is sent.
+        let _ctx = ctx;
 
-    if let Some(index_url) = url.strip_prefix(CRATES_INDEX_PATH) {
-        handle_index_request(request, index_url, config);
-    } else if let Some(crate_url) = url.strip_prefix(CRATES_API_PATH) {
-        handle_download_request(request, crate_url, config);
-    } else {
-        warn!("proxy: unknown index or download API path: {url}");
-        send_error_response(request, 404);
+        let subject = "registry config file";
+        let Some((request, _fetch_slot)) = acquire_fetch_slot(request, FetchKind::Index, subject)
+        else {
+            return;
+        };
+
+        match upstream_request_acquire() {
+            Ok(_permit) => match download_config_json(&config.index_url) {
+                Ok(json) => {
+                    info!("fetch: successfully got upstream registry config file");
+                    upstream_config_json_store(&json);
+                }
+                Err(err) => {
+                    warn!("fetch: failed to get upstream registry config file: {err}");
+                    upstream_config_json_fetch_failed();
+                }
+            },
+            Err(reason) => warn!("fetch: {reason} for registry config file"),
+        }
+
+        send_config_json_response(request.into_request(), &config);
     };
-}
 
-/// Server listening address
-enum ListenAddress {
-    /// IP address + port
-    SocketAddr(String),
-    /// Unix domain socket path
-    UnixPath(String),
+    spawn_worker(
+        request,
+        thread_name,
+        "registry config file".to_owned(),
+        thread_proc,
+    );
 }
 
-/// Runs HTTP proxy server forever.
-fn main_loop(listen_addr: &ListenAddress, config: &ProxyConfig) -> ! {
-    let server = match listen_addr {
-        ListenAddress::SocketAddr(addr) => {
-            info!("proxy: starting HTTP server at: {addr}");
-            Server::http(addr).expect("failed to start the HTTP server")
-        }
-        ListenAddress::UnixPath(path) => {
-            info!("proxy: starting HTTP server at Unix socket {path}");
-            let path = Path::new(path);
-            // Reap stale socket files before binding.
-            std::fs::remove_file(path).ok();
-            Server::http_unix(path).expect("failed to start the HTTP server")
-        }
-    };
+/// Waits for the concurrent upstream download of the same crate file to finish
+/// and serves the request with its result, if possible.
+///
+/// Returns the request back if it has to be forwarded to the upstream server,
+/// together with the lead fetch guard to hold while downloading, if any.
+fn coalesce_crate_fetch(
+    request: WorkerRequest,
+    crate_info: &CrateInfo,
+    config: &ProxyConfig,
+) -> Option<(WorkerRequest, Option<InflightLead>)> {
+    let key = crate_info.to_file_path().to_string_lossy().into_owned();
 
-    // Main HTTP request accept loop.
     loop {
-        let request = server.recv().expect("failed to accept new HTTP requests");
+        match CRATE_FETCHES.lead_or_wait(&key, COALESCED_FETCH_WAIT) {
+            Inflight::Lead(lead) => return Some((request, Some(lead))),
+            Inflight::TimedOut => {
+                warn!("proxy: concurrent download of {crate_info} is too slow, downloading again");
+                return Some((request, None));
+            }
+            Inflight::Finished => (),
+        }
 
-        // Forbid non-downloading HTTP methods.
-        if *request.method() != Method::Get {
-            warn!(
-                "proxy: unexpected download API method: {}",
-                request.method()
-            );
-            send_error_response(request, 403);
-            continue;
+        if let Some(json) = metadata_fetch_not_found(request.url()) {
+            debug!("proxy: concurrent download of {crate_info} found nothing");
+            send_json_response(request.into_request(), 404, json);
+            return None;
+        }
+
+        if let Some(data) = config.crate_cache.fetch(crate_info) {
+            debug!("proxy: serving {crate_info} downloaded by a concurrent request");
+            let integrity = crate_integrity_header(crate_info, config);
+            send_crate_data_response(request.into_request(), data, integrity);
+            return None;
         }
 
-        handle_get_request(request, config);
+        // The concurrent download failed, so one of the waiting requests retries it.
+        debug!("proxy: concurrent download of {crate_info} failed, retrying...");
     }
 }
 
-/// Prints the program version banner.
-fn version() {
-    let build = option_env!("CI_PIPELINE_ID");
-    let rev = option_env!("CI_COMMIT_SHORT_SHA");
-    let tag = option_env!("CI_COMMIT_REF_NAME");
+/// Waits for the concurrent upstream fetch of the same index entry to finish
+/// and serves the request with its result, if possible.
+///
+/// Returns the request back if it has to be forwarded to the upstream server,
+/// together with the lead fetch guard to hold while fetching, if any.
+fn coalesce_index_fetch(
+    request: WorkerRequest,
+    entry: &IndexEntry,
+    config: &ProxyConfig,
+) -> Option<(WorkerRequest, Option<InflightLead>)> {
+    loop {
+        match INDEX_FETCHES.lead_or_wait(entry.name(), COALESCED_FETCH_WAIT) {
+            Inflight::Lead(lead) => return Some((request, Some(lead))),
+            Inflight::TimedOut => {
+                warn!("proxy: concurrent fetch of {entry} is too slow, fetching again");
+                return Some((request, None));
+            }
+            Inflight::Finished => (),
+        }
 
-    if let (Some(build), Some(rev), Some(tag)) = (build, rev, tag) {
-        println!("crates-io-proxy {VERSION}+{build}.g{rev}.{tag}");
-    } else {
-        println!("crates-io-proxy {VERSION}");
-    }
-}
+        if let Some(json) = metadata_fetch_not_found(request.url()) {
+            debug!("proxy: concurrent fetch of {entry} found nothing");
+            send_index_entry_not_found_response(request.into_request(), entry, json, config);
+            return None;
+        }
 
-/// Prints the program invocation help page.
-fn usage() {
-    println!("Usage:\n    crates-io-proxy [options]\n");
-    println!("Options:");
-    println!("    -v, --verbose              print more debug info");
-    println!("    -h, --help                 print help and exit");
-    println!("    -V, --version              print version and exit");
-    println!("    -L, --listen ADDRESS:PORT  address and port to listen at (0.0.0.0:3080)");
-    println!("        --listen-unix PATH     Unix domain socket path to listen at");
-    println!("    -U, --upstream-url URL     upstream download URL (https://crates.io/)");
-    println!("    -I, --index-url URL        upstream index URL (https://index.crates.io/)");
-    println!("    -S, --proxy-url URL        this proxy server URL (http://localhost:3080/)");
-    println!("    -C, --cache-dir DIR        proxy cache directory (/var/cache/crates-io-proxy)");
-    println!("    -T, --cache-ttl SECONDS    index cache entry Time-to-Live in seconds (3600)");
-    println!("\nEnvironment:");
-    println!("    INDEX_CRATES_IO_URL        same as --index-url option");
-    println!("    CRATES_IO_URL              same as --upstream-url option");
-    println!("    CRATES_IO_PROXY_URL        same as --proxy-url option");
-    println!("    CRATES_IO_PROXY_CACHE_DIR  same as --cache-dir option");
-    println!("    CRATES_IO_PROXY_CACHE_TTL  same as --cache-ttl option");
-}
+        // Only the index entries refreshed by the concurrent fetch are served.
+        if let Some(cached_entry) = metadata_fetch_index_entry(entry.name())
+            .filter(|cached_entry| !cached_entry.is_expired_with_ttl(&config.cache_ttl()))
+        {
+            if cached_entry.is_equivalent(entry) {
+                debug!(
+                    "proxy: index entry {entry} refreshed by a concurrent request is up to date"
+                );
+                send_index_entry_not_modified_response(request.into_request(), &cached_entry);
+                return None;
+            }
 
-fn main() {
-    let index_crates_io_url =
-        env::var("INDEX_CRATES_IO_URL").unwrap_or_else(|_| INDEX_CRATES_IO_URL.to_string());
-    let crates_io_url = env::var("CRATES_IO_URL").unwrap_or_else(|_| CRATES_IO_URL.to_string());
-    let default_proxy_url =
-        env::var("CRATES_IO_PROXY_URL").unwrap_or_else(|_| DEFAULT_PROXY_URL.to_string());
-    let default_cache_dir =
-        env::var("CRATES_IO_PROXY_CACHE_DIR").unwrap_or_else(|_| DEFAULT_CACHE_DIR.to_string());
-    let default_cache_ttl_secs: u64 = env::var("CRATES_IO_PROXY_CACHE_TTL")
-        .map_or(DEFAULT_CACHE_TTL_SECS, |s| {
-            s.parse().expect("bad CRATES_IO_PROXY_CACHE_DIR value")
-        });
+            if let Some(data) = config.index_cache.fetch(entry) {
+                debug!("proxy: serving index entry {entry} refreshed by a concurrent request");
+                send_index_entry_file_response(request.into_request(), cached_entry, data);
+                return None;
+            }
+        }
 
-    let mut verbose: u32 = 0;
-    let mut args = Arguments::from_env();
+        // The concurrent fetch failed, so one of the waiting requests retries it.
+        debug!("proxy: concurrent fetch of {entry} failed, retrying...");
+    }
+}
 
-    if args.contains(["-h", "--help"]) {
-        usage();
+/// Forwards the crate download request to the upstream server.
+///
+/// Processes the download request in a dedicated thread.
+fn forward_download_request(
+    request: Request,
+    ctx: RequestContext,
+    crate_info: CrateInfo,
+    config: ProxyConfig,
+) {
+    if let Some(json) = metadata_fetch_not_found(request.url()) {
+        debug!("proxy: serving cached upstream not found status for {crate_info}");
+        send_json_response(request, 404, json);
         return;
     }
 
-    if args.contains(["-V", "--version"]) {
-        version();
+    if is_maintenance_mode() {
+        send_maintenance_response(request, &crate_info);
         return;
     }
 
-    while args.contains(["-v", "--verbose"]) {
-        verbose += 1;
-    }
+    let thread_name = format!("worker-fetch-crate-{}", crate_info.name());
+    let subject = crate_info.to_string();
+    let auth = upstream_authorization(&request);
 
-    let listen_addr_unix = args
-        .opt_value_from_str("--listen-unix")
-        .expect("bad listen socket path");
+    let thread_proc = move |request: WorkerRequest| {
+        // Keep the request context alive until the response is sent.
+        let _ctx = ctx;
 
-    let listen_addr_ip = args
-        .opt_value_from_str(["-L", "--listen"])
-        .expect("bad listen address argument")
-        .unwrap_or_else(|| LISTEN_ADDRESS.to_string());
+        let Some((request, _lead)) = coalesce_crate_fetch(request, &crate_info, &config) else {
+            return;
+        };
 
-    let index_url_string = args
-        .opt_value_from_str(["-I", "--index-url"])
-        .expect("bad upstream index URL argument")
-        .unwrap_or(index_crates_io_url);
+        // Only the requests actually downloading the crate file take the fetch slots.
+        let Some((request, _fetch_slot)) =
+            acquire_fetch_slot(request, FetchKind::Crate, &crate_info)
+        else {
+            return;
+        };
 
-    let upstream_url_string = args
-        .opt_value_from_str(["-U", "--upstream-url"])
-        .expect("bad upstream download URL argument")
-        .unwrap_or(crates_io_url);
+        let _permit = match upstream_request_acquire() {
+            Ok(permit) => permit,
+            Err(reason) => {
+                warn!("fetch: {reason} for {crate_info}");
+                send_json_response(request.into_request(), 503, format_json_error(reason));
+                return;
+            }
+        };
 
-    let proxy_url_string = args
-        .opt_value_from_str(["-S", "--proxy-url"])
-        .expect("bad proxy URL argument")
-        .unwrap_or(default_proxy_url);
+        match download_crate(
+            &config.upstream_url,
+            &crate_info,
+            config.dl_upstream_template.as_deref(),
+            auth.as_deref(),
+        ) {
+            Ok((len, reader)) => {
+                info!("fetch: downloading {crate_info} ({len} bytes)");
+                metadata_invalidate_not_found(request.url());
+
+                // Verify the crate file against the cached index entry, if any.
+                let checksum = cached_crate_checksum(&crate_info, &config);
+
+                if checksum.is_none() {
+                    debug!("fetch: no cached index checksum for {crate_info}");
+                }
 
-    let cache_dir_string = args
-        .opt_value_from_str(["-C", "--cache-dir"])
-        .expect("bad cache directory argument")
-        .unwrap_or(default_cache_dir);
+                let integrity = checksum
+                    .as_deref()
+                    .filter(|_| config.emit_sri)
+                    .and_then(content_integrity_header);
 
-    let cache_ttl_secs: u64 = args
-        .opt_value_from_str(["-T", "--cache-ttl"])
-        .expect("bad cache TTL argument")
-        .unwrap_or(default_cache_ttl_secs);
+                // The upstream crate file is read in the configured chunks as it is sent.
+                let writer = config.crate_cache.writer(&crate_info);
+                let reader = CachingReader::new(reader, writer, len, checksum);
+                let reader = BufReader::with_capacity(config.stream_chunk_size, reader);
+                send_crate_stream_response(request.into_request(), reader, len, integrity);
+            }
+            Err(err) => {
+                if config.verbose_upstream_errors {
+                    log_upstream_error_headers(&err);
+                }
+                send_fetch_error_response(request.into_request(), err);
+            }
+        }
+    };
 
-    let loglevel = match verbose {
-        0 => "warn",
-        1 => "info",
-        2 => "debug",
-        _ => "trace",
+    spawn_worker(request, thread_name, subject, thread_proc);
+}
+
+/// Forwards the crate download HEAD request to the upstream server.
+///
+/// Processes the request in a dedicated thread.
+fn forward_download_head_request(
+    request: Request,
+    ctx: RequestContext,
+    crate_info: CrateInfo,
+    config: ProxyConfig,
+) {
+    if let Some(json) = metadata_fetch_not_found(request.url()) {
+        debug!("proxy: serving cached upstream not found status for {crate_info}");
+        send_json_response(request, 404, json);
+        return;
+    }
+
+    if is_maintenance_mode() {
+        send_maintenance_response(request, &crate_info);
+        return;
+    }
+
+    let thread_name = format!("worker-head-crate-{}", crate_info.name());
+    let subject = crate_info.to_string();
+    let auth = upstream_authorization(&request);
+
+    let thread_proc = move |request: WorkerRequest| {
+        // Keep the request context alive until the response is sent.
+        let _ctx = ctx;
+
+        let Some((request, _fetch_slot)) =
+            acquire_fetch_slot(request, FetchKind::Crate, &crate_info)
+        else {
+            return;
+        };
+
+        let _permit = match upstream_request_acquire() {
+            Ok(permit) => permit,
+            Err(reason) => {
+                warn!("fetch: {reason} for {crate_info}");
+                send_json_response(request.into_request(), 503, format_json_error(reason));
+                return;
+            }
+        };
+
+        match head_crate(
+            &config.upstream_url,
+            &crate_info,
+            config.dl_upstream_template.as_deref(),
+            auth.as_deref(),
+        ) {
+            Ok((len, headers)) => {
+                info!("fetch: checked {crate_info} ({len} bytes)");
+                metadata_invalidate_not_found(request.url());
+                send_crate_head_response(request.into_request(), len, headers);
+            }
+            Err(err) => {
+                if config.verbose_upstream_errors {
+                    log_upstream_error_headers(&err);
+                }
+                send_fetch_error_response(request.into_request(), err);
+            }
+        }
     };
 
-    LogBuilder::from_env(LogEnv::new().default_filter_or(loglevel)).init();
+    spawn_worker(request, thread_name, subject, thread_proc);
+}
+
+/// Forwards the registry index entry download request to the upstream server.
+///
+/// Processes the download request in a dedicated thread.
+///
+/// If the requested index entry file already exists in the cache,
+/// attempts to reduce the amount of data transferred on both sides.
+fn forward_index_request(
+    request: Request,
+    ctx: RequestContext,
+    entry: IndexEntry,
+    cached_entry: Option<IndexEntry>,
+    config: ProxyConfig,
+) {
+    // Select where the new HTTP request headers will come from.
+    let req_entry = cached_entry.unwrap_or_else(|| entry.clone());
+
+    if let Some(json) = metadata_fetch_not_found(request.url()) {
+        debug!("proxy: serving cached upstream not found status for {entry}");
+        send_index_entry_not_found_response(request, &entry, json, &config);
+        return;
+    }
 
-    let index_url = Url::parse(&index_url_string).expect("invalid upstream URL format");
+    if is_maintenance_mode() {
+        if let Some(data) = config.index_cache.fetch(&entry) {
+            warn!("proxy: serving possibly stale cached index entry {entry} during maintenance");
+            send_index_entry_file_response(request, req_entry, data);
+        } else {
+            send_maintenance_response(request, &entry);
+        }
+        return;
+    }
 
-    info!("proxy: using upstream index URL: {index_url}");
+    let thread_name = format!("worker-fetch-index-{entry}");
+    let subject = entry.to_string();
+    let auth = upstream_authorization(&request);
 
-    let upstream_url = Url::parse(&upstream_url_string).expect("invalid upstream URL format");
+    let thread_proc = move |request: WorkerRequest| {
+        // Keep the request context alive until the response is sent.
+        let _ctx = ctx;
 
-    info!("proxy: using upstream download URL: {upstream_url}");
+        let Some((request, _lead)) = coalesce_index_fetch(request, &entry, &config) else {
+            return;
+        };
 
-    let proxy_url = Url::parse(&proxy_url_string).expect("invalid proxy URL format");
+        // Only the requests actually fetching the index entry take the fetch slots.
+        let Some((request, _fetch_slot)) = acquire_fetch_slot(request, FetchKind::Index, &entry)
+        else {
+            return;
+        };
 
-    info!("proxy: using proxy server URL: {proxy_url}");
+        let _permit = match upstream_request_acquire() {
+            Ok(permit) => permit,
+            Err(reason) => {
+                warn!("fetch: {reason} for {entry}");
 
-    let cache_dir = PathBuf::from(cache_dir_string);
-    let index_dir = cache_dir.join("index");
-    let crates_dir = cache_dir.join("crates");
-    let cache_ttl = Duration::from_secs(cache_ttl_secs);
+                if let Some(data) = config.index_cache.fetch(&entry) {
+                    warn!("proxy: forwarding possibly stale cached index data for {entry}");
+                    send_index_entry_file_response(request.into_request(), req_entry, data);
+                } else {
+                    send_json_response(request.into_request(), 503, format_json_error(reason));
+                }
+                return;
+            }
+        };
 
-    info!(
-        "cache: using index directory: {}",
-        index_dir.to_string_lossy()
-    );
+        forward_index_request_proc(request, entry, req_entry, auth, &config);
+    };
 
-    info!(
-        "cache: using crates directory: {}",
-        crates_dir.to_string_lossy()
-    );
+    spawn_worker(request, thread_name, subject, thread_proc);
+}
 
-    info!("cache: using index entry TTL = {cache_ttl_secs} seconds");
+/// Forwards the registry index entry download request to the upstream server
+/// bypassing the index cache.
+///
+/// Processes the download request in a dedicated thread.
+fn forward_uncached_index_request(
+    request: Request,
+    ctx: RequestContext,
+    entry: IndexEntry,
+    config: ProxyConfig,
+) {
+    if is_maintenance_mode() {
+        send_maintenance_response(request, &entry);
+        return;
+    }
 
-    let config = ProxyConfig {
-        index_url,
-        upstream_url,
-        proxy_url,
-        index_dir,
-        crates_dir,
-        cache_ttl,
+    let thread_name = format!("worker-fetch-index-{entry}");
+    let subject = entry.to_string();
+    let auth = upstream_authorization(&request);
+
+    let thread_proc = move |request: WorkerRequest| {
+        // Keep the request context alive until the response is sent.
+        let _ctx = ctx;
+
+        let Some((request, _fetch_slot)) = acquire_fetch_slot(request, FetchKind::Index, &entry)
+        else {
+            return;
+        };
+
+        let _permit = match upstream_request_acquire() {
+            Ok(permit) => permit,
+            Err(reason) => {
+                warn!("fetch: {reason} for {entry}");
+                send_json_response(request.into_request(), 503, format_json_error(reason));
+                return;
+            }
+        };
+
+        match download_index_entry(
+            &config.index_url,
+            entry,
+            config.large_index_entry_size,
+            auth.as_deref(),
+        ) {
+            Ok(response) if response.status == 200 => {
+                debug!(
+                    "proxy: forwarding uncached index data for {}",
+                    response.entry
+                );
+                send_index_entry_data_response(request.into_request(), response);
+            }
+            Ok(response) => {
+                debug!(
+                    "proxy: forwarding the up to date status for {}",
+                    response.entry
+                );
+                send_index_entry_not_modified_response(request.into_request(), &response.entry);
+            }
+            Err(err) => {
+                if config.verbose_upstream_errors {
+                    log_upstream_error_headers(&err);
+                }
+                send_fetch_error_response(request.into_request(), err);
+            }
+        }
     };
 
-    let listen_addr = match listen_addr_unix {
-        Some(unix_path) => ListenAddress::UnixPath(unix_path),
-        None => ListenAddress::SocketAddr(listen_addr_ip),
+    spawn_worker(request, thread_name, subject, thread_proc);
+}
+
+/// Revalidates the stale cached index entry with the upstream registry
+/// in a background worker thread.
+///
+/// Does nothing if the index entry is being fetched already,
+/// or if the worker thread limits are reached.
+fn revalidate_index_entry_in_background(
+    cached_entry: IndexEntry,
+    auth: Option<String>,
+    config: ProxyConfig,
+) {
+    let Some(lead) = INDEX_FETCHES.try_lead(cached_entry.name()) else {
+        debug!("proxy: index entry {cached_entry} is being fetched already");
+        return;
     };
 
-    // Start the main HTTP server.
-    main_loop(&listen_addr, &config)
+    let (Some(slot), Some(fetch_slot)) = (
+        WorkerSlot::acquire(),
+        WorkerSlot::acquire_fetch(FetchKind::Index),
+    ) else {
+        debug!("proxy: worker thread limit reached, not revalidating {cached_entry}");
+        return;
+    };
+
+    let thread_name = format!("worker-revalidate-index-{cached_entry}");
+    let subject = cached_entry.to_string();
+
+    let thread_proc = move || {
+        // Wake up the requests waiting for this fetch when done.
+        let _slots = (slot, fetch_slot, lead);
+
+        let _permit = match upstream_request_acquire() {
+            Ok(permit) => permit,
+            Err(reason) => {
+                warn!("fetch: {reason} for {cached_entry}");
+                return;
+            }
+        };
+
+        let name = cached_entry.to_string();
+
+        match download_index_entry(
+            &config.index_url,
+            cached_entry,
+            config.large_index_entry_size,
+            auth.as_deref(),
+        ) {
+            Ok(response) => {
+                if response.status == 200 {
+                    info!("fetch: successfully revalidated index entry for {name}");
+                    config.index_cache.store(&response.entry, &response.data);
+
+                    if config.prefetch_crates {
+                        prefetch_crates_in_background(&name, &response.data, auth, config.clone());
+                    }
+                } else {
+                    debug!("fetch: stale index entry for {name} is up to date");
+                }
+
+                metadata_store_index_entry(&response.entry);
+            }
+            Err(err) => {
+                if config.verbose_upstream_errors {
+                    log_upstream_error_headers(&err);
+                }
+                warn!("fetch: failed to revalidate index entry for {name}: {err}");
+            }
+        }
+    };
+
+    if let Err(e) = std::thread::Builder::new()
+        .name(thread_name)
+        .spawn(thread_proc)
+    {
+        error!("proxy: failed to spawn a worker thread for {subject}: {e}");
+    }
+}
+
+/// Lists the crate versions to prefetch from the index entry file data.
+///
+/// Returns the crate versions with their checksums, skipping the yanked ones.
+fn index_data_prefetch_list(data: &[u8]) -> Vec<(CrateInfo, Option<String>)> {
+    index_data_records(data)
+        .filter(|record| record["yanked"] != true)
+        .filter_map(|record| {
+            let crate_info =
+                CrateInfo::try_new(record["name"].as_str()?, record["vers"].as_str()?)?;
+            let checksum = record["cksum"].as_str().map(ToOwned::to_owned);
+            Some((crate_info, checksum))
+        })
+        .collect()
+}
+
+/// Checks if the crate file is available without downloading it.
+fn is_crate_cached(crate_info: &CrateInfo, config: &ProxyConfig) -> bool {
+    let in_static_dir = config
+        .static_crates_dir
+        .as_deref()
+        .is_some_and(|dir| cache_static_crate_size(dir, crate_info).is_some());
+
+    in_static_dir || config.crate_cache.size(crate_info).is_some()
+}
+
+/// Downloads the crate file into the cache without serving it.
+///
+/// Returns the crate file size in bytes.
+fn download_crate_into_cache(
+    crate_info: &CrateInfo,
+    checksum: Option<String>,
+    auth: Option<&str>,
+    config: &ProxyConfig,
+) -> Result<u64, String> {
+    let _permit = upstream_request_acquire()?;
+
+    let (len, reader) = download_crate(
+        &config.upstream_url,
+        crate_info,
+        config.dl_upstream_template.as_deref(),
+        auth,
+    )
+    .map_err(|e| e.to_string())?;
+
+    let writer = config.crate_cache.writer(crate_info);
+    let mut reader = CachingReader::new(reader, writer, len, checksum);
+    copy(&mut reader, &mut sink()).map_err(|e| e.to_string())
+}
+
+/// Downloads the crate file into the cache, unless it is being downloaded already.
+fn prefetch_crate(
+    crate_info: &CrateInfo,
+    checksum: Option<String>,
+    auth: Option<&str>,
+    config: &ProxyConfig,
+) {
+    let key = crate_info.to_file_path().to_string_lossy().into_owned();
+
+    let Some(_lead) = CRATE_FETCHES.try_lead(&key) else {
+        debug!("proxy: crate {crate_info} is being downloaded already");
+        return;
+    };
+
+    match download_crate_into_cache(crate_info, checksum, auth, config) {
+        Ok(len) => debug!("fetch: prefetched {crate_info} ({len} bytes)"),
+        Err(e) => warn!("fetch: failed to prefetch {crate_info}: {e}"),
+    }
+}
+
+/// Downloads the crate versions listed in the fresh index entry file data
+/// that are not cached yet in a background thread.
+///
+/// The crate files are downloaded one at a time, within the worker thread
+/// and upstream request limits.
+fn prefetch_crates_in_background(
+    name: &str,
+    data: &[u8],
+    auth: Option<String>,
+    config: ProxyConfig,
+) {
+    let crates = index_data_prefetch_list(data);
+
+    if crates.is_empty() {
+        return;
+    }
+
+    let (Some(slot), Some(fetch_slot)) = (
+        WorkerSlot::acquire(),
+        WorkerSlot::acquire_fetch(FetchKind::Crate),
+    ) else {
+        debug!("proxy: worker thread limit reached, not prefetching {name}");
+        return;
+    };
+
+    let thread_name = format!("worker-prefetch-crates-{name}");
+    let subject = name.to_owned();
+
+    let thread_proc = move || {
+        let _slots = (slot, fetch_slot);
+
+        for (crate_info, checksum) in crates {
+            if is_maintenance_mode() {
+                debug!("proxy: maintenance mode, not prefetching {crate_info}");
+                return;
+            }
+
+            if !is_crate_cached(&crate_info, &config) {
+                prefetch_crate(&crate_info, checksum, auth.as_deref(), &config);
+            }
+        }
+    };
+
+    if let Err(e) = std::thread::Builder::new()
+        .name(thread_name)
+        .spawn(thread_proc)
+    {
+        error!("proxy: failed to spawn a worker thread for {subject}: {e}");
+    }
+}
+
+/// Downloads the index entry into the cache, unless it already lists the crate version.
+///
+/// Returns `true` if the index entry was downloaded.
+fn seed_index_entry(crate_info: &CrateInfo, config: &ProxyConfig) -> Result<bool, String> {
+    let entry = IndexEntry::new(&crate_info.index_name());
+
+    if let Some(data) = config.index_cache.fetch(&entry) {
+        if index_data_has_version(&data, crate_info.version()) {
+            return Ok(false);
+        }
+    }
+
+    let _permit = upstream_request_acquire()?;
+
+    let response = download_index_entry(
+        &config.index_url,
+        entry,
+        config.large_index_entry_size,
+        None,
+    )
+    .map_err(|e| e.to_string())?;
+
+    if response.status != 200 {
+        return Err(format!("unexpected index entry status {}", response.status));
+    }
+
+    config.index_cache.store(&response.entry, &response.data);
+    metadata_store_index_entry(&response.entry);
+
+    Ok(true)
+}
+
+/// Downloads the index entry and the crate file into the cache,
+/// unless they are cached already.
+///
+/// Returns `true` if anything was downloaded.
+fn seed_crate(
+    crate_info: &CrateInfo,
+    checksum: Option<String>,
+    config: &ProxyConfig,
+) -> Result<bool, String> {
+    let index_seeded = seed_index_entry(crate_info, config)?;
+
+    if is_crate_cached(crate_info, config) {
+        return Ok(index_seeded);
+    }
+
+    // Old lock files keep the checksums in a separate table.
+    let checksum = checksum.or_else(|| cached_crate_checksum(crate_info, config));
+    download_crate_into_cache(crate_info, checksum, None, config)?;
+
+    Ok(true)
+}
+
+/// Seeds the cache with the registry crates listed in the Cargo lock file
+/// before starting the server.
+fn seed_cache_from_lockfile(path: &Path, config: &ProxyConfig) {
+    let crates = load_lockfile(path).unwrap_or_else(|e| {
+        fatal_error(format!(
+            "cache: failed to read the lock file {}: {e}",
+            path.display()
+        ))
+    });
+
+    let total = crates.len();
+    info!("cache: seeding {total} crates from {}", path.display());
+
+    let (mut seeded, mut cached, mut failed) = (0, 0, 0);
+
+    for (i, (crate_info, checksum)) in crates.into_iter().enumerate() {
+        match seed_crate(&crate_info, checksum, config) {
+            Ok(true) => {
+                seeded += 1;
+                info!("cache: seeded {}/{total}: {crate_info}", i + 1);
+            }
+            Ok(false) => {
+                cached += 1;
+                debug!(
+                    "cache: skipped {}/{total}: {crate_info} is cached already",
+                    i + 1
+                );
+            }
+            Err(e) => {
+                failed += 1;
+                warn!("cache: failed to seed {}/{total}: {crate_info}: {e}", i + 1);
+            }
+        }
+    }
+
+    let summary = format!(
+        "cache: seeded {seeded} crates from {}, {cached} cached already, {failed} failed",
+        path.display()
+    );
+
+    if failed > 0 {
+        warn!("{summary}");
+    } else {
+        info!("{summary}");
+    }
+}
+
+/// Processes the registry index entry download request in a worker thread.
+fn forward_index_request_proc(
+    request: WorkerRequest,
+    entry: IndexEntry,
+    req_entry: IndexEntry,
+    auth: Option<String>,
+    config: &ProxyConfig,
+) {
+    // Keep the cached index entry metadata for serving the stale data.
+    let cached_entry = req_entry.clone();
+
+    match download_index_entry(
+        &config.index_url,
+        req_entry,
+        config.large_index_entry_size,
+        auth.as_deref(),
+    ) {
+        Ok(response) => {
+            metadata_invalidate_not_found(request.url());
+
+            // Check for HTTP 200 or HTTP 304 statuses.
+            if response.status == 200 {
+                info!("fetch: successfully got index entry for {entry}");
+                config.index_cache.store(&response.entry, &response.data);
+
+                if config.prefetch_crates {
+                    prefetch_crates_in_background(
+                        entry.name(),
+                        &response.data,
+                        auth,
+                        config.clone(),
+                    );
+                }
+            } else {
+                debug!("fetch: cached index entry for {entry} is up to date");
+            }
+
+            metadata_store_index_entry(&response.entry);
+
+            if response.entry.is_equivalent(&entry) {
+                // Updated index entry file metadata matches that of the client request.
+                debug!("proxy: forwarding the up to date status for {entry}");
+                send_index_entry_not_modified_response(request.into_request(), &response.entry);
+            } else if response.status == 200 {
+                // Upstream registry sent us updated index entry data.
+                debug!("proxy: forwarding new index data for {entry}");
+                send_index_entry_data_response(request.into_request(), response);
+            } else if let Some(data) = config.index_cache.fetch(&entry) {
+                // Upstream registry sent us 304 Not Modified,
+                // but the client does not have this file cached.
+                // Fetch the index entry file from the local filesystem cache.
+                debug!("proxy: forwarding cached index data for {entry}");
+                send_index_entry_file_response(request.into_request(), response.entry, data);
+            } else {
+                // Something went very wrong with the local filesystem cache.
+                error!("cache: lost index cache file for {entry}");
+                // Invalidate the volatile metadata cache and ask the client to retry.
+                metadata_invalidate_index_entry(&entry);
+                send_error_response(request.into_request(), 503);
+            }
+        }
+        Err(err) => {
+            if let ureq::Error::Transport(err) = err.as_ref() {
+                if let Some(data) = config.index_cache.fetch(&entry) {
+                    error!("fetch: index connection failed: {err}");
+
+                    // The upstream registry can not be reached at the moment, likely
+                    // due to an intermittent network failure.
+                    // Serve a possibly stale index entry file from the local filesystem
+                    // cache anyway to keep the clients running.
+                    warn!("proxy: forwarding possibly stale cached index data for {entry}");
+
+                    if cached_entry.is_equivalent(&entry) {
+                        send_index_entry_not_modified_response(
+                            request.into_request(),
+                            &cached_entry,
+                        );
+                    } else {
+                        send_index_entry_file_response(request.into_request(), cached_entry, data);
+                    }
+                    return;
+                }
+            }
+
+            if config.verbose_upstream_errors {
+                log_upstream_error_headers(&err);
+            }
+
+            match *err {
+                ureq::Error::Status(404, response) if config.empty_index_for_missing => {
+                    stats_count(&STATS.upstream_errors, 1);
+
+                    let json = response.into_string().unwrap_or_else(format_json_error);
+                    warn!("fetch: upstream returned HTTP status 404: {json}");
+
+                    metadata_store_not_found(request.url(), &json);
+                    send_index_entry_not_found_response(
+                        request.into_request(),
+                        &entry,
+                        json,
+                        config,
+                    );
+                }
+                // Forward non-recoverable download errors back to the clients.
+                err => send_fetch_error_response(request.into_request(), Box::new(err)),
+            }
+        }
+    }
+}
+
+/// Fetches the cached index entry last modified before the snapshot time.
+fn fetch_pinned_index_entry(
+    name: &str,
+    snapshot_time: SystemTime,
+    config: &ProxyConfig,
+) -> Option<(IndexEntry, Vec<u8>)> {
+    let entry = config.index_cache.try_find(name)?;
+
+    if entry.mtime()? > snapshot_time {
+        return None;
+    }
+
+    let data = config.index_cache.fetch(&entry)?;
+    Some((entry, data))
+}
+
+/// Reads the index entry file from the git registry index working tree.
+///
+/// The git index uses the same file layout as the sparse index,
+/// so the file modification time is used as the Last-Modified time.
+fn fetch_git_index_entry(dir: &Path, name: &str) -> Option<(IndexEntry, Vec<u8>)> {
+    let entry = cache_try_find_index_entry(dir, name)?;
+    let data = cache_fetch_index_entry(dir, &entry)?;
+    Some((entry, data))
+}
+
+/// Reads the cached index entry file without contacting the upstream registry.
+///
+/// Recreates the missing index entry metadata from the cached file mtime.
+fn fetch_offline_index_entry(name: &str, config: &ProxyConfig) -> Option<(IndexEntry, Vec<u8>)> {
+    let entry = metadata_fetch_index_entry(name).or_else(|| config.index_cache.try_find(name))?;
+    let data = config.index_cache.fetch(&entry)?;
+    Some((entry, data))
+}
+
+/// Parses the index entry file data into the crate version records.
+///
+/// The index entries are served as is, so the lines not parsed as JSON
+/// objects (e.g. a future upstream format extension) are skipped here.
+/// The unknown record fields are ignored by the callers.
+fn index_data_records(data: &[u8]) -> impl Iterator<Item = serde_json::Value> + '_ {
+    data.split(|&b| b == b'\n')
+        .filter(|line| !line.trim_ascii().is_empty())
+        .filter_map(|line| match serde_json::from_slice(line) {
+            Ok(record @ serde_json::Value::Object(_)) => Some(record),
+            Ok(_) => {
+                debug!("proxy: skipping non-object index entry line");
+                None
+            }
+            Err(e) => {
+                debug!("proxy: skipping malformed index entry line: {e}");
+                None
+            }
+        })
+}
+
+/// Checks if the crate version is listed in the index entry file data.
+fn index_data_has_version(data: &[u8], version: &str) -> bool {
+    index_data_records(data).any(|record| record["vers"] == version)
+}
+
+/// Finds the crate version checksum in the cached index entry, if any.
+fn cached_crate_checksum(crate_info: &CrateInfo, config: &ProxyConfig) -> Option<String> {
+    config
+        .index_cache
+        .fetch(&IndexEntry::new(&crate_info.index_name()))
+        .and_then(|data| index_data_checksum(&data, crate_info.version()))
+}
+
+/// Formats the SHA-256 checksum in hex as the SRI-style integrity metadata.
+fn format_sri_sha256(checksum: &str) -> Option<String> {
+    let digest = (0..checksum.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(checksum.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    (digest.len() == 32).then(|| format!("sha256-{}", BASE64_STANDARD.encode(digest)))
+}
+
+/// Builds the crate file integrity metadata header from the SHA-256 checksum in hex.
+fn content_integrity_header(checksum: &str) -> Option<Header> {
+    Header::from_bytes(CONTENT_INTEGRITY_HEADER, format_sri_sha256(checksum)?).ok()
+}
+
+/// Builds the crate file integrity metadata header, if enabled.
+///
+/// The crate version checksum is looked up in the cached index entry.
+fn crate_integrity_header(crate_info: &CrateInfo, config: &ProxyConfig) -> Option<Header> {
+    if !config.emit_sri {
+        return None;
+    }
+
+    cached_crate_checksum(crate_info, config)
+        .as_deref()
+        .and_then(content_integrity_header)
+}
+
+/// Finds the crate version checksum in the index entry file data.
+fn index_data_checksum(data: &[u8], version: &str) -> Option<String> {
+    index_data_records(data)
+        .find(|record| record["vers"] == version)
+        .and_then(|record| record["cksum"].as_str().map(ToOwned::to_owned))
+}
+
+/// Client request `Cache-Control` header directives
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct ClientCacheControl {
+    /// The cached response must be revalidated with the upstream server
+    no_cache: bool,
+
+    /// The response must not be served from or stored in the cache
+    no_store: bool,
+}
+
+impl ClientCacheControl {
+    /// Parses the client request `Cache-Control` header value.
+    fn parse(value: &str) -> Self {
+        let mut cache_control = ClientCacheControl::default();
+
+        for directive in value.split(',') {
+            // Ignore the directive arguments, e.g. `no-cache="field"`.
+            let name = directive.split('=').next().unwrap_or_default().trim();
+
+            if name.eq_ignore_ascii_case("no-cache") {
+                cache_control.no_cache = true;
+            }
+            if name.eq_ignore_ascii_case("no-store") {
+                cache_control.no_store = true;
+            }
+        }
+
+        cache_control
+    }
+}
+
+/// Processes one crate download API request.
+fn handle_download_request(
+    request: Request,
+    mut ctx: RequestContext,
+    crate_url: &str,
+    config: &ProxyConfig,
+) {
+    let Some(crate_info) = CrateInfo::try_from_download_url(crate_url) else {
+        warn!("proxy: unrecognized download API endpoint: {crate_url}");
+        send_error_response(request, 404);
+        return;
+    };
+
+    debug!("proxy: download API endpoint hit: {crate_url}");
+    ctx.set_crate_name(RequestKind::Crate, crate_info.name());
+
+    // Only the crate versions listed in the pinned index snapshot are available.
+    if let Some(snapshot_time) = config.snapshot_time {
+        let pinned = fetch_pinned_index_entry(&crate_info.index_name(), snapshot_time, config);
+
+        if !pinned.is_some_and(|(_, data)| index_data_has_version(&data, crate_info.version())) {
+            info!("proxy: {crate_info} is not in the index snapshot");
+            let json = format_json_error("crate version is not in the index snapshot");
+            send_json_response(request, 404, json);
+            return;
+        }
+    }
+
+    if *request.method() == Method::Head {
+        handle_download_head_request(request, ctx, crate_info, config);
+        return;
+    }
+
+    stats_count_crate_download(&crate_info.index_name());
+
+    // The static crate mirror directory is authoritative, if configured.
+    if let Some(static_dir) = &config.static_crates_dir {
+        if let Some(data) = cache_fetch_static_crate(static_dir, &crate_info) {
+            debug!("proxy: static mirror hit for {crate_info}");
+            ctx.set_cache_result("static-hit");
+            stats_count(&STATS.crate_hits, 1);
+            let integrity = crate_integrity_header(&crate_info, config);
+            send_crate_data_response(request, data, integrity);
+            return;
+        }
+    }
+
+    if let Some(data) = config.crate_cache.fetch(&crate_info) {
+        debug!("proxy: local cache hit for {crate_info}");
+        ctx.set_cache_result("cache-hit");
+        stats_count(&STATS.crate_hits, 1);
+        let integrity = crate_integrity_header(&crate_info, config);
+        send_crate_data_response(request, data, integrity);
+    } else if config.offline {
+        ctx.set_cache_result("offline-miss");
+        stats_count(&STATS.crate_misses, 1);
+        send_offline_miss_response(request, &crate_info);
+    } else if let Some(base_url) = &config.redirect_downloads_url {
+        let url = crate_download_url(base_url, &crate_info, Some(REDIRECT_CRATE_URL_TEMPLATE));
+        debug!("proxy: redirecting {crate_info} download to {url}");
+        ctx.set_cache_result("redirect");
+        stats_count(&STATS.crate_misses, 1);
+        send_redirect_response(request, &url);
+    } else {
+        ctx.set_cache_result("miss");
+        stats_count(&STATS.crate_misses, 1);
+        forward_download_request(request, ctx, crate_info, config.clone());
+    }
+}
+
+/// Processes one crate download API HEAD request.
+///
+/// The cached crate files are not read, unless recompressed, and
+/// the cache misses are forwarded upstream as HEAD requests too.
+fn handle_download_head_request(
+    request: Request,
+    mut ctx: RequestContext,
+    crate_info: CrateInfo,
+    config: &ProxyConfig,
+) {
+    // The static crate mirror directory is authoritative, if configured.
+    if let Some(static_dir) = &config.static_crates_dir {
+        if let Some(len) = cache_static_crate_size(static_dir, &crate_info) {
+            debug!("proxy: static mirror hit for {crate_info}");
+            ctx.set_cache_result("static-hit");
+            stats_count(&STATS.crate_hits, 1);
+            let headers = crate_integrity_header(&crate_info, config)
+                .into_iter()
+                .collect();
+            send_crate_head_response(request, len, headers);
+            return;
+        }
+    }
+
+    if let Some(len) = config.crate_cache.size(&crate_info) {
+        debug!("proxy: local cache hit for {crate_info}");
+        ctx.set_cache_result("cache-hit");
+        stats_count(&STATS.crate_hits, 1);
+        let headers = crate_integrity_header(&crate_info, config)
+            .into_iter()
+            .collect();
+        send_crate_head_response(request, len, headers);
+    } else if config.offline {
+        ctx.set_cache_result("offline-miss");
+        stats_count(&STATS.crate_misses, 1);
+        send_offline_miss_response(request, &crate_info);
+    } else if let Some(base_url) = &config.redirect_downloads_url {
+        let url = crate_download_url(base_url, &crate_info, Some(REDIRECT_CRATE_URL_TEMPLATE));
+        debug!("proxy: redirecting {crate_info} download to {url}");
+        ctx.set_cache_result("redirect");
+        stats_count(&STATS.crate_misses, 1);
+        send_redirect_response(request, &url);
+    } else {
+        ctx.set_cache_result("miss");
+        stats_count(&STATS.crate_misses, 1);
+        forward_download_head_request(request, ctx, crate_info, config.clone());
+    }
+}
+
+/// Logs the cache decision made for a sparse registry index request.
+///
+/// These log records are only enabled with the `--log-cache-decisions` option.
+fn log_cache_decision(ctx: &mut RequestContext, decision: &'static str, entry: &IndexEntry) {
+    ctx.set_cache_result(decision);
+
+    // All other cache decisions forward the request to the upstream registry.
+    if decision.ends_with("-hit") {
+        stats_count(&STATS.index_hits, 1);
+    } else {
+        stats_count(&STATS.index_misses, 1);
+    }
+
+    info!(target: CACHE_DECISION_LOG_TARGET, "cache: decision={decision} crate={entry}");
+}
+
+/// Processes one sparse registry index API request.
+fn handle_index_request(
+    request: Request,
+    mut ctx: RequestContext,
+    index_url: &str,
+    config: &ProxyConfig,
+) {
+    if is_config_json_url(index_url) {
+        // The generated config file is good enough without the upstream one.
+        if upstream_config_json_needs_fetch() && !config.offline {
+            forward_config_json_request(request, ctx, config.clone());
+        } else {
+            send_config_json_response(request, config);
+        }
+        return;
+    }
+
+    let Some(mut index_entry) = IndexEntry::try_from_index_url(index_url) else {
+        warn!("proxy: malformed registry index path: {index_url}");
+        send_error_response(request, 404);
+        return;
+    };
+
+    debug!("proxy: requesting index entry for {index_entry}");
+    ctx.set_crate_name(RequestKind::Index, index_entry.name());
+
+    let mut cache_control = ClientCacheControl::default();
+
+    // Extract cache control headers from all index requests.
+    for header in request.headers() {
+        if header.field.equiv("If-None-Match") {
+            let etag = header.value.as_str();
+            debug!("proxy: checking known index entry {index_entry} with ETag: {etag}");
+            index_entry.set_etag(etag);
+        }
+        if header.field.equiv("If-Modified-Since") {
+            let last_modified = header.value.as_str();
+            debug!("proxy: checking known index entry {index_entry} with Last-Modified: {last_modified}");
+            index_entry.set_last_modified(last_modified);
+        }
+        if header.field.equiv("Cache-Control") && config.respect_client_cache_control {
+            cache_control = ClientCacheControl::parse(header.value.as_str());
+        }
+    }
+
+    // Serve the pinned index snapshot without contacting the upstream registry.
+    if let Some(snapshot_time) = config.snapshot_time {
+        ctx.set_cache_result("snapshot");
+
+        match fetch_pinned_index_entry(index_entry.name(), snapshot_time, config) {
+            Some((entry, _)) if entry.is_equivalent(&index_entry) => {
+                send_index_entry_not_modified_response(request, &entry);
+            }
+            Some((entry, data)) => send_index_entry_file_response(request, entry, data),
+            None => {
+                info!("proxy: index entry for {index_entry} is not in the snapshot");
+                let json = format_json_error("index entry is not in the snapshot");
+                send_json_response(request, 404, json);
+            }
+        }
+        return;
+    }
+
+    // Bypass the index cache entirely at the client request, unless offline.
+    if cache_control.no_store && !config.offline {
+        info!("proxy: client requested no-store for {index_entry}, bypassing cache...");
+        log_cache_decision(&mut ctx, "client-no-store", &index_entry);
+        forward_uncached_index_request(request, ctx, index_entry, config.clone());
+        return;
+    }
+
+    // Serve the git index entry without contacting the upstream registry.
+    if let Some(git_index_dir) = &config.git_index_dir {
+        ctx.set_cache_result("git-index");
+
+        match fetch_git_index_entry(git_index_dir, index_entry.name()) {
+            Some((entry, _)) if entry.is_equivalent(&index_entry) => {
+                send_index_entry_not_modified_response(request, &entry);
+            }
+            Some((entry, data)) => send_index_entry_file_response(request, entry, data),
+            None => {
+                info!("proxy: index entry for {index_entry} is not in the git index");
+                let json = format_json_error("index entry is not in the git index");
+                send_json_response(request, 404, json);
+            }
+        }
+        return;
+    }
+
+    // Serve the cached index entry without contacting the upstream registry.
+    if config.offline {
+        match fetch_offline_index_entry(index_entry.name(), config) {
+            Some((entry, _)) if entry.is_equivalent(&index_entry) => {
+                ctx.set_cache_result("offline-hit");
+                stats_count(&STATS.index_hits, 1);
+                send_index_entry_not_modified_response(request, &entry);
+            }
+            Some((entry, data)) => {
+                ctx.set_cache_result("offline-hit");
+                stats_count(&STATS.index_hits, 1);
+                send_index_entry_file_response(request, entry, data);
+            }
+            None => {
+                ctx.set_cache_result("offline-miss");
+                stats_count(&STATS.index_misses, 1);
+                send_offline_miss_response(request, &index_entry);
+            }
+        }
+        return;
+    }
+
+    // Try to serve the request from the local index cache first.
+    // NOTE: The index file cache can not be used without matching metadata.
+    if let Some(cached_entry) = metadata_fetch_index_entry(index_entry.name()) {
+        // Revalidate the fresh cache entries at the client request.
+        if cache_control.no_cache {
+            info!("proxy: client requested no-cache for {index_entry}, revalidating...");
+            log_cache_decision(&mut ctx, "client-no-cache", &index_entry);
+            forward_index_request(
+                request,
+                ctx,
+                index_entry,
+                Some(cached_entry),
+                config.clone(),
+            );
+            return;
+        }
+
+        // Recently expired cache entries are served while revalidating in the background.
+        let is_stale_servable = config.stale_ttl.is_some_and(|stale_ttl| {
+            !cached_entry.is_expired_with_ttl(&config.cache_ttl().saturating_add(stale_ttl))
+        });
+
+        if is_stale_servable && cached_entry.is_expired_with_ttl(&config.cache_ttl()) {
+            if cached_entry.is_equivalent(&index_entry) {
+                debug!("proxy: stale index metadata hit for {index_entry}");
+                log_cache_decision(&mut ctx, "stale-metadata-hit", &index_entry);
+                let auth = upstream_authorization(&request);
+                revalidate_index_entry_in_background(cached_entry.clone(), auth, config.clone());
+                send_index_entry_not_modified_response(request, &cached_entry);
+                return;
+            }
+
+            if let Some(data) = config.index_cache.fetch(&index_entry) {
+                debug!("proxy: stale index data cache hit for {index_entry}");
+                log_cache_decision(&mut ctx, "stale-data-hit", &index_entry);
+                let auth = upstream_authorization(&request);
+                revalidate_index_entry_in_background(cached_entry.clone(), auth, config.clone());
+                send_index_entry_file_response(request, cached_entry, data);
+                return;
+            }
+        }
+
+        // Expired cache entries require a new request to the upstream registry.
+        if cached_entry.is_expired_with_ttl(&config.cache_ttl()) {
+            info!("proxy: index cache expired for {index_entry}, refreshing...");
+            log_cache_decision(&mut ctx, "ttl-expired-refresh", &index_entry);
+            forward_index_request(
+                request,
+                ctx,
+                index_entry,
+                Some(cached_entry),
+                config.clone(),
+            );
+            return;
+        }
+
+        // Check for the index metadata cache hit via ETag and Last-Modified fields.
+        if cached_entry.is_equivalent(&index_entry) {
+            // Bound the worst case staleness of the metadata cache hits.
+            if let Some(max_age) = &config.max_metadata_age {
+                if cached_entry.is_expired_with_ttl(max_age) {
+                    info!("proxy: index metadata too old for {index_entry}, revalidating...");
+                    log_cache_decision(&mut ctx, "max-age-revalidate", &index_entry);
+                    forward_index_request(
+                        request,
+                        ctx,
+                        index_entry,
+                        Some(cached_entry),
+                        config.clone(),
+                    );
+                    return;
+                }
+            }
+
+            debug!("proxy: index metadata cache hit for {index_entry}");
+            log_cache_decision(&mut ctx, "fresh-metadata-hit", &index_entry);
+            send_index_entry_not_modified_response(request, &cached_entry);
+            return;
+        }
+
+        // Check for the index file cache hit next.
+        if let Some(data) = config.index_cache.fetch(&index_entry) {
+            debug!("proxy: index data cache hit for {index_entry}");
+            log_cache_decision(&mut ctx, "data-cache-hit", &index_entry);
+            send_index_entry_file_response(request, cached_entry, data);
+            return;
+        }
+    }
+
+    // Try to recreate the index entry metadata from the cached file mtime.
+    let mtimed_entry = config.index_cache.try_find(index_entry.name());
+
+    if let Some(entry) = &mtimed_entry {
+        let last_modified = entry.last_modified().unwrap();
+
+        info!(
+            "proxy: recreated index cache metadata for {entry} with Last-Modified: {last_modified}"
+        );
+        log_cache_decision(&mut ctx, "cold-reconstruct", entry);
+    } else {
+        log_cache_decision(&mut ctx, "full-forward", &index_entry);
+    }
+
+    // Fall back to forwarding the request to the upstream registry.
+    forward_index_request(request, ctx, index_entry, mtimed_entry, config.clone());
+}
+
+/// Gets the lowercase crate name from the crate publishing request body.
+///
+/// The body starts with the 32-bit little endian length of the JSON metadata.
+fn publish_crate_name(body: &[u8]) -> Option<String> {
+    let (len, rest) = body.split_first_chunk::<4>()?;
+    let len = usize::try_from(u32::from_le_bytes(*len)).ok()?;
+    let metadata: serde_json::Value = serde_json::from_slice(rest.get(..len)?).ok()?;
+    let name = metadata.get("name")?.as_str()?;
+
+    is_valid_crate_name(name).then(|| name.to_ascii_lowercase())
+}
+
+/// Forwards the crate publishing request to the upstream registry API.
+///
+/// The upstream response is passed on to the client as is. The published
+/// crate index entry metadata is dropped on success, so the new version
+/// is fetched on the next index request.
+fn forward_publish_request(request: Request, config: ProxyConfig) {
+    if config.offline {
+        let json = format_json_error("publishing is not available in the offline mode");
+        send_json_response(request, 503, json);
+        return;
+    }
+
+    if is_maintenance_mode() {
+        send_maintenance_response(request, "crate publishing");
+        return;
+    }
+
+    let thread_proc = move |mut request: WorkerRequest| {
+        let Some(body) = read_request_body(&mut request, PUBLISH_REQUEST_BODY_LIMIT) else {
+            send_error_response(request.into_request(), 413);
+            return;
+        };
+
+        let Some((request, _fetch_slot)) =
+            acquire_fetch_slot(request, FetchKind::Crate, "crate publishing")
+        else {
+            return;
+        };
+
+        let _permit = match upstream_request_acquire() {
+            Ok(permit) => permit,
+            Err(reason) => {
+                warn!("fetch: {reason} for crate publishing");
+                send_json_response(request.into_request(), 503, format_json_error(reason));
+                return;
+            }
+        };
+
+        let url = config.upstream_url.join(CRATES_PUBLISH_PATH).unwrap();
+        let mut upstream_request = ureq_agent().put(url.as_str());
+
+        // The upstream registry authenticates the publisher by the API token.
+        if let Some(header) = request
+            .headers()
+            .iter()
+            .find(|header| header.field.equiv("Authorization"))
+        {
+            upstream_request = upstream_request.set("Authorization", header.value.as_str());
+        }
+
+        let name = publish_crate_name(&body);
+        info!(
+            "fetch: publishing crate {} ({} bytes)",
+            name.as_deref().unwrap_or("(unknown)"),
+            body.len()
+        );
+
+        // Publishing is not idempotent, so the failures are never retried.
+        let response = match upstream_request.send_bytes(&body) {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            Err(ureq::Error::Transport(err)) => {
+                stats_count(&STATS.upstream_errors, 1);
+                error!("fetch: connection failed: {err}");
+                send_json_response(request.into_request(), 502, format_json_error(err));
+                return;
+            }
+        };
+
+        let code = response.status();
+
+        if code == 200 {
+            if let Some(name) = &name {
+                info!("fetch: published crate {name}, dropping its cached index metadata");
+                metadata_invalidate_index_entry(&IndexEntry::new(name));
+            }
+        } else {
+            warn!("fetch: upstream returned HTTP status {code} for crate publishing");
+        }
+
+        match response.into_string() {
+            Ok(json) => send_json_response(request.into_request(), code, json),
+            Err(err) => {
+                error!("fetch: failed to read the crate publishing response: {err}");
+                send_json_response(request.into_request(), 502, format_json_error(err));
+            }
+        }
+    };
+
+    let thread_name = "worker-publish-crate".to_owned();
+    let subject = "crate publishing".to_owned();
+    spawn_worker(request, thread_name, subject, thread_proc);
+}
+
+/// Looks up the client label by the token from the `Authorization` header.
+fn authenticate_client(request: &Request) -> Option<String> {
+    let header = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))?;
+
+    let token = header.value.as_str();
+    let token = token.strip_prefix("Bearer ").unwrap_or(token);
+
+    client_token_label(token)
+}
+
+/// Reads the whole request body up to the size limit.
+///
+/// Sends the interim `100 Continue` response first if the client is waiting
+/// for it with the `Expect: 100-continue` header.
+/// Returns `None` if the request body is too large or can not be read.
+fn read_request_body(request: &mut Request, limit: u64) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+
+    request
+        .as_reader()
+        .take(limit + 1)
+        .read_to_end(&mut body)
+        .ok()?;
+
+    (body.len() as u64 <= limit).then_some(body)
+}
+
+/// Processes one admin API request.
+///
+/// The admin API is enabled by setting the admin token.
+fn handle_admin_request(mut request: Request, config: &ProxyConfig) {
+    let Some(admin_token) = &config.admin_token else {
+        send_error_response(request, 404);
+        return;
+    };
+
+    // The body is always read, since the clients sending `Expect: 100-continue`
+    // stall the connection unless it is read.
+    let Some(body) = read_request_body(&mut request, ADMIN_REQUEST_BODY_LIMIT) else {
+        send_error_response(request, 413);
+        return;
+    };
+
+    let authorized = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().strip_prefix("Bearer "))
+        .is_some_and(|token| constant_time_eq(token.as_bytes(), admin_token.as_bytes()));
+
+    if !authorized {
+        warn!(
+            "auth: rejected unauthorized admin request: {}",
+            request.url()
+        );
+        send_json_response(request, 401, format_json_error("admin token required"));
+        return;
+    }
+
+    dispatch_admin_request(request, &body, config);
+}
+
+/// Processes the admin API request received at the admin socket.
+///
+/// No admin token is required, the access is controlled by the socket file permissions.
+fn handle_admin_socket_request(mut request: Request, config: &ProxyConfig) {
+    let Some(body) = read_request_body(&mut request, ADMIN_REQUEST_BODY_LIMIT) else {
+        send_error_response(request, 413);
+        return;
+    };
+
+    dispatch_admin_request(request, &body, config);
+}
+
+/// Dispatches the authorized admin API request to its route handler.
+fn dispatch_admin_request(request: Request, body: &[u8], config: &ProxyConfig) {
+    let url = request.url().to_owned();
+    let path = url.strip_prefix(ADMIN_API_PATH).unwrap_or_default();
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+
+    match (request.method(), path) {
+        (Method::Post, "maintenance/on") => set_maintenance_mode(request, true),
+        (Method::Post, "maintenance/off") => set_maintenance_mode(request, false),
+        (Method::Get, "debug/resolve") => send_resolved_upstream_url(request, query, config),
+        (Method::Put, ADMIN_CACHE_TTL_PATH) => set_cache_ttl(request, body, config),
+        (Method::Post, path) if path.starts_with(ADMIN_REFRESH_PATH) => {
+            refresh_crate(
+                request,
+                path.strip_prefix(ADMIN_REFRESH_PATH).unwrap_or_default(),
+            );
+        }
+        (Method::Delete, path) if path.starts_with(ADMIN_PURGE_PATH) => {
+            purge_crate(
+                request,
+                path.strip_prefix(ADMIN_PURGE_PATH).unwrap_or_default(),
+                config,
+            );
+        }
+        _ => {
+            warn!(
+                "proxy: unknown admin API request: {} {url}",
+                request.method()
+            );
+            send_error_response(request, 404);
+        }
+    }
+}
+
+/// Switches the planned maintenance mode on or off.
+fn set_maintenance_mode(request: Request, maintenance: bool) {
+    MAINTENANCE_MODE.store(maintenance, Ordering::Relaxed);
+    warn!(
+        "proxy: maintenance mode is {}",
+        if maintenance { "on" } else { "off" }
+    );
+
+    send_json_response(request, 200, format!(r#"{{"maintenance":{maintenance}}}"#));
+}
+
+/// Parses the admin API cache TTL request body, e.g. `{"cache_ttl": 600}`.
+fn parse_cache_ttl_body(body: &[u8]) -> Option<u64> {
+    let json: serde_json::Value = serde_json::from_slice(body).ok()?;
+
+    // The maximum value is reserved for the configured TTL.
+    json.get("cache_ttl")?
+        .as_u64()
+        .filter(|&secs| secs != u64::MAX)
+}
+
+/// Updates the index entry cache TTL used by the new requests.
+fn set_cache_ttl(request: Request, body: &[u8], config: &ProxyConfig) {
+    let Some(cache_ttl_secs) = parse_cache_ttl_body(body) else {
+        let json = format_json_error("expected a JSON object with the cache_ttl field");
+        send_json_response(request, 400, json);
+        return;
+    };
+
+    let previous_secs = config.cache_ttl().as_secs();
+    RUNTIME_CACHE_TTL_SECS.store(cache_ttl_secs, Ordering::Relaxed);
+    warn!("cache: index entry TTL changed from {previous_secs} to {cache_ttl_secs} seconds");
+
+    let json = serde_json::json!({
+        "previous_cache_ttl": previous_secs,
+        "cache_ttl": cache_ttl_secs,
+    });
+    send_json_response(request, 200, json.to_string());
+}
+
+/// Checks if the proxy request path refers to the crate index entry or crate files.
+fn is_crate_request_path(path: &str, name: &str) -> bool {
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+
+    // Crate names are case-insensitive, as in the index.
+    if let Some(index_url) = path.strip_prefix(CRATES_INDEX_PATH) {
+        IndexEntry::try_from_index_url(index_url)
+            .is_some_and(|entry| entry.name().eq_ignore_ascii_case(name))
+    } else if let Some(crate_url) = path.strip_prefix(CRATES_API_PATH) {
+        CrateInfo::try_from_download_url(crate_url)
+            .is_some_and(|crate_info| crate_info.name().eq_ignore_ascii_case(name))
+    } else {
+        false
+    }
+}
+
+/// Clears the cached upstream HTTP 404 responses for the crate,
+/// so the next request for it is forwarded to the upstream server.
+fn refresh_crate(request: Request, name: &str) {
+    if name.is_empty() || name.contains('/') {
+        send_json_response(request, 400, format_json_error("invalid crate name"));
+        return;
+    }
+
+    let count = metadata_invalidate_not_found_matching(|path| is_crate_request_path(path, name));
+    warn!("proxy: refreshed crate {name}, cleared {count} cached not found statuses");
+
+    let json = serde_json::json!({ "crate": name, "cleared": count });
+    send_json_response(request, 200, json.to_string());
+}
+
+/// Removes the crate index entry and all its crate files from the cache.
+///
+/// The cached index entry metadata and upstream HTTP 404 responses
+/// for the crate are dropped too.
+fn purge_crate(request: Request, name: &str, config: &ProxyConfig) {
+    if !is_valid_crate_name(name) {
+        send_json_response(request, 400, format_json_error("invalid crate name"));
+        return;
+    }
+
+    // Index entry names are lowercase, unlike the crate file names.
+    let entry = IndexEntry::new(&name.to_ascii_lowercase());
+    let index_entry = config.index_cache.remove(&entry);
+    metadata_invalidate_index_entry(&entry);
+
+    let crate_files = config.crate_cache.remove(name);
+    let cleared = metadata_invalidate_not_found_matching(|path| is_crate_request_path(path, name));
+
+    warn!(
+        "cache: purged crate {name}, removed {} crate files{}",
+        crate_files.len(),
+        if index_entry {
+            " and the index entry"
+        } else {
+            ""
+        }
+    );
+
+    let json = serde_json::json!({
+        "crate": name,
+        "index_entry": index_entry,
+        "crate_files": crate_files,
+        "cleared": cleared,
+    });
+    send_json_response(request, 200, json.to_string());
+}
+
+/// Resolves the upstream server URL a proxy request path would be forwarded to.
+///
+/// Returns `None` for the unrecognized request paths.
+fn resolve_upstream_url(path: &str, config: &ProxyConfig) -> Option<Url> {
+    if let Some(index_url) = path.strip_prefix(CRATES_INDEX_PATH) {
+        if is_config_json_url(index_url) {
+            return config.index_url.join(CONFIG_JSON_ENDPOINT).ok();
+        }
+
+        let entry = IndexEntry::try_from_index_url(index_url)?;
+        Some(index_entry_url(&config.index_url, &entry))
+    } else if let Some(crate_url) = path.strip_prefix(CRATES_API_PATH) {
+        let crate_info = CrateInfo::try_from_download_url(crate_url)?;
+
+        Some(match &config.redirect_downloads_url {
+            Some(base_url) => {
+                crate_download_url(base_url, &crate_info, Some(REDIRECT_CRATE_URL_TEMPLATE))
+            }
+            None => crate_download_url(
+                &config.upstream_url,
+                &crate_info,
+                config.dl_upstream_template.as_deref(),
+            ),
+        })
+    } else {
+        None
+    }
+}
+
+/// Sends the upstream server URL resolved for the `path` query parameter
+/// without fetching it.
+fn send_resolved_upstream_url(request: Request, query: &str, config: &ProxyConfig) {
+    let Some((_, path)) = url::form_urlencoded::parse(query.as_bytes()).find(|(k, _)| k == "path")
+    else {
+        let json = format_json_error("missing path query parameter");
+        send_json_response(request, 400, json);
+        return;
+    };
+
+    let Some(upstream_url) = resolve_upstream_url(&path, config) else {
+        let json = format_json_error("unrecognized request path");
+        send_json_response(request, 404, json);
+        return;
+    };
+
+    let json = serde_json::json!({ "path": path, "upstream_url": upstream_url.as_str() });
+    send_json_response(request, 200, json.to_string());
+}
+
+/// Sends the liveness probe response.
+///
+/// Never contacts the upstream server.
+fn send_healthz_response(request: Request) {
+    let json = serde_json::json!({
+        "status": "ok",
+        "maintenance": is_maintenance_mode(),
+        "cache_entries": metadata_cache_len(),
+    });
+
+    send_json_response(request, 200, json.to_string());
+}
+
+/// Sends the readiness probe response.
+///
+/// The proxy server is ready if the cache storage is writable.
+fn send_readyz_response(request: Request, config: &ProxyConfig) {
+    let index_cache = config.index_cache.is_writable();
+    let crate_cache = config.crate_cache.is_writable();
+    let ready = index_cache && crate_cache;
+
+    let json = serde_json::json!({
+        "status": if ready { "ok" } else { "unavailable" },
+        "index_cache_writable": index_cache,
+        "crate_cache_writable": crate_cache,
+    });
+
+    send_json_response(request, if ready { 200 } else { 503 }, json.to_string());
+}
+
+/// Sends the Prometheus metrics response.
+fn send_metrics_response(request: Request) {
+    let content_type = METRICS_HTTP_CTYPE.parse::<Header>().unwrap();
+    let text = STATS.format_metrics(
+        active_workers(),
+        active_fetches(FetchKind::Index),
+        active_fetches(FetchKind::Crate),
+    );
+    let response = Response::from_string(text)
+        .with_header(content_type)
+        .with_chunked_threshold(usize::MAX);
+
+    request.respond(response).unwrap_or_else(log_send_error);
+}
+
+/// Processes one HTTP GET or HEAD request.
+///
+/// Only registry index and download API requests are supported.
+fn handle_get_request(request: Request, ctx: RequestContext, config: &ProxyConfig) {
+    let url = request.url().to_owned();
+
+    // Health probes and metrics are served to unauthenticated clients too.
+    if url == HEALTHZ_PATH {
+        send_healthz_response(request);
+        return;
+    }
+    if url == READYZ_PATH {
+        send_readyz_response(request, config);
+        return;
+    }
+    if url == METRICS_PATH {
+        send_metrics_response(request);
+        return;
+    }
+
+    if config.client_tokens_file.is_some() {
+        let addr = request.remote_addr().map(ToString::to_string);
+        let addr = addr.as_deref().unwrap_or("unknown");
+
+        if !client_tokens_available() {
+            if config.auth_fail_policy == AuthFailPolicy::Closed {
+                error!("auth: client tokens unavailable, rejected request from {addr}: {url}");
+                let json = format_json_error("authentication unavailable");
+                send_json_response(request, 503, json);
+                return;
+            }
+
+            warn!("auth: client tokens unavailable, fail-open allowed request from {addr}: {url}");
+        } else if let Some(label) = authenticate_client(&request) {
+            info!("auth: client {label} requested {url}");
+        } else {
+            warn!("auth: rejected unauthenticated request from {addr}: {url}");
+            send_json_response(request, 401, format_json_error("authentication required"));
+            return;
+        }
+    }
+
+    if let Some(index_url) = url.strip_prefix(CRATES_INDEX_PATH) {
+        handle_index_request(request, ctx, index_url, config);
+    } else if let Some(crate_url) = url.strip_prefix(CRATES_API_PATH) {
+        handle_download_request(request, ctx, crate_url, config);
+    } else {
+        warn!("proxy: unknown index or download API path: {url}");
+        send_error_response(request, 404);
+    };
+}
+
+/// Limits the number of concurrent requests from the same client.
+///
+/// Takes a request slot for the client IP address and attaches it to the request context,
+/// or refuses the request with HTTP 503 if the client has too many requests in progress.
+fn acquire_client_slot(
+    request: Request,
+    ctx: &mut RequestContext,
+    config: &ProxyConfig,
+) -> Option<Request> {
+    if let (Some(max), Some(addr)) = (config.max_requests_per_client, request.remote_addr()) {
+        let Some(slot) = ClientSlot::acquire(addr.ip(), max) else {
+            warn!("proxy: too many concurrent requests from {addr}");
+            let json = format_json_error("too many concurrent requests");
+            send_json_response(request, 503, json);
+            return None;
+        };
+
+        ctx.set_client_slot(slot);
+    }
+
+    Some(request)
+}
+
+/// Periodically saves the index entry metadata cache into a file.
+fn start_metadata_persist_thread(path: PathBuf, interval: Duration) {
+    let thread_proc = move || loop {
+        std::thread::sleep(interval);
+
+        match metadata_save(&path) {
+            Ok(count) => debug!("cache: saved {count} index metadata entries"),
+            Err(e) => error!("cache: failed to save index metadata: {e}"),
+        }
+    };
+
+    std::thread::Builder::new()
+        .name("metadata-persist".to_owned())
+        .spawn(thread_proc)
+        .expect("failed to spawn the metadata persist thread");
+}
+
+/// Periodically logs the cache activity statistics.
+fn start_stats_log_thread(interval: Duration) {
+    let thread_proc = move || {
+        let mut last = STATS.snapshot();
+
+        loop {
+            std::thread::sleep(interval);
+
+            let total = STATS.snapshot();
+            let recent = total.since(&last);
+            last = total;
+
+            info!(
+                "proxy: stats: total: {total}; last {} s: {recent}; active workers: {} \
+                 (index fetches: {}, crate fetches: {})",
+                interval.as_secs(),
+                active_workers(),
+                active_fetches(FetchKind::Index),
+                active_fetches(FetchKind::Crate)
+            );
+        }
+    };
+
+    std::thread::Builder::new()
+        .name("stats-log".to_owned())
+        .spawn(thread_proc)
+        .expect("failed to spawn the stats log thread");
+}
+
+/// Creates the S3 bucket crate files cache configured by the AWS environment variables.
+fn open_s3_crate_cache(s3_url: &Url) -> Arc<dyn CrateCache> {
+    let region = env::var("AWS_REGION")
+        .or_else(|_| env::var("AWS_DEFAULT_REGION"))
+        .unwrap_or_else(|_| DEFAULT_S3_REGION.to_owned());
+
+    let endpoint = env::var("AWS_ENDPOINT_URL").ok().map(|s| {
+        Url::parse(&s).unwrap_or_else(|e| usage_error(format!("invalid S3 endpoint URL: {e}")))
+    });
+
+    let credentials = match (
+        env::var("AWS_ACCESS_KEY_ID"),
+        env::var("AWS_SECRET_ACCESS_KEY"),
+    ) {
+        (Ok(access_key), Ok(secret_key)) => Some(S3Credentials {
+            access_key,
+            secret_key,
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+        }),
+        _ => {
+            warn!("cache: no AWS credentials set, using anonymous S3 bucket access");
+            None
+        }
+    };
+
+    let cache = S3CrateCache::new(s3_url, endpoint.as_ref(), &region, credentials)
+        .unwrap_or_else(|e| usage_error(format!("invalid crate cache backend: {e}")));
+
+    info!("cache: using crates S3 bucket: {s3_url}");
+    Arc::new(cache)
+}
+
+/// Opens the SQLite index entry cache database.
+#[cfg(feature = "sqlite")]
+fn open_sqlite_index_cache(path: &Path) -> Arc<dyn IndexCache> {
+    info!("cache: using index database: {}", path.to_string_lossy());
+
+    match sqlite_cache::SqliteIndexCache::open(path) {
+        Ok(cache) => Arc::new(cache),
+        Err(e) => fatal_error(format!("cache: failed to open the index database: {e}")),
+    }
+}
+
+/// Reports the missing SQLite index entry cache support.
+#[cfg(not(feature = "sqlite"))]
+fn open_sqlite_index_cache(_path: &Path) -> Arc<dyn IndexCache> {
+    usage_error("SQLite index cache support is not enabled in this build")
+}
+
+/// Server listening address
+enum ListenAddress {
+    /// IP address + port
+    SocketAddr(String),
+    /// Unix domain socket path + live socket takeover wait time
+    UnixPath(String, Option<Duration>),
+    /// Abstract namespace Unix domain socket name
+    UnixAbstract(String),
+}
+
+/// Removes the stale Unix domain socket file left at the path, if any.
+///
+/// Waits up to `takeover_wait` for another server still listening
+/// at the socket to exit, and refuses to start if it does not.
+fn reap_stale_unix_socket(path: &Path, takeover_wait: Option<Duration>) {
+    let deadline = Instant::now() + takeover_wait.unwrap_or_default();
+    let mut waiting = false;
+
+    loop {
+        match UnixStream::connect(path) {
+            Ok(_) if Instant::now() >= deadline => fatal_error(format!(
+                "proxy: another server is listening at Unix socket {}",
+                path.to_string_lossy()
+            )),
+            Ok(_) => {
+                if !waiting {
+                    warn!(
+                        "proxy: waiting for another server listening at Unix socket {} to exit",
+                        path.to_string_lossy()
+                    );
+                    waiting = true;
+                }
+
+                std::thread::sleep(SOCKET_TAKEOVER_POLL_INTERVAL);
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => return,
+            Err(_) => break,
+        }
+    }
+
+    info!(
+        "proxy: reaping stale Unix socket file {}",
+        path.to_string_lossy()
+    );
+
+    std::fs::remove_file(path)
+        .unwrap_or_else(|e| warn!("proxy: failed to remove stale Unix socket file: {e}"));
+}
+
+/// Binds a Unix domain socket in the Linux abstract namespace.
+#[cfg(target_os = "linux")]
+fn bind_abstract_unix_socket(name: &str) -> std::io::Result<UnixListener> {
+    use std::os::linux::net::SocketAddrExt;
+
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    UnixListener::bind_addr(&addr)
+}
+
+/// Reports the missing abstract namespace Unix domain socket support.
+#[cfg(not(target_os = "linux"))]
+fn bind_abstract_unix_socket(_name: &str) -> std::io::Result<UnixListener> {
+    Err(std::io::Error::new(
+        ErrorKind::Unsupported,
+        "abstract Unix domain sockets are only supported on Linux",
+    ))
+}
+
+/// Waits up to `timeout` for the running worker threads to finish.
+///
+/// Returns `false` if some worker threads are still running.
+fn drain_workers(timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+
+    while active_workers() > 0 {
+        if Instant::now() >= deadline {
+            return false;
+        }
+
+        std::thread::sleep(SHUTDOWN_POLL_INTERVAL);
+    }
+
+    true
+}
+
+/// Formats the effective proxy server configuration as a JSON object.
+///
+/// The admin token is redacted, only its presence is shown.
+fn format_config_json(
+    listen_addr: &ListenAddress,
+    cache_dir: &Path,
+    config: &ProxyConfig,
+) -> serde_json::Value {
+    let listen = match listen_addr {
+        ListenAddress::SocketAddr(addr) => addr.clone(),
+        ListenAddress::UnixPath(path, _) => format!("unix:{path}"),
+        ListenAddress::UnixAbstract(name) => format!("unix:@{name}"),
+    };
+
+    let secs = |ttl: Option<Duration>| ttl.map(|ttl| ttl.as_secs());
+    let path = |path: &Option<PathBuf>| {
+        path.as_ref()
+            .map(|path| path.to_string_lossy().into_owned())
+    };
+
+    serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "listen": listen,
+        "proxy_url": config.proxy_url.as_str(),
+        "index_url": config.index_url.as_str(),
+        "upstream_url": config.upstream_url.as_str(),
+        "dl_upstream_template": config.dl_upstream_template,
+        "redirect_downloads_url": config.redirect_downloads_url.as_ref().map(Url::as_str),
+        "cache_dir": cache_dir.to_string_lossy(),
+        "static_crates_dir": path(&config.static_crates_dir),
+        "git_index_dir": path(&config.git_index_dir),
+        "metadata_file": path(&config.metadata_file),
+        "offline": config.offline,
+        "cache_ttl": config.cache_ttl().as_secs(),
+        "stale_ttl": secs(config.stale_ttl),
+        "prefetch_crates": config.prefetch_crates,
+        "empty_index_for_missing": config.empty_index_for_missing,
+        "max_metadata_age": secs(config.max_metadata_age),
+        "snapshot_time": config.snapshot_time.map(httpdate::fmt_http_date),
+        "respect_client_cache_control": config.respect_client_cache_control,
+        "emit_sri": config.emit_sri,
+        "gzip_index_responses": GZIP_INDEX_RESPONSES.load(Ordering::Relaxed),
+        "per_crate_metrics": stats_crate_downloads_enabled(),
+        "client_tokens_file": path(&config.client_tokens_file),
+        "auth_fail_policy": config.auth_fail_policy.as_str(),
+        "admin_api": config.admin_token.is_some() || config.admin_socket.is_some(),
+        "admin_socket": config.admin_socket,
+        "max_requests_per_client": config.max_requests_per_client,
+        "verbose_upstream_errors": config.verbose_upstream_errors,
+        "slow_request_threshold_ms": config.slow_request_threshold.map(|t| t.as_millis()),
+        "large_index_entry_size": config.large_index_entry_size,
+        "stream_chunk_size": config.stream_chunk_size,
+        "shutdown_timeout": config.shutdown_timeout.as_secs(),
+    })
+}
+
+/// Starts the HTTP server listening at the address.
+fn bind_server(listen_addr: &ListenAddress) -> Result<Server, Box<dyn Error + Send + Sync>> {
+    match listen_addr {
+        ListenAddress::SocketAddr(addr) => {
+            info!("proxy: starting HTTP server at: {addr}");
+            Server::http(addr)
+        }
+        ListenAddress::UnixPath(path, takeover_wait) => {
+            info!("proxy: starting HTTP server at Unix socket {path}");
+            let path = Path::new(path);
+            // Reap stale socket files before binding.
+            reap_stale_unix_socket(path, *takeover_wait);
+            Server::http_unix(path)
+        }
+        ListenAddress::UnixAbstract(name) => {
+            info!("proxy: starting HTTP server at abstract Unix socket @{name}");
+            // Abstract sockets have no files to reap.
+            bind_abstract_unix_socket(name)
+                .map_err(Into::into)
+                .and_then(|listener| Server::from_listener(listener, None))
+        }
+    }
+}
+
+/// Gets the delay before the next HTTP server restart attempt
+/// after the number of consecutive accept failures given.
+fn accept_retry_delay(failures: u32) -> Duration {
+    ACCEPT_RETRY_BASE_DELAY
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(ACCEPT_RETRY_MAX_DELAY)
+}
+
+/// Stops the HTTP server and releases its listening socket.
+fn close_server(server: Server) {
+    let is_abstract = server
+        .server_addr()
+        .to_unix()
+        .is_some_and(|addr| addr.as_pathname().is_none());
+
+    // tiny_http panics dropping the servers listening at abstract Unix sockets,
+    // so those are leaked instead: their accept thread has either exited
+    // after an accept error, or the process is about to exit anyway.
+    if is_abstract {
+        std::mem::forget(server);
+    } else {
+        drop(server);
+    }
+}
+
+/// Restarts the HTTP server after it failed to accept new connections.
+///
+/// The failed server is closed first, since closing a Unix socket server
+/// removes its socket file. Retries with exponential backoff, so exhausted
+/// file descriptors shed the new connections instead of spinning.
+/// Returns `None` on shutdown.
+fn restart_server(
+    listen_addr: &ListenAddress,
+    server: Server,
+    failures: &mut u32,
+    shutdown: &AtomicBool,
+) -> Option<Server> {
+    close_server(server);
+
+    while !shutdown.load(Ordering::Relaxed) {
+        *failures = failures.saturating_add(1);
+        std::thread::sleep(accept_retry_delay(*failures));
+
+        match bind_server(listen_addr) {
+            Ok(server) => return Some(server),
+            Err(e) => error!("proxy: failed to restart the HTTP server: {e}"),
+        }
+    }
+
+    None
+}
+
+/// Serves the admin API requests at the admin socket until shutdown.
+fn admin_loop(
+    admin_addr: &ListenAddress,
+    server: Server,
+    config: &ProxyConfig,
+    shutdown: &AtomicBool,
+) {
+    let mut accept_failures = 0;
+    let mut server = Some(server);
+
+    while !shutdown.load(Ordering::Relaxed) {
+        let Some(active_server) = &server else {
+            break;
+        };
+
+        let request = match active_server.recv_timeout(SHUTDOWN_POLL_INTERVAL) {
+            Ok(Some(request)) => request,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("proxy: failed to accept new admin API requests: {e}");
+
+                server = server.take().and_then(|server| {
+                    restart_server(admin_addr, server, &mut accept_failures, shutdown)
+                });
+                continue;
+            }
+        };
+
+        accept_failures = 0;
+
+        if request.url().starts_with(ADMIN_API_PATH) {
+            handle_admin_socket_request(request, config);
+        } else {
+            send_error_response(request, 404);
+        }
+    }
+
+    if let Some(server) = server {
+        close_server(server);
+    }
+}
+
+/// Runs HTTP proxy server until a shutdown signal is received.
+///
+/// Stops accepting new requests on `SIGTERM` or `SIGINT`, and waits for
+/// the requests in progress to complete before returning.
+fn main_loop(listen_addr: &ListenAddress, config: &ProxyConfig) {
+    let server = bind_server(listen_addr)
+        .unwrap_or_else(|e| fatal_error(format!("proxy: failed to start the HTTP server: {e}")));
+
+    // Reload the client tokens file on SIGHUP.
+    let reload = Arc::new(AtomicBool::new(false));
+
+    if config.client_tokens_file.is_some() {
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&reload))
+            .expect("failed to register the SIGHUP handler");
+    }
+
+    // Shut down gracefully on the first SIGTERM or SIGINT, and immediately on the second one.
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    for signal in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        signal_hook::flag::register_conditional_shutdown(
+            signal,
+            EXIT_RUNTIME_ERROR,
+            Arc::clone(&shutdown),
+        )
+        .and_then(|_| signal_hook::flag::register(signal, Arc::clone(&shutdown)))
+        .expect("failed to register the shutdown signal handlers");
+    }
+
+    let admin_thread = config.admin_socket.as_ref().map(|path| {
+        let admin_addr = ListenAddress::UnixPath(path.clone(), None);
+        let admin_server = bind_server(&admin_addr).unwrap_or_else(|e| {
+            fatal_error(format!("proxy: failed to start the admin API server: {e}"))
+        });
+
+        let config = config.clone();
+        let shutdown = Arc::clone(&shutdown);
+
+        std::thread::spawn(move || admin_loop(&admin_addr, admin_server, &config, &shutdown))
+    });
+
+    // Consecutive HTTP server accept failures
+    let mut accept_failures = 0;
+
+    // The HTTP server is only missing when shutting down during a restart.
+    let mut server = Some(server);
+
+    // Main HTTP request accept loop.
+    while !shutdown.load(Ordering::Relaxed) {
+        let Some(active_server) = &server else {
+            break;
+        };
+
+        let request = match active_server.recv_timeout(SHUTDOWN_POLL_INTERVAL) {
+            Ok(Some(request)) => request,
+            Ok(None) => continue,
+            Err(e) => {
+                // The HTTP server stops accepting new connections after an accept error,
+                // e.g. when the file descriptors are exhausted, so it has to be restarted.
+                error!("proxy: failed to accept new HTTP requests: {e}");
+
+                server = server.take().and_then(|server| {
+                    restart_server(listen_addr, server, &mut accept_failures, &shutdown)
+                });
+                continue;
+            }
+        };
+
+        accept_failures = 0;
+
+        if reload.swap(false, Ordering::Relaxed) {
+            if let Some(path) = &config.client_tokens_file {
+                info!("auth: reloading client tokens file");
+                client_tokens_load(path)
+                    .unwrap_or_else(|e| error!("auth: failed to reload client tokens: {e}"));
+
+                if !client_tokens_available() {
+                    warn!(
+                        "auth: client tokens unavailable, applying the fail-{} policy",
+                        config.auth_fail_policy.as_str()
+                    );
+                }
+            }
+        }
+
+        if request.url().starts_with(ADMIN_API_PATH) {
+            // The admin routes are only served at the admin socket when it is configured.
+            if config.admin_socket.is_some() {
+                warn!(
+                    "proxy: admin API request at the public listener: {}",
+                    request.url()
+                );
+                send_error_response(request, 404);
+            } else {
+                handle_admin_request(request, config);
+            }
+            continue;
+        }
+
+        // Crate publishing requests are passed through to the upstream registry.
+        if *request.method() == Method::Put && request.url() == CRATES_PUBLISH_PATH {
+            forward_publish_request(request, config.clone());
+            continue;
+        }
+
+        // Forbid non-downloading HTTP methods.
+        if !matches!(request.method(), Method::Get | Method::Head) {
+            warn!(
+                "proxy: unexpected download API method: {}",
+                request.method()
+            );
+            send_error_response(request, 403);
+            continue;
+        }
+
+        let mut ctx = RequestContext::new(request.url());
+
+        if let Some(threshold) = config.slow_request_threshold {
+            ctx.set_slow_threshold(threshold);
+        }
+
+        let Some(request) = acquire_client_slot(request, &mut ctx, config) else {
+            continue;
+        };
+
+        handle_get_request(request, ctx, config);
+    }
+
+    warn!("proxy: shutting down, waiting for the requests in progress to complete");
+    if let Some(server) = server {
+        close_server(server);
+    }
+
+    if let Some(admin_thread) = admin_thread {
+        admin_thread
+            .join()
+            .unwrap_or_else(|_| error!("proxy: admin API server thread panicked"));
+    }
+
+    if !drain_workers(config.shutdown_timeout) {
+        warn!(
+            "proxy: shutdown timeout expired with {} worker threads still running",
+            active_workers()
+        );
+    }
+
+    if let Some(path) = &config.metadata_file {
+        match metadata_save(path) {
+            Ok(count) => info!("cache: saved {count} index metadata entries"),
+            Err(e) => error!("cache: failed to save index metadata: {e}"),
+        }
+    }
+
+    info!("proxy: shutdown complete");
+}
+
+/// Prints the program version banner.
+fn version() {
+    let build = option_env!("CI_PIPELINE_ID");
+    let rev = option_env!("CI_COMMIT_SHORT_SHA");
+    let tag = option_env!("CI_COMMIT_REF_NAME");
+
+    if let (Some(build), Some(rev), Some(tag)) = (build, rev, tag) {
+        println!("crates-io-proxy {VERSION}+{build}.g{rev}.{tag}");
+    } else {
+        println!("crates-io-proxy {VERSION}");
+    }
+}
+
+/// Reports a fatal runtime error and exits.
+fn fatal_error(message: impl Display) -> ! {
+    error!("{message}");
+    std::process::exit(EXIT_RUNTIME_ERROR);
+}
+
+/// Reports a command line usage error and exits.
+fn usage_error(message: impl Display) -> ! {
+    eprintln!("crates-io-proxy: {message}");
+    eprintln!("Run `crates-io-proxy --help` for the list of options.");
+    std::process::exit(EXIT_USAGE_ERROR);
+}
+
+/// Parses an octal file mode string like `0750`.
+fn parse_file_mode(s: &str) -> Result<u32, ParseIntError> {
+    u32::from_str_radix(s.trim_start_matches("0o"), 8)
+}
+
+/// Parses a numeric `UID[:GID]` file owner string.
+fn parse_file_owner(s: &str) -> Result<(u32, Option<u32>), ParseIntError> {
+    match s.split_once(':') {
+        Some((uid, gid)) => Ok((uid.parse()?, Some(gid.parse()?))),
+        None => Ok((s.parse()?, None)),
+    }
+}
+
+/// Parses a snapshot timestamp given as UNIX seconds or an HTTP date.
+fn parse_timestamp(s: &str) -> Result<SystemTime, String> {
+    if let Ok(secs) = s.parse() {
+        return Ok(UNIX_EPOCH + Duration::from_secs(secs));
+    }
+
+    httpdate::parse_http_date(s).map_err(|e| e.to_string())
+}
+
+/// Reports a bad command line argument value and exits.
+fn bad_argument(what: &str, error: pico_args::Error) -> ! {
+    usage_error(format!("bad {what} argument: {error}"))
+}
+
+/// Checks if the configuration file entry is overridden by the command line
+/// arguments or the environment variables.
+fn is_config_entry_overridden(entry: &ConfigEntry, cli_args: &[OsString]) -> bool {
+    let name = entry.option_name();
+    let short = SHORT_OPTIONS
+        .iter()
+        .find(|(long, _)| *long == name)
+        .map(|&(_, c)| c);
+
+    // The short flags may be combined, e.g. `-vv`.
+    let is_short = |arg: &str| {
+        short.is_some_and(|c| {
+            arg.strip_prefix('-')
+                .is_some_and(|s| !s.is_empty() && s.chars().all(|x| x == c))
+        })
+    };
+
+    let on_cli = cli_args
+        .iter()
+        .filter_map(|arg| arg.to_str())
+        .any(|arg| arg == name || is_short(arg));
+
+    on_cli
+        || ENV_OPTIONS
+            .iter()
+            .any(|&(long, var)| long == name && env::var_os(var).is_some())
+}
+
+/// Prints the program invocation help page.
+fn usage() {
+    println!("Usage:\n    crates-io-proxy [options]\n");
+    println!("General options:");
+    println!("    -v, --verbose              print more debug info");
+    println!("        --config FILE          read options from the TOML file");
+    println!("        --print-config-json    print the effective configuration as JSON");
+    println!("    -h, --help                 print help and exit");
+    println!("    -V, --version              print version and exit");
+    println!("\nListen options:");
+    println!("    -L, --listen ADDRESS:PORT  address and port to listen at (0.0.0.0:3080)");
+    println!("        --listen-unix PATH     Unix domain socket path or @name to listen at");
+    println!("        --socket-takeover-wait SECONDS");
+    println!("                               wait for a live Unix socket to be released (0)");
+    println!("    -S, --proxy-url URL        this proxy server URL (http://localhost:3080/)");
+    println!("        --shutdown-timeout SECONDS");
+    println!("                               wait for requests in progress on exit (30)");
+    println!("        --close-after-download-bytes BYTES");
+    println!("                               close connections after larger crate downloads");
+    println!("        --stream-chunk-size BYTES");
+    println!("                               streamed crate download chunk size (64 KiB)");
+    println!("        --gzip-index-responses");
+    println!("                               compress index entries for gzip clients");
+    println!("\nUpstream options:");
+    println!("    -U, --upstream-url URL     upstream download URL (https://crates.io/)");
+    println!("    -I, --index-url URL        upstream index URL (https://index.crates.io/)");
+    println!("        --git-index-dir DIR    serve the index from a git index checkout");
+    println!("        --offline              never contact upstream, serve only cached data");
+    println!("        --dl-upstream-template TEMPLATE");
+    println!("                               upstream crate file URL path template");
+    println!("        --redirect-downloads BASE_URL");
+    println!("                               redirect crate cache misses to the URL");
+    println!("        --upstream-index-prefix PATH");
+    println!("                               upstream index path prefix (none)");
+    println!("        --upstream-qps N       limit upstream requests per second (unlimited)");
+    println!("        --max-upstream-concurrency N");
+    println!("                               limit concurrent upstream requests (unlimited)");
+    println!("        --max-retries N        retry failed upstream requests N times (0)");
+    println!("        --retry-base-delay MILLIS");
+    println!("                               first retry delay, doubled after each (100)");
+    println!("        --egress-proxy URL     send upstream requests via a proxy server");
+    println!("        --ca-cert FILE         trust extra upstream root certificates");
+    println!("\nCache options:");
+    println!("    -C, --cache-dir DIR        proxy cache directory (/var/cache/crates-io-proxy)");
+    println!("    -T, --cache-ttl SECONDS    index cache entry Time-to-Live in seconds (3600)");
+    println!("        --stale-ttl SECONDS    serve expired index entries while revalidating");
+    println!("        --negative-ttl SECONDS");
+    println!("                               cache upstream 404 statuses in seconds (60)");
+    println!("        --empty-index-for-missing");
+    println!("                               serve empty index entries for missing crates");
+    println!("        --cache-dir-mode MODE  cache directories octal permissions");
+    println!("        --cache-dir-owner UID[:GID]");
+    println!("                               cache directories owner user and group IDs");
+    println!("        --max-cache-size BYTES");
+    println!("                               evict least recently used crate files (unlimited)");
+    println!("        --mem-cache-size BYTES");
+    println!("                               keep hot crate files in memory (off)");
+    println!("        --cache-inline-threshold BYTES");
+    println!("                               largest crate file kept in memory (256 KiB)");
+    println!("        --prefetch-crates      download all versions of the fetched crates");
+    println!("        --seed-lockfile FILE   seed the cache from the Cargo.lock file");
+    println!("        --static-crate-dir DIR");
+    println!("                               read-only crate files mirror directory");
+    println!("        --metadata-backend redis://HOST[:PORT][/DB]");
+    println!("                               share index metadata via Redis");
+    println!("        --metadata-persist-interval SECONDS");
+    println!("                               save index metadata periodically");
+    println!("        --max-metadata-age SECONDS");
+    println!("                               revalidate older index metadata (unlimited)");
+    println!("        --respect-client-cache-control");
+    println!("                               honor client no-cache and no-store (off)");
+    println!("        --crate-cache-backend s3://BUCKET/PREFIX");
+    println!("                               store crate files in an S3 bucket");
+    println!("        --recompress-crates zstd");
+    println!("                               store crate files recompressed (off)");
+    println!("        --index-cache-format FORMAT");
+    println!("                               index cache storage: files, sqlite (files)");
+    println!("        --since TIMESTAMP      serve only the index cached before TIMESTAMP");
+    println!("        --on-cache-version-mismatch ACTION");
+    println!("                               fail or drain on cache format change (fail)");
+    println!("        --fsync-writes         flush cached files to disk after writing");
+    println!("\nObservability options:");
+    println!("        --log-cache-decisions  log the index cache decision for every request");
+    println!("        --verbose-upstream-errors");
+    println!("                               log upstream response headers on errors");
+    println!("        --log-slow-requests MILLIS");
+    println!("                               log requests slower than MILLIS (off)");
+    println!("        --warn-on-large-index-entry BYTES");
+    println!("                               warn about larger index entries (1048576)");
+    println!("        --stats-log-interval SECONDS");
+    println!("                               log cache activity statistics periodically");
+    println!("        --per-crate-metrics    expose the top crate download counters (off)");
+    println!("        --log-file PATH        write the log to the file (stderr)");
+    println!("        --log-rotate-size BYTES");
+    println!("                               rotate the log file at the size (off)");
+    println!("        --log-keep N           keep N rotated log files (5)");
+    println!("        --log-format text|json");
+    println!("                               log record format (text)");
+    println!("\nSecurity options:");
+    println!("        --client-tokens-file PATH");
+    println!("                               require client tokens listed in the file");
+    println!("        --auth-fail-policy closed|open");
+    println!("                               policy if the tokens file fails to load (closed)");
+    println!("        --admin-token TOKEN    enable the admin API with the bearer token");
+    println!("        --admin-socket PATH    serve the admin API at the Unix socket only");
+    println!("        --max-requests-per-client N");
+    println!("                               limit requests in progress per client (unlimited)");
+    println!("        --max-threads N        limit concurrent worker threads (unlimited)");
+    println!("        --max-inflight-index N");
+    println!("                               limit concurrent index fetches (256)");
+    println!("        --max-inflight-crates N");
+    println!("                               limit concurrent crate fetches (128)");
+    println!("        --emit-sri             send crate X-Content-Integrity headers (off)");
+    println!("\nEnvironment:");
+    println!("    INDEX_CRATES_IO_URL        same as --index-url option");
+    println!("    CRATES_IO_URL              same as --upstream-url option");
+    println!("    CRATES_IO_PROXY_URL        same as --proxy-url option");
+    println!("    CRATES_IO_PROXY_CACHE_DIR  same as --cache-dir option");
+    println!("    CRATES_IO_PROXY_CACHE_TTL  same as --cache-ttl option");
+    println!("    CRATES_IO_PROXY_ADMIN_TOKEN");
+    println!("                               same as --admin-token option");
+    println!("    HTTPS_PROXY                same as --egress-proxy option");
+    println!("\nExit status:");
+    println!("    0                          success");
+    println!("    1                          runtime error");
+    println!("    2                          invalid command line or environment");
+}
+
+fn main() {
+    let start = Instant::now();
+    let start_time = SystemTime::now();
+
+    let index_crates_io_url =
+        env::var("INDEX_CRATES_IO_URL").unwrap_or_else(|_| INDEX_CRATES_IO_URL.to_string());
+    let crates_io_url = env::var("CRATES_IO_URL").unwrap_or_else(|_| CRATES_IO_URL.to_string());
+    let default_proxy_url =
+        env::var("CRATES_IO_PROXY_URL").unwrap_or_else(|_| DEFAULT_PROXY_URL.to_string());
+    let default_cache_dir =
+        env::var("CRATES_IO_PROXY_CACHE_DIR").unwrap_or_else(|_| DEFAULT_CACHE_DIR.to_string());
+    let default_cache_ttl_secs: u64 =
+        env::var("CRATES_IO_PROXY_CACHE_TTL").map_or(DEFAULT_CACHE_TTL_SECS, |s| {
+            s.parse().unwrap_or_else(|e| {
+                usage_error(format!("bad CRATES_IO_PROXY_CACHE_TTL value: {e}"))
+            })
+        });
+
+    let default_admin_token = env::var("CRATES_IO_PROXY_ADMIN_TOKEN").ok();
+    let default_egress_proxy = env::var("HTTPS_PROXY")
+        .or_else(|_| env::var("https_proxy"))
+        .ok();
+
+    let mut verbose: u32 = 0;
+    let mut args = Arguments::from_env();
+
+    if args.contains(["-h", "--help"]) {
+        usage();
+        return;
+    }
+
+    if args.contains(["-V", "--version"]) {
+        version();
+        return;
+    }
+
+    let config_file_path: Option<PathBuf> = args
+        .opt_value_from_str("--config")
+        .unwrap_or_else(|e| bad_argument("configuration file path", e));
+
+    let config_entries = match &config_file_path {
+        Some(path) => load_config(path).unwrap_or_else(|e| {
+            usage_error(format!(
+                "bad configuration file {}: {e}",
+                path.to_string_lossy()
+            ))
+        }),
+        None => Vec::new(),
+    };
+
+    // The command line options and the environment variables take precedence.
+    let cli_args = args.finish();
+    let config_entries: Vec<ConfigEntry> = config_entries
+        .into_iter()
+        .filter(|entry| !is_config_entry_overridden(entry, &cli_args))
+        .collect();
+
+    let config_args = config_entries.iter().flat_map(ConfigEntry::to_args);
+    args = Arguments::from_vec(cli_args.into_iter().chain(config_args).collect());
+
+    while args.contains(["-v", "--verbose"]) {
+        verbose += 1;
+    }
+
+    let log_cache_decisions = args.contains("--log-cache-decisions");
+    let fsync_writes = args.contains("--fsync-writes");
+    let verbose_upstream_errors = args.contains("--verbose-upstream-errors");
+    let respect_client_cache_control = args.contains("--respect-client-cache-control");
+    let emit_sri = args.contains("--emit-sri");
+    let offline = args.contains("--offline");
+    let gzip_index_responses = args.contains("--gzip-index-responses");
+    let per_crate_metrics = args.contains("--per-crate-metrics");
+    let print_config_json = args.contains("--print-config-json");
+    let prefetch_crates = args.contains("--prefetch-crates");
+    let empty_index_for_missing = args.contains("--empty-index-for-missing");
+
+    let listen_addr_unix: Option<String> = args
+        .opt_value_from_str("--listen-unix")
+        .unwrap_or_else(|e| bad_argument("listen socket path", e));
+
+    let socket_takeover_secs: Option<u64> = args
+        .opt_value_from_str("--socket-takeover-wait")
+        .unwrap_or_else(|e| bad_argument("socket takeover wait time", e));
+
+    let listen_addr_ip = args
+        .opt_value_from_str(["-L", "--listen"])
+        .unwrap_or_else(|e| bad_argument("listen address", e))
+        .unwrap_or_else(|| LISTEN_ADDRESS.to_string());
+
+    let index_url_string = args
+        .opt_value_from_str(["-I", "--index-url"])
+        .unwrap_or_else(|e| bad_argument("upstream index URL", e))
+        .unwrap_or(index_crates_io_url);
+
+    let upstream_url_string = args
+        .opt_value_from_str(["-U", "--upstream-url"])
+        .unwrap_or_else(|e| bad_argument("upstream download URL", e))
+        .unwrap_or(crates_io_url);
+
+    let proxy_url_string = args
+        .opt_value_from_str(["-S", "--proxy-url"])
+        .unwrap_or_else(|e| bad_argument("proxy URL", e))
+        .unwrap_or(default_proxy_url);
+
+    let cache_dir_string = args
+        .opt_value_from_str(["-C", "--cache-dir"])
+        .unwrap_or_else(|e| bad_argument("cache directory", e))
+        .unwrap_or(default_cache_dir);
+
+    let cache_ttl_secs: u64 = args
+        .opt_value_from_str(["-T", "--cache-ttl"])
+        .unwrap_or_else(|e| bad_argument("cache TTL", e))
+        .unwrap_or(default_cache_ttl_secs);
+
+    let stale_ttl_secs: Option<u64> = args
+        .opt_value_from_str("--stale-ttl")
+        .unwrap_or_else(|e| bad_argument("stale index entry TTL", e));
+
+    let negative_ttl_secs: u64 = args
+        .opt_value_from_str("--negative-ttl")
+        .unwrap_or_else(|e| bad_argument("negative cache TTL", e))
+        .unwrap_or(DEFAULT_NEGATIVE_TTL_SECS);
+
+    let index_prefix: Option<String> = args
+        .opt_value_from_str("--upstream-index-prefix")
+        .unwrap_or_else(|e| bad_argument("upstream index prefix", e));
+
+    let dl_upstream_template: Option<String> = args
+        .opt_value_from_str("--dl-upstream-template")
+        .unwrap_or_else(|e| bad_argument("upstream download template", e));
+
+    let redirect_downloads_string: Option<String> = args
+        .opt_value_from_str("--redirect-downloads")
+        .unwrap_or_else(|e| bad_argument("download redirect URL", e));
+
+    let seed_lockfile: Option<String> = args
+        .opt_value_from_str("--seed-lockfile")
+        .unwrap_or_else(|e| bad_argument("lock file path", e));
+
+    let static_crates_dir_string: Option<String> = args
+        .opt_value_from_str("--static-crate-dir")
+        .unwrap_or_else(|e| bad_argument("static crate directory", e));
+
+    let git_index_dir_string: Option<String> = args
+        .opt_value_from_str("--git-index-dir")
+        .unwrap_or_else(|e| bad_argument("git index directory", e));
+
+    let egress_proxy: Option<String> = args
+        .opt_value_from_str("--egress-proxy")
+        .unwrap_or_else(|e| bad_argument("egress proxy URL", e))
+        .or(default_egress_proxy);
+
+    let ca_cert_string: Option<String> = args
+        .opt_value_from_str("--ca-cert")
+        .unwrap_or_else(|e| bad_argument("CA certificate file", e));
+
+    let upstream_qps: Option<u32> = args
+        .opt_value_from_str("--upstream-qps")
+        .unwrap_or_else(|e| bad_argument("upstream QPS", e));
+
+    let max_retries: Option<u32> = args
+        .opt_value_from_str("--max-retries")
+        .unwrap_or_else(|e| bad_argument("maximum upstream retries", e));
+
+    let retry_base_delay_millis: u64 = args
+        .opt_value_from_str("--retry-base-delay")
+        .unwrap_or_else(|e| bad_argument("retry base delay", e))
+        .unwrap_or(DEFAULT_RETRY_BASE_DELAY_MILLIS);
+
+    let max_upstream_concurrency: Option<usize> = args
+        .opt_value_from_str("--max-upstream-concurrency")
+        .unwrap_or_else(|e| bad_argument("maximum upstream concurrency", e));
+
+    let client_tokens_file: Option<PathBuf> = args
+        .opt_value_from_str("--client-tokens-file")
+        .unwrap_or_else(|e| bad_argument("client tokens file", e));
+
+    let auth_fail_policy: Option<String> = args
+        .opt_value_from_str("--auth-fail-policy")
+        .unwrap_or_else(|e| bad_argument("auth fail policy", e));
+
+    let admin_token: Option<String> = args
+        .opt_value_from_str("--admin-token")
+        .unwrap_or_else(|e| bad_argument("admin token", e))
+        .or(default_admin_token);
+
+    let admin_socket: Option<String> = args
+        .opt_value_from_str("--admin-socket")
+        .unwrap_or_else(|e| bad_argument("admin socket path", e));
+
+    let max_requests_per_client: Option<usize> = args
+        .opt_value_from_str("--max-requests-per-client")
+        .unwrap_or_else(|e| bad_argument("max requests per client", e));
+
+    let max_threads: Option<usize> = args
+        .opt_value_from_str("--max-threads")
+        .unwrap_or_else(|e| bad_argument("max threads", e));
+
+    let max_inflight_index: usize = args
+        .opt_value_from_str("--max-inflight-index")
+        .unwrap_or_else(|e| bad_argument("max inflight index fetches", e))
+        .unwrap_or(DEFAULT_MAX_INFLIGHT_INDEX);
+
+    let max_inflight_crates: usize = args
+        .opt_value_from_str("--max-inflight-crates")
+        .unwrap_or_else(|e| bad_argument("max inflight crate fetches", e))
+        .unwrap_or(DEFAULT_MAX_INFLIGHT_CRATES);
+
+    let cache_dir_mode: Option<u32> = args
+        .opt_value_from_fn("--cache-dir-mode", parse_file_mode)
+        .unwrap_or_else(|e| bad_argument("cache directory mode", e));
+
+    let cache_dir_owner: Option<(u32, Option<u32>)> = args
+        .opt_value_from_fn("--cache-dir-owner", parse_file_owner)
+        .unwrap_or_else(|e| bad_argument("cache directory owner", e));
+
+    let max_metadata_age_secs: Option<u64> = args
+        .opt_value_from_str("--max-metadata-age")
+        .unwrap_or_else(|e| bad_argument("max metadata age", e));
+
+    let crate_cache_backend: Option<Url> = args
+        .opt_value_from_str("--crate-cache-backend")
+        .unwrap_or_else(|e| bad_argument("crate cache backend", e));
+
+    let max_cache_size: Option<u64> = args
+        .opt_value_from_str("--max-cache-size")
+        .unwrap_or_else(|e| bad_argument("max cache size", e));
+
+    let mem_cache_size: Option<usize> = args
+        .opt_value_from_str("--mem-cache-size")
+        .unwrap_or_else(|e| bad_argument("memory cache size", e));
+
+    let cache_inline_threshold: Option<usize> = args
+        .opt_value_from_str("--cache-inline-threshold")
+        .unwrap_or_else(|e| bad_argument("cache inline threshold", e));
+
+    let recompress_format: Option<String> = args
+        .opt_value_from_str("--recompress-crates")
+        .unwrap_or_else(|e| bad_argument("crate recompression format", e));
+
+    let version_mismatch_action: Option<String> = args
+        .opt_value_from_str("--on-cache-version-mismatch")
+        .unwrap_or_else(|e| bad_argument("cache version mismatch action", e));
+
+    let index_cache_format: Option<String> = args
+        .opt_value_from_str("--index-cache-format")
+        .unwrap_or_else(|e| bad_argument("index cache format", e));
+
+    let slow_request_millis: Option<u64> = args
+        .opt_value_from_str("--log-slow-requests")
+        .unwrap_or_else(|e| bad_argument("slow request threshold", e));
+
+    let large_index_entry_size: usize = args
+        .opt_value_from_str("--warn-on-large-index-entry")
+        .unwrap_or_else(|e| bad_argument("large index entry size", e))
+        .unwrap_or(DEFAULT_LARGE_INDEX_ENTRY_SIZE);
+
+    let snapshot_time: Option<SystemTime> = args
+        .opt_value_from_fn("--since", parse_timestamp)
+        .unwrap_or_else(|e| bad_argument("snapshot timestamp", e));
+
+    let metadata_backend: Option<Url> = args
+        .opt_value_from_str("--metadata-backend")
+        .unwrap_or_else(|e| bad_argument("metadata backend", e));
+
+    let metadata_persist_secs: Option<u64> = args
+        .opt_value_from_str("--metadata-persist-interval")
+        .unwrap_or_else(|e| bad_argument("metadata persist interval", e));
+
+    let stats_log_secs: Option<u64> = args
+        .opt_value_from_str("--stats-log-interval")
+        .unwrap_or_else(|e| bad_argument("stats log interval", e));
+
+    let shutdown_timeout_secs: u64 = args
+        .opt_value_from_str("--shutdown-timeout")
+        .unwrap_or_else(|e| bad_argument("shutdown timeout", e))
+        .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS);
+
+    let close_after_download_bytes: Option<u64> = args
+        .opt_value_from_str("--close-after-download-bytes")
+        .unwrap_or_else(|e| bad_argument("download size to close connections after", e));
+
+    let stream_chunk_size: usize = args
+        .opt_value_from_str("--stream-chunk-size")
+        .unwrap_or_else(|e| bad_argument("stream chunk size", e))
+        .unwrap_or(DEFAULT_STREAM_CHUNK_SIZE);
+
+    let log_file_path: Option<PathBuf> = args
+        .opt_value_from_str("--log-file")
+        .unwrap_or_else(|e| bad_argument("log file path", e));
+
+    let log_rotate_size: Option<u64> = args
+        .opt_value_from_str("--log-rotate-size")
+        .unwrap_or_else(|e| bad_argument("log rotate size", e));
+
+    let log_keep: Option<usize> = args
+        .opt_value_from_str("--log-keep")
+        .unwrap_or_else(|e| bad_argument("log keep count", e));
+
+    let log_format: Option<String> = args
+        .opt_value_from_str("--log-format")
+        .unwrap_or_else(|e| bad_argument("log format", e));
+
+    let mut unused_args = args.finish();
+    let mut unknown_config_keys = Vec::new();
+
+    // Unknown configuration file keys are ignored with a warning.
+    for entry in &config_entries {
+        let name = OsString::from(entry.option_name());
+
+        if let Some(pos) = unused_args.iter().position(|arg| *arg == name) {
+            let end = (pos + entry.to_args().len()).min(unused_args.len());
+            unused_args.drain(pos..end);
+            unknown_config_keys.push(&entry.key);
+        }
+    }
+
+    // Reject unknown options and stray arguments.
+    if let Some(arg) = unused_args.first() {
+        usage_error(format!("unexpected argument: {}", arg.to_string_lossy()));
+    }
+
+    let loglevel = match verbose {
+        0 => "warn",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+
+    let cache_decision_level = if log_cache_decisions {
+        LevelFilter::Info
+    } else {
+        LevelFilter::Off
+    };
+
+    let mut log_builder = LogBuilder::from_env(LogEnv::new().default_filter_or(loglevel));
+    log_builder.filter_module(CACHE_DECISION_LOG_TARGET, cache_decision_level);
+
+    match log_format.as_deref() {
+        None | Some("text") => (),
+        Some("json") => {
+            log_builder.format(|buf, record| write_json_record(buf, record));
+        }
+        Some(format) => usage_error(format!("unknown log format: {format}")),
+    }
+
+    if let Some(path) = log_file_path {
+        let keep = log_keep.unwrap_or(DEFAULT_LOG_KEEP);
+
+        let log_file =
+            RotatingLogFile::open(path.clone(), log_rotate_size, keep).unwrap_or_else(|e| {
+                usage_error(format!(
+                    "failed to open log file {}: {e}",
+                    path.to_string_lossy()
+                ))
+            });
+
+        log_builder.target(LogTarget::Pipe(Box::new(log_file)));
+    } else if log_rotate_size.is_some() || log_keep.is_some() {
+        usage_error("--log-rotate-size and --log-keep require --log-file");
+    }
+
+    log_builder.init();
+
+    if let Some(path) = &config_file_path {
+        info!(
+            "proxy: using configuration file: {}",
+            path.to_string_lossy()
+        );
+    }
+
+    for key in unknown_config_keys {
+        warn!("proxy: ignoring unknown configuration file key: {key}");
+    }
+
+    let index_url = Url::parse(&index_url_string)
+        .and_then(|url| apply_index_prefix(&url, index_prefix.as_deref().unwrap_or_default()))
+        .unwrap_or_else(|e| usage_error(format!("invalid upstream index URL: {e}")));
+
+    info!("proxy: using upstream index URL: {index_url}");
+
+    let upstream_url = Url::parse(&upstream_url_string)
+        .unwrap_or_else(|e| usage_error(format!("invalid upstream download URL: {e}")));
+
+    info!("proxy: using upstream download URL: {upstream_url}");
+
+    if let Some(template) = &dl_upstream_template {
+        info!("proxy: using upstream download URL template: {template}");
+    }
+
+    let redirect_downloads_url = redirect_downloads_string.map(|url_string| {
+        let mut url = Url::parse(&url_string)
+            .unwrap_or_else(|e| usage_error(format!("invalid download redirect URL: {e}")));
+
+        // Resolve the crate file paths relative to the last path segment.
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+
+        info!("proxy: redirecting crate cache misses to: {url}");
+        url
+    });
+
+    // Crate files are never downloaded from the upstream server with redirects.
+    if redirect_downloads_url.is_some() {
+        if dl_upstream_template.is_some() {
+            usage_error("--redirect-downloads conflicts with --dl-upstream-template");
+        }
+
+        if recompress_format.is_some() {
+            usage_error("--redirect-downloads conflicts with --recompress-crates");
+        }
+    }
+
+    let proxy_url = Url::parse(&proxy_url_string)
+        .unwrap_or_else(|e| usage_error(format!("invalid proxy URL: {e}")));
+
+    info!("proxy: using proxy server URL: {proxy_url}");
+
+    let cache_dir = PathBuf::from(cache_dir_string);
+    let index_dir = cache_dir.join("index");
+    let crates_dir = cache_dir.join("crates");
+    let cache_ttl = Duration::from_secs(cache_ttl_secs);
+
+    info!(
+        "cache: using index directory: {}",
+        index_dir.to_string_lossy()
+    );
+
+    info!(
+        "cache: using crates directory: {}",
+        crates_dir.to_string_lossy()
+    );
+
+    let drain_on_version_mismatch = match version_mismatch_action.as_deref() {
+        None | Some("fail") => false,
+        Some("drain") => true,
+        Some(action) => usage_error(format!("unknown cache version mismatch action: {action}")),
+    };
+
+    let version_mismatch = cache_version_mismatch(&cache_dir)
+        .unwrap_or_else(|e| fatal_error(format!("failed to read cache format version: {e}")));
+
+    if let Some(version) = version_mismatch {
+        if !drain_on_version_mismatch {
+            fatal_error(format!(
+                "cache format version {version} does not match the current version \
+                 {CACHE_FORMAT_VERSION}: remove the cache directory contents \
+                 or use the --on-cache-version-mismatch drain option"
+            ));
+        }
+
+        warn!("cache: draining the cache directory with format version {version}");
+        cache_drain_dir(&cache_dir)
+            .unwrap_or_else(|e| fatal_error(format!("failed to drain cache directory: {e}")));
+    }
+
+    for dir in [&cache_dir, &index_dir, &crates_dir] {
+        cache_create_dir(dir, cache_dir_mode, cache_dir_owner).unwrap_or_else(|e| {
+            fatal_error(format!(
+                "failed to create cache directory {}: {e}",
+                dir.to_string_lossy()
+            ))
+        });
+    }
+
+    cache_write_version(&cache_dir)
+        .unwrap_or_else(|e| fatal_error(format!("failed to write cache format version: {e}")));
+
+    // The start counter is only informational, so failing to update it is not fatal.
+    let start_count = cache_count_start(&cache_dir)
+        .inspect(|count| info!("proxy: server start number {count}"))
+        .inspect_err(|e| warn!("proxy: failed to update the server start counter: {e}"))
+        .ok();
+
+    stats_start_init(start, start_time, start_count);
+
+    info!("cache: using index entry TTL = {cache_ttl_secs} seconds");
+
+    let stale_ttl = stale_ttl_secs.map(Duration::from_secs);
+
+    if let Some(secs) = stale_ttl_secs {
+        info!("cache: serving expired index entries for {secs} more seconds while revalidating");
+    }
+
+    if negative_ttl_secs > 0 {
+        info!("cache: caching upstream not found statuses for {negative_ttl_secs} seconds");
+        metadata_not_found_init(Duration::from_secs(negative_ttl_secs));
+    }
+
+    if fsync_writes {
+        info!("cache: flushing cached files to the storage device");
+    }
+
+    let recompress_crates = match recompress_format.as_deref() {
+        None => false,
+        Some("zstd") if cfg!(feature = "zstd") => {
+            info!("cache: recompressing crate files with zstd");
+            true
+        }
+        Some("zstd") => usage_error("zstd crate recompression is not enabled in this build"),
+        Some(format) => usage_error(format!("unknown crate recompression format: {format}")),
+    };
+
+    if let Some(max_size) = max_cache_size {
+        if crate_cache_backend.is_some() {
+            usage_error("--max-cache-size conflicts with --crate-cache-backend");
+        }
+
+        info!("cache: limiting crates directory size to {max_size} bytes");
+        cache_limit_init(crates_dir.clone(), max_size);
+    }
+
+    let crate_cache: Arc<dyn CrateCache> = match crate_cache_backend {
+        Some(s3_url) => open_s3_crate_cache(&s3_url),
+        None => Arc::new(FileCrateCache::new(
+            crates_dir,
+            fsync_writes,
+            recompress_crates,
+        )),
+    };
+
+    if mem_cache_size.is_none() && cache_inline_threshold.is_some() {
+        usage_error("--cache-inline-threshold requires --mem-cache-size");
+    }
+
+    let crate_cache: Arc<dyn CrateCache> = match mem_cache_size {
+        Some(max_size) => {
+            let threshold = cache_inline_threshold.unwrap_or(DEFAULT_CACHE_INLINE_THRESHOLD);
+            info!(
+                "cache: keeping up to {max_size} bytes of crate files \
+                 of up to {threshold} bytes each in memory"
+            );
+            Arc::new(MemCrateCache::new(crate_cache, max_size, threshold))
+        }
+        None => crate_cache,
+    };
+
+    let index_cache: Arc<dyn IndexCache> = match index_cache_format.as_deref() {
+        None | Some("files") => Arc::new(FileIndexCache::new(index_dir, fsync_writes)),
+        Some("sqlite") => open_sqlite_index_cache(&cache_dir.join(INDEX_DATABASE_FILE_NAME)),
+        Some(format) => usage_error(format!("unknown index cache format: {format}")),
+    };
+
+    let max_metadata_age = max_metadata_age_secs.map(Duration::from_secs);
+
+    if let Some(secs) = max_metadata_age_secs {
+        info!("cache: using max index metadata age = {secs} seconds");
+    }
+
+    let static_crates_dir = static_crates_dir_string.map(PathBuf::from);
+
+    if let Some(dir) = &static_crates_dir {
+        info!(
+            "cache: using static crates mirror directory: {}",
+            dir.to_string_lossy()
+        );
+    }
+
+    let git_index_dir = git_index_dir_string.map(PathBuf::from);
+
+    if let Some(dir) = &git_index_dir {
+        if snapshot_time.is_some() {
+            usage_error("--git-index-dir conflicts with --since");
+        }
+
+        info!(
+            "proxy: using git index directory: {}",
+            dir.to_string_lossy()
+        );
+
+        // Pass the git index configuration file fields through as the upstream ones.
+        let json = std::fs::read_to_string(dir.join(CONFIG_JSON_ENDPOINT)).unwrap_or_else(|e| {
+            fatal_error(format!(
+                "proxy: failed to read the git index config file: {e}"
+            ))
+        });
+        upstream_config_json_store(&json);
+    }
+
+    if offline {
+        info!("proxy: offline mode, serving only the cached data");
+
+        if seed_lockfile.is_some() {
+            usage_error("--seed-lockfile conflicts with --offline");
+        }
+    }
+
+    if prefetch_crates {
+        info!("cache: prefetching all crate versions of the fetched index entries");
+    }
+
+    if empty_index_for_missing {
+        warn!("proxy: serving empty index entries instead of HTTP 404 for the missing crates");
+    }
+
+    if let Some(time) = snapshot_time {
+        info!(
+            "cache: pinning the index to the snapshot at {}",
+            httpdate::fmt_http_date(time)
+        );
+    }
+
+    if metadata_persist_secs == Some(0) {
+        usage_error("--metadata-persist-interval must be at least 1");
+    }
+
+    if metadata_backend.is_some() && metadata_persist_secs.is_some() {
+        usage_error("--metadata-persist-interval conflicts with --metadata-backend");
+    }
+
+    // The shared metadata backend keeps the index metadata by itself.
+    let metadata_file = metadata_backend
+        .is_none()
+        .then(|| cache_dir.join(METADATA_FILE_NAME));
+
+    if let Some(url) = metadata_backend {
+        info!("cache: using Redis index metadata backend at {url}");
+        let backend = RedisMetadata::new(url)
+            .unwrap_or_else(|e| usage_error(format!("invalid metadata backend: {e}")));
+        metadata_backend_init(Box::new(backend));
+    }
+
+    if let Some(metadata_file) = &metadata_file {
+        match metadata_load(metadata_file) {
+            Ok(count) => info!("cache: loaded {count} index metadata entries"),
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => warn!("cache: failed to load index metadata: {e}"),
+        }
+
+        if let Some(secs) = metadata_persist_secs {
+            info!("cache: saving index metadata every {secs} seconds");
+            start_metadata_persist_thread(metadata_file.clone(), Duration::from_secs(secs));
+        }
+    }
+
+    if let Some(secs) = stats_log_secs {
+        if secs == 0 {
+            usage_error("--stats-log-interval must be at least 1");
+        }
+
+        info!("proxy: logging cache statistics every {secs} seconds");
+        start_stats_log_thread(Duration::from_secs(secs));
+    }
+
+    if max_requests_per_client == Some(0) {
+        usage_error("--max-requests-per-client must be at least 1");
+    }
+
+    if let Some(max) = max_threads {
+        if max == 0 {
+            usage_error("--max-threads must be at least 1");
+        }
+
+        info!("proxy: limiting worker threads to {max}");
+        worker_limit_init(max);
+    }
+
+    if max_inflight_index == 0 {
+        usage_error("--max-inflight-index must be at least 1");
+    }
+
+    if max_inflight_crates == 0 {
+        usage_error("--max-inflight-crates must be at least 1");
+    }
+
+    info!("proxy: limiting concurrent index fetches to {max_inflight_index}");
+    fetch_limit_init(FetchKind::Index, max_inflight_index);
+
+    info!("proxy: limiting concurrent crate fetches to {max_inflight_crates}");
+    fetch_limit_init(FetchKind::Crate, max_inflight_crates);
+
+    if let Some(url) = egress_proxy.filter(|url| !url.is_empty()) {
+        // NOTE: ureq accepts some malformed proxy URLs silently.
+        let proxy = Url::parse(&url)
+            .map_err(|e| e.to_string())
+            .and_then(|url| match url.host_str() {
+                Some(_) => ureq::Proxy::new(url).map_err(|e| e.to_string()),
+                None => Err("missing proxy server host name".to_owned()),
+            })
+            .unwrap_or_else(|e| usage_error(format!("invalid egress proxy URL: {e}")));
+
+        // NOTE: The proxy URL may contain credentials, so it is not logged.
+        info!("fetch: sending upstream requests via the egress proxy server");
+        EGRESS_PROXY.get_or_init(|| proxy);
+    }
+
+    if !STREAM_CHUNK_SIZE_RANGE.contains(&stream_chunk_size) {
+        usage_error(format!(
+            "--stream-chunk-size must be between {} and {} bytes",
+            STREAM_CHUNK_SIZE_RANGE.start(),
+            STREAM_CHUNK_SIZE_RANGE.end()
+        ));
+    }
+
+    if let Some(max) = close_after_download_bytes {
+        info!("proxy: closing client connections after crate downloads over {max} bytes");
+        CLOSE_AFTER_DOWNLOAD_BYTES.get_or_init(|| max);
+    }
+
+    if per_crate_metrics {
+        info!("proxy: exposing the per-crate download metrics");
+        stats_crate_downloads_init();
+    }
+
+    if gzip_index_responses {
+        info!("proxy: compressing index entry responses for gzip clients");
+        GZIP_INDEX_RESPONSES.store(true, Ordering::Relaxed);
+    }
+
+    if let Some(path) = ca_cert_string.map(PathBuf::from) {
+        let tls_config = tls_config_with_ca_cert(&path).unwrap_or_else(|e| {
+            fatal_error(format!(
+                "fetch: failed to load CA certificate file {}: {e}",
+                path.to_string_lossy()
+            ))
+        });
+        TLS_CONFIG.get_or_init(|| tls_config);
+    }
+
+    if let Some(qps) = upstream_qps {
+        if qps == 0 {
+            usage_error("--upstream-qps must be at least 1");
+        }
+
+        info!("fetch: limiting upstream requests to {qps} per second");
+        upstream_rate_limit_init(qps);
+    }
+
+    if let Some(retries) = max_retries.filter(|&retries| retries > 0) {
+        let base_delay = Duration::from_millis(retry_base_delay_millis);
+        info!(
+            "fetch: retrying failed upstream requests up to {retries} times after {} ms",
+            base_delay.as_millis()
+        );
+        UPSTREAM_RETRIES.get_or_init(|| (retries, base_delay));
+    }
+
+    if let Some(max) = max_upstream_concurrency {
+        if max == 0 {
+            usage_error("--max-upstream-concurrency must be at least 1");
+        }
+
+        info!("fetch: limiting upstream requests to {max} at a time");
+        upstream_concurrency_init(max);
+    }
+
+    let slow_request_threshold = slow_request_millis.map(Duration::from_millis);
+
+    if let Some(millis) = slow_request_millis {
+        info!("proxy: logging requests slower than {millis} ms");
+    }
+
+    let auth_fail_policy = match auth_fail_policy.as_deref() {
+        None | Some("closed") => AuthFailPolicy::Closed,
+        Some("open") => AuthFailPolicy::Open,
+        Some(policy) => usage_error(format!("unknown auth fail policy: {policy}")),
+    };
+
+    if let Some(path) = &client_tokens_file {
+        info!("auth: using client tokens file: {}", path.to_string_lossy());
+
+        if let Err(e) = client_tokens_load(path) {
+            if auth_fail_policy == AuthFailPolicy::Closed {
+                fatal_error(format!("auth: failed to load the client tokens file: {e}"));
+            }
+
+            error!("auth: failed to load the client tokens file, failing open: {e}");
+        }
+    }
+
+    if let Some(path) = &admin_socket {
+        info!("auth: admin API enabled at Unix socket {path} only");
+    } else if admin_token.is_some() {
+        info!("auth: admin API enabled");
+    }
+
+    let config = ProxyConfig {
+        index_url,
+        upstream_url,
+        proxy_url,
+        index_cache,
+        crate_cache,
+        dl_upstream_template,
+        redirect_downloads_url,
+        static_crates_dir,
+        git_index_dir,
+        offline,
+        cache_ttl,
+        stale_ttl,
+        prefetch_crates,
+        empty_index_for_missing,
+        max_metadata_age,
+        respect_client_cache_control,
+        emit_sri,
+        client_tokens_file,
+        auth_fail_policy,
+        admin_token,
+        admin_socket,
+        max_requests_per_client,
+        verbose_upstream_errors,
+        slow_request_threshold,
+        large_index_entry_size,
+        stream_chunk_size,
+        snapshot_time,
+        metadata_file,
+        shutdown_timeout: Duration::from_secs(shutdown_timeout_secs),
+    };
+
+    let listen_addr = match listen_addr_unix {
+        Some(unix_path) => match unix_path.strip_prefix('@') {
+            Some(name) => ListenAddress::UnixAbstract(name.to_owned()),
+            None => {
+                ListenAddress::UnixPath(unix_path, socket_takeover_secs.map(Duration::from_secs))
+            }
+        },
+        None => ListenAddress::SocketAddr(listen_addr_ip),
+    };
+
+    if print_config_json {
+        println!("{}", format_config_json(&listen_addr, &cache_dir, &config));
+    }
+
+    if let Some(path) = seed_lockfile {
+        seed_cache_from_lockfile(Path::new(&path), &config);
+    }
+
+    // Start the main HTTP server.
+    main_loop(&listen_addr, &config)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_dir::TestDir;
+
+    use super::*;
+
+    #[test]
+    fn test_index_prefix() {
+        let entry = IndexEntry::new("serde");
+        let index_url = Url::parse("https://mirror.example/").unwrap();
+
+        let url = apply_index_prefix(&index_url, "").unwrap();
+        assert_eq!(
+            url.join(&entry.to_index_url()).unwrap().as_str(),
+            "https://mirror.example/se/rd/serde"
+        );
+
+        let url = apply_index_prefix(&index_url, "crates-index").unwrap();
+        assert_eq!(
+            url.join(&entry.to_index_url()).unwrap().as_str(),
+            "https://mirror.example/crates-index/se/rd/serde"
+        );
+        assert_eq!(
+            url.join(CONFIG_JSON_ENDPOINT).unwrap().as_str(),
+            "https://mirror.example/crates-index/config.json"
+        );
+
+        let index_url = Url::parse("https://mirror.example/registry/").unwrap();
+        let url = apply_index_prefix(&index_url, "/crates/index/").unwrap();
+        assert_eq!(
+            url.join(&entry.to_index_url()).unwrap().as_str(),
+            "https://mirror.example/registry/crates/index/se/rd/serde"
+        );
+    }
+
+    #[test]
+    fn test_crate_download_url() {
+        let crate_info = CrateInfo::new("serde", "1.0.0");
+
+        let site_url = Url::parse(CRATES_IO_URL).unwrap();
+        assert_eq!(
+            crate_download_url(&site_url, &crate_info, None).as_str(),
+            "https://crates.io/api/v1/crates/serde/1.0.0/download"
+        );
+
+        let site_url = Url::parse("https://static.crates.io/").unwrap();
+        let template = "crates/{crate}/{crate}-{version}.crate";
+        assert_eq!(
+            crate_download_url(&site_url, &crate_info, Some(template)).as_str(),
+            "https://static.crates.io/crates/serde/serde-1.0.0.crate"
+        );
+
+        let site_url = Url::parse("https://cdn.example.com/crates/").unwrap();
+        assert_eq!(
+            crate_download_url(&site_url, &crate_info, Some(REDIRECT_CRATE_URL_TEMPLATE)).as_str(),
+            "https://cdn.example.com/crates/serde/serde-1.0.0.crate"
+        );
+    }
+
+    #[test]
+    fn test_resolve_upstream_url() {
+        let config = ProxyConfig::default();
+
+        let resolve = |path| resolve_upstream_url(path, &config).map(String::from);
+
+        assert_eq!(
+            resolve("/index/se/rd/serde").as_deref(),
+            Some("https://index.crates.io/se/rd/serde")
+        );
+        assert_eq!(
+            resolve("/index/config.json").as_deref(),
+            Some("https://index.crates.io/config.json")
+        );
+        assert_eq!(
+            resolve("/api/v1/crates/serde/1.0.0/download").as_deref(),
+            Some("https://crates.io/api/v1/crates/serde/1.0.0/download")
+        );
+        assert_eq!(resolve("/index/serde"), None);
+        assert_eq!(resolve("/unknown"), None);
+    }
+
+    #[test]
+    fn test_is_crate_request_path() {
+        assert!(is_crate_request_path("/index/se/rd/serde", "serde"));
+        assert!(is_crate_request_path("/index/se/rd/serde", "Serde"));
+        assert!(is_crate_request_path(
+            "/api/v1/crates/serde/1.0.0/download",
+            "serde"
+        ));
+        assert!(!is_crate_request_path("/index/se/rd/serde_json", "serde"));
+        assert!(!is_crate_request_path(
+            "/api/v1/crates/serde_json/1.0.0/download",
+            "serde"
+        ));
+        assert!(!is_crate_request_path("/index/config.json", "serde"));
+    }
+
+    #[test]
+    fn test_fetch_git_index_entry() {
+        let dir = TestDir::new("git");
+        std::fs::create_dir_all(dir.join("3/s")).unwrap();
+        std::fs::write(dir.join("3/s/syn"), b"{}\n").unwrap();
+
+        let (entry, data) = fetch_git_index_entry(&dir, "syn").unwrap();
+        assert_eq!(entry.name(), "syn");
+        assert!(entry.last_modified().is_some());
+        assert_eq!(data, b"{}\n");
+
+        assert!(fetch_git_index_entry(&dir, "serde").is_none());
+    }
+
+    #[test]
+    fn test_index_data_records() {
+        let data = b"{\"name\":\"a\",\"vers\":\"0.1.0\",\"cksum\":\"abc\",\"future\":[1]}\n\
+                     \n\
+                     [\"not\",\"a\",\"record\"]\n\
+                     {\"name\":\"a\",\"vers\":\"0.2.0\"}\n\
+                     {\"name\":\"a\",\"vers\":\"0.3";
+
+        // Only the well-formed records are parsed, unknown fields included.
+        let versions: Vec<_> = index_data_records(data)
+            .map(|record| record["vers"].clone())
+            .collect();
+        assert_eq!(versions, ["0.1.0", "0.2.0"]);
+
+        assert!(index_data_has_version(data, "0.2.0"));
+        assert!(!index_data_has_version(data, "0.3"));
+        assert_eq!(index_data_checksum(data, "0.1.0").as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn test_index_data_prefetch_list() {
+        let data = b"{\"name\":\"Ab\",\"vers\":\"0.1.0\",\"cksum\":\"abc\",\"yanked\":false}\n\
+                     {\"name\":\"Ab\",\"vers\":\"0.2.0\",\"yanked\":true}\n\
+                     {\"name\":\"Ab\",\"vers\":\"../0.3.0\"}\n\
+                     {\"name\":\"Ab\",\"vers\":\"0.4.0\"}\n";
+
+        // Yanked and invalid crate versions are skipped.
+        assert_eq!(
+            index_data_prefetch_list(data),
+            [
+                (CrateInfo::new("Ab", "0.1.0"), Some("abc".to_owned())),
+                (CrateInfo::new("Ab", "0.4.0"), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_index_data_has_version() {
+        let data = b"{\"name\":\"a\",\"vers\":\"0.1.0\"}\n{\"name\":\"a\",\"vers\":\"0.2.0\"}\n";
+
+        assert!(index_data_has_version(data, "0.1.0"));
+        assert!(index_data_has_version(data, "0.2.0"));
+        assert!(!index_data_has_version(data, "0.3.0"));
+        assert!(!index_data_has_version(b"", "0.1.0"));
+    }
+
+    #[test]
+    fn test_index_data_checksum() {
+        let data = b"{\"name\":\"a\",\"vers\":\"0.1.0\",\"cksum\":\"abc\"}\n\
+                     {\"name\":\"a\",\"vers\":\"0.2.0\"}\n";
+
+        assert_eq!(index_data_checksum(data, "0.1.0").as_deref(), Some("abc"));
+        assert_eq!(index_data_checksum(data, "0.2.0"), None);
+        assert_eq!(index_data_checksum(data, "0.3.0"), None);
+    }
+
+    #[test]
+    fn test_cached_crate_checksum_mixed_case() {
+        let dir = TestDir::new("checksum");
+        let config = ProxyConfig {
+            index_cache: Arc::new(FileIndexCache::new(dir.to_path_buf(), false)),
+            emit_sri: true,
+            ..ProxyConfig::default()
+        };
+
+        // SHA-256 of the empty string
+        let checksum = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let data =
+            format!("{{\"name\":\"Inflector\",\"vers\":\"0.11.4\",\"cksum\":\"{checksum}\"}}\n");
+
+        // The index entries are stored under the lowercase crate names.
+        config
+            .index_cache
+            .store(&IndexEntry::new("inflector"), data.as_bytes());
+
+        let crate_info = CrateInfo::new("Inflector", "0.11.4");
+        assert_eq!(
+            cached_crate_checksum(&crate_info, &config).as_deref(),
+            Some(checksum)
+        );
+        assert!(crate_integrity_header(&crate_info, &config).is_some());
+    }
+
+    #[test]
+    fn test_crate_checksum_mismatch() {
+        let dir = TestDir::new("checksum-mismatch");
+        let crate_info = CrateInfo::new("checksum-mismatch-test", "0.1.0");
+
+        let upstream = Server::http("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.server_addr().to_ip().unwrap();
+
+        // The upstream crate file does not match the index entry checksum.
+        let upstream_thread = std::thread::spawn(move || {
+            let request = upstream.recv().unwrap();
+            request.respond(Response::from_string("corrupted")).unwrap();
+        });
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+
+        let config = ProxyConfig {
+            upstream_url: Url::parse(&format!("http://{upstream_addr}/")).unwrap(),
+            crate_cache: Arc::new(FileCrateCache::new(dir.join("crates"), false, false)),
+            index_cache: Arc::new(FileIndexCache::new(dir.join("index"), false)),
+            ..ProxyConfig::default()
+        };
+
+        // SHA-256 of the empty string
+        let checksum = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let data = format!(
+            "{{\"name\":\"checksum-mismatch-test\",\"vers\":\"0.1.0\",\"cksum\":\"{checksum}\"}}\n"
+        );
+        config
+            .index_cache
+            .store(&IndexEntry::new("checksum-mismatch-test"), data.as_bytes());
+
+        // The connection is left open, so the client has to time out.
+        let client = std::thread::spawn(move || {
+            let url = format!("http://{addr}/api/v1/crates/checksum-mismatch-test/0.1.0/download");
+            let agent = ureq::AgentBuilder::new()
+                .timeout_read(Duration::from_millis(500))
+                .build();
+            let response = agent.get(&url).call().ok()?;
+            response.into_string().ok()
+        });
+        handle_get_request(server.recv().unwrap(), RequestContext::new("bad"), &config);
+
+        // The response can not be turned into an error anymore, so it is cut short.
+        assert_eq!(client.join().unwrap(), None);
+        upstream_thread.join().unwrap();
+
+        // The corrupted crate file is not cached.
+        assert_eq!(config.crate_cache.fetch(&crate_info), None);
+    }
+
+    #[test]
+    fn test_format_sri_sha256() {
+        // SHA-256 of the empty string
+        let checksum = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+        assert_eq!(
+            format_sri_sha256(checksum).as_deref(),
+            Some("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=")
+        );
+        assert_eq!(format_sri_sha256(&checksum[..62]), None);
+        assert_eq!(format_sri_sha256("xyz"), None);
+        assert_eq!(format_sri_sha256(""), None);
+    }
+
+    #[test]
+    fn test_client_cache_control() {
+        let no_cache = ClientCacheControl {
+            no_cache: true,
+            no_store: false,
+        };
+        let no_store = ClientCacheControl {
+            no_cache: false,
+            no_store: true,
+        };
+
+        assert_eq!(ClientCacheControl::parse("no-cache"), no_cache);
+        assert_eq!(ClientCacheControl::parse("max-age=0, No-Cache"), no_cache);
+        assert_eq!(ClientCacheControl::parse("no-cache=\"ETag\""), no_cache);
+        assert_eq!(ClientCacheControl::parse("no-store"), no_store);
+        assert_eq!(ClientCacheControl::parse(" no-store , max-age=0"), no_store);
+
+        let both = ClientCacheControl::parse("no-cache, no-store");
+        assert!(both.no_cache && both.no_store);
+
+        assert_eq!(ClientCacheControl::parse("max-age=0"), Default::default());
+        assert_eq!(
+            ClientCacheControl::parse("no-transform"),
+            Default::default()
+        );
+        assert_eq!(ClientCacheControl::parse(""), Default::default());
+    }
+
+    #[test]
+    fn test_config_entry_overridden() {
+        let entries = crate::config_file::parse_config(
+            "listen = \"0.0.0.0:80\"\nverbose = true\nemit_sri = true\nlog_keep = 3",
+        )
+        .unwrap();
+        let cli_args: Vec<OsString> = ["-L", "127.0.0.1:80", "-vv", "--log-keep", "5"]
+            .into_iter()
+            .map(OsString::from)
+            .collect();
+
+        let overridden: Vec<bool> = entries
+            .iter()
+            .map(|entry| is_config_entry_overridden(entry, &cli_args))
+            .collect();
+        assert_eq!(overridden, [true, true, false, true]);
+    }
+
+    #[test]
+    fn test_accepts_gzip() {
+        assert!(accepts_gzip("gzip"));
+        assert!(accepts_gzip("deflate, GZIP;q=0.5"));
+        assert!(accepts_gzip("br, *"));
+        assert!(!accepts_gzip(""));
+        assert!(!accepts_gzip("identity"));
+        assert!(!accepts_gzip("x-gzip2"));
+        assert!(!accepts_gzip("gzip;q=0"));
+        assert!(!accepts_gzip("gzip; q=0.000, br"));
+    }
+
+    #[test]
+    fn test_gzip_encode() {
+        let data = b"{\"name\":\"gzip\",\"vers\":\"0.1.0\"}\n".repeat(100);
+        let gzip_data = gzip_encode(&data).unwrap();
+        assert!(gzip_data.len() < data.len());
+
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(gzip_data.as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn test_publish_crate_name() {
+        let metadata = br#"{"name":"Serde_Json","vers":"1.0.0"}"#;
+        let mut body = (metadata.len() as u32).to_le_bytes().to_vec();
+        body.extend_from_slice(metadata);
+        body.extend_from_slice(&[4, 0, 0, 0, 1, 2, 3, 4]);
+
+        assert_eq!(publish_crate_name(&body).as_deref(), Some("serde_json"));
+        assert_eq!(publish_crate_name(&body[..10]), None);
+        assert_eq!(publish_crate_name(&body[..3]), None);
+
+        let metadata = br#"{"name":"../x","vers":"1.0.0"}"#;
+        let mut body = (metadata.len() as u32).to_le_bytes().to_vec();
+        body.extend_from_slice(metadata);
+        assert_eq!(publish_crate_name(&body), None);
+    }
+
+    #[test]
+    fn test_format_config_json() {
+        let config = ProxyConfig {
+            admin_token: Some("secret".to_owned()),
+            stale_ttl: Some(Duration::from_secs(60)),
+            ..ProxyConfig::default()
+        };
+
+        let listen_addr = ListenAddress::UnixAbstract("proxy".to_owned());
+        let json = format_config_json(&listen_addr, Path::new("/tmp/cache"), &config);
+
+        assert_eq!(json["listen"], "unix:@proxy");
+        assert_eq!(json["cache_dir"], "/tmp/cache");
+        assert_eq!(json["index_url"], INDEX_CRATES_IO_URL);
+        assert_eq!(json["stale_ttl"], 60);
+        assert_eq!(json["max_metadata_age"], serde_json::Value::Null);
+        assert_eq!(json["admin_api"], true);
+        assert_eq!(json["admin_socket"], serde_json::Value::Null);
+        assert_eq!(json["stream_chunk_size"], DEFAULT_STREAM_CHUNK_SIZE);
+        assert!(!json.to_string().contains("secret"));
+    }
+
+    #[test]
+    fn test_parse_cache_ttl_body() {
+        assert_eq!(parse_cache_ttl_body(br#"{"cache_ttl": 600}"#), Some(600));
+        assert_eq!(parse_cache_ttl_body(br#"{"cache_ttl": 0}"#), Some(0));
+        assert_eq!(parse_cache_ttl_body(br#"{"cache_ttl": -1}"#), None);
+        assert_eq!(parse_cache_ttl_body(br#"{"cache_ttl": "600"}"#), None);
+        assert_eq!(parse_cache_ttl_body(br#"{"ttl": 600}"#), None);
+        assert_eq!(parse_cache_ttl_body(b"600"), None);
+        assert_eq!(parse_cache_ttl_body(b""), None);
+    }
+
+    #[test]
+    fn test_admin_loop() {
+        use std::io::{Read, Write};
+
+        let dir = TestDir::new("admin-socket");
+        let path = dir.join("admin.sock");
+        let admin_addr = ListenAddress::UnixPath(path.to_string_lossy().into_owned(), None);
+        let server = bind_server(&admin_addr).unwrap();
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let admin_thread = {
+            let shutdown = Arc::clone(&shutdown);
+            std::thread::spawn(move || {
+                admin_loop(&admin_addr, server, &ProxyConfig::default(), &shutdown);
+            })
+        };
+
+        let send = |request: &str| {
+            let mut stream = UnixStream::connect(&path).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        // No admin token is required at the admin socket.
+        let response = send("GET /admin/debug/resolve?path=/index/se/rd/serde HTTP/1.0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.0 200 "), "{response}");
+        assert!(response.contains("https://index.crates.io/se/rd/serde"));
+
+        // The proxy routes are not served at the admin socket.
+        let response = send("GET /index/se/rd/serde HTTP/1.0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.0 404 "), "{response}");
+
+        shutdown.store(true, Ordering::Relaxed);
+        admin_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_read_request_body() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpStream;
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            write!(
+                stream,
+                "POST /admin/test HTTP/1.1\r\nHost: {addr}\r\n\
+                 Expect: 100-continue\r\nContent-Length: 5\r\n\r\n"
+            )
+            .unwrap();
+
+            // The request body is only sent after the interim response.
+            let mut status = String::new();
+            reader.read_line(&mut status).unwrap();
+            assert!(status.starts_with("HTTP/1.1 100 "), "{status}");
+
+            stream.write_all(b"hello").unwrap();
+        });
+
+        let mut request = server.recv().unwrap();
+        let body = read_request_body(&mut request, 5);
+        client.join().unwrap();
+
+        assert_eq!(body.unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_fetch_error_content_length() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+
+        // Large enough to be sent chunked by default.
+        let json = format_json_error("x".repeat(0x10000));
+        let json_len = json.len().to_string();
+
+        let client = std::thread::spawn(move || {
+            (0..2)
+                .map(|_| match ureq::get(&format!("http://{addr}/")).call() {
+                    Err(ureq::Error::Status(status, response)) => {
+                        (status, response.header("Content-Length").map(str::to_owned))
+                    }
+                    other => panic!("unexpected response: {other:?}"),
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let upstream_response = ureq::Response::new(502, "Bad Gateway", &json).unwrap();
+        let error = Box::new(ureq::Error::Status(502, upstream_response));
+        send_fetch_error_response(server.recv().unwrap(), error);
+
+        let error = ureq::get("http://127.0.0.1:1/").call().unwrap_err();
+        send_fetch_error_response(server.recv().unwrap(), Box::new(error));
+
+        let responses = client.join().unwrap();
+        assert_eq!(responses[0], (502, Some(json_len)));
+        assert_eq!(responses[1].0, 502);
+        assert!(responses[1].1.is_some());
+    }
+
+    #[test]
+    fn test_stale_index_on_upstream_failure() {
+        let dir = TestDir::new("stale");
+        let data = b"{\"name\":\"stale-serve-test\",\"vers\":\"0.1.0\"}\n";
+        let etag = "\"stale-serve-test\"";
+
+        let upstream = Server::http("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.server_addr().to_ip().unwrap();
+
+        let upstream_thread = std::thread::spawn(move || {
+            let request = upstream.recv().unwrap();
+            let etag = Header::from_bytes("ETag", etag).unwrap();
+            let response = Response::from_data(data.as_slice()).with_header(etag);
+            request.respond(response).unwrap();
+        });
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+
+        let get = move || {
+            let response = ureq::get(&format!("http://{addr}/index/st/al/stale-serve-test"))
+                .call()
+                .unwrap();
+            let etag = response.header("ETag").map(str::to_owned);
+            (response.status(), etag, response.into_string().unwrap())
+        };
+
+        // Expire the cached index entry immediately.
+        let mut config = ProxyConfig {
+            index_url: Url::parse(&format!("http://{upstream_addr}/")).unwrap(),
+            index_cache: Arc::new(FileIndexCache::new(dir.join("index"), false)),
+            cache_ttl: Duration::ZERO,
+            ..ProxyConfig::default()
+        };
+
+        let client = std::thread::spawn(get);
+        let request = server.recv().unwrap();
+        handle_get_request(request, RequestContext::new("first"), &config);
+
+        let first = client.join().unwrap();
+        upstream_thread.join().unwrap();
+        assert_eq!(first.0, 200);
+        assert_eq!(first.1, Some(format!("W/{etag}")));
+
+        // The upstream registry can not be reached anymore.
+        config.index_url = Url::parse("http://127.0.0.1:1/").unwrap();
+
+        let client = std::thread::spawn(get);
+        let request = server.recv().unwrap();
+        handle_get_request(request, RequestContext::new("second"), &config);
+
+        assert_eq!(client.join().unwrap(), first);
+
+        metadata_invalidate_index_entry(&IndexEntry::new("stale-serve-test"));
+    }
+
+    #[test]
+    fn test_empty_index_for_missing() {
+        let path = "/index/em/pt/empty-missing-test";
+
+        let upstream = Server::http("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.server_addr().to_ip().unwrap();
+
+        let upstream_thread = std::thread::spawn(move || {
+            let request = upstream.recv().unwrap();
+            let json = format_json_error("not found");
+            request
+                .respond(Response::from_string(json).with_status_code(404))
+                .unwrap();
+        });
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+
+        let get = move || match ureq::get(&format!("http://{addr}{path}")).call() {
+            Ok(response) => (response.status(), response.into_string().unwrap()),
+            Err(ureq::Error::Status(status, _)) => (status, String::new()),
+            Err(e) => panic!("unexpected error: {e}"),
+        };
+
+        metadata_not_found_init(Duration::from_secs(60));
+
+        let mut config = ProxyConfig {
+            index_url: Url::parse(&format!("http://{upstream_addr}/")).unwrap(),
+            empty_index_for_missing: true,
+            ..ProxyConfig::default()
+        };
+
+        // The upstream HTTP 404 is replaced with an empty index entry.
+        let client = std::thread::spawn(get);
+        let request = server.recv().unwrap();
+        handle_get_request(request, RequestContext::new("upstream"), &config);
+
+        assert_eq!(client.join().unwrap(), (200, String::new()));
+        upstream_thread.join().unwrap();
+
+        // The cached upstream HTTP 404 is replaced too.
+        config.index_url = Url::parse("http://127.0.0.1:1/").unwrap();
+
+        let client = std::thread::spawn(get);
+        let request = server.recv().unwrap();
+        handle_get_request(request, RequestContext::new("cached"), &config);
+
+        assert_eq!(client.join().unwrap(), (200, String::new()));
+
+        config.empty_index_for_missing = false;
+
+        let client = std::thread::spawn(get);
+        let request = server.recv().unwrap();
+        handle_get_request(request, RequestContext::new("disabled"), &config);
+
+        assert_eq!(client.join().unwrap().0, 404);
+
+        metadata_invalidate_not_found(path);
+    }
+
+    #[test]
+    fn test_stale_while_revalidate() {
+        let dir = TestDir::new("swr");
+        let old_data = b"{\"name\":\"swr-test\",\"vers\":\"0.1.0\"}\n";
+        let new_data = b"{\"name\":\"swr-test\",\"vers\":\"0.2.0\"}\n";
+
+        let upstream = Server::http("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.server_addr().to_ip().unwrap();
+
+        // The index entry is updated upstream right after the first fetch.
+        let upstream_thread = std::thread::spawn(move || {
+            for (data, etag) in [(old_data, "\"old\""), (new_data, "\"new\"")] {
+                let request = upstream.recv().unwrap();
+                let etag = Header::from_bytes("ETag", etag).unwrap();
+                let response = Response::from_data(data.as_slice()).with_header(etag);
+                request.respond(response).unwrap();
+            }
+        });
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+
+        let get = move || {
+            let response = ureq::get(&format!("http://{addr}/index/sw/r-/swr-test"))
+                .call()
+                .unwrap();
+            let etag = response.header("ETag").map(str::to_owned);
+            (etag, response.into_string().unwrap())
+        };
+
+        // Expire the cached index entry immediately, but keep serving it.
+        let config = ProxyConfig {
+            index_url: Url::parse(&format!("http://{upstream_addr}/")).unwrap(),
+            index_cache: Arc::new(FileIndexCache::new(dir.join("index"), false)),
+            cache_ttl: Duration::ZERO,
+            stale_ttl: Some(Duration::from_secs(60)),
+            ..ProxyConfig::default()
+        };
+
+        let client = std::thread::spawn(get);
+        handle_get_request(
+            server.recv().unwrap(),
+            RequestContext::new("first"),
+            &config,
+        );
+        assert_eq!(client.join().unwrap().1.as_bytes(), old_data);
+
+        // The stale index entry is served while being revalidated.
+        let client = std::thread::spawn(get);
+        handle_get_request(
+            server.recv().unwrap(),
+            RequestContext::new("second"),
+            &config,
+        );
+        let (etag, data) = client.join().unwrap();
+        assert_eq!(etag.as_deref(), Some("W/\"old\""));
+        assert_eq!(data.as_bytes(), old_data);
+
+        upstream_thread.join().unwrap();
+
+        // The revalidated index entry is cached for the next request.
+        let entry = IndexEntry::new("swr-test");
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while metadata_fetch_index_entry("swr-test").and_then(|e| e.etag().map(str::to_owned))
+            != Some("\"new\"".to_owned())
+        {
+            assert!(Instant::now() < deadline, "index entry was not revalidated");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(config.index_cache.fetch(&entry).unwrap(), new_data);
+
+        metadata_invalidate_index_entry(&entry);
+    }
+
+    #[test]
+    fn test_single_flight_index_refresh() {
+        let dir = TestDir::new("sf");
+        let old_data = b"{\"name\":\"sf-test\",\"vers\":\"0.1.0\"}\n";
+        let new_data = b"{\"name\":\"sf-test\",\"vers\":\"0.2.0\"}\n";
+
+        let upstream = Server::http("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.server_addr().to_ip().unwrap();
+
+        // Count the upstream fetches, keeping the first one in flight for a while.
+        let upstream_thread = std::thread::spawn(move || {
+            let mut count = 0;
+
+            while let Some(request) = upstream.recv_timeout(Duration::from_secs(2)).unwrap() {
+                std::thread::sleep(Duration::from_millis(300));
+                let etag = Header::from_bytes("ETag", "\"new\"").unwrap();
+                let response = Response::from_data(new_data.as_slice()).with_header(etag);
+                request.respond(response).unwrap();
+                count += 1;
+            }
+
+            count
+        });
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+
+        let get = move || {
+            ureq::get(&format!("http://{addr}/index/sf/-t/sf-test"))
+                .call()
+                .unwrap()
+                .into_string()
+                .unwrap()
+        };
+
+        // The foreground requests refresh the expired index entries,
+        // while the stale ones are revalidated in the background.
+        let foreground_config = ProxyConfig {
+            index_url: Url::parse(&format!("http://{upstream_addr}/")).unwrap(),
+            index_cache: Arc::new(FileIndexCache::new(dir.join("index"), false)),
+            cache_ttl: Duration::from_secs(1),
+            ..ProxyConfig::default()
+        };
+        let background_config = ProxyConfig {
+            stale_ttl: Some(Duration::from_secs(60)),
+            ..foreground_config.clone()
+        };
+
+        let mut entry = IndexEntry::new("sf-test");
+        entry.set_etag("\"old\"");
+        entry.set_last_updated_at(Instant::now() - Duration::from_secs(2));
+        foreground_config.index_cache.store(&entry, old_data);
+        metadata_store_index_entry(&entry);
+
+        // The stale index entry is served while the background revalidation starts.
+        let client = std::thread::spawn(get);
+        handle_get_request(
+            server.recv().unwrap(),
+            RequestContext::new("background"),
+            &background_config,
+        );
+        assert_eq!(client.join().unwrap().as_bytes(), old_data);
+
+        // The concurrent foreground refreshes wait for it instead of fetching again.
+        let clients: Vec<_> = (0..3).map(|_| std::thread::spawn(get)).collect();
+
+        for _ in &clients {
+            handle_get_request(
+                server.recv().unwrap(),
+                RequestContext::new("foreground"),
+                &foreground_config,
+            );
+        }
+
+        for client in clients {
+            assert_eq!(client.join().unwrap().as_bytes(), new_data);
+        }
+
+        assert_eq!(upstream_thread.join().unwrap(), 1);
+
+        metadata_invalidate_index_entry(&entry);
+    }
+
+    #[test]
+    fn test_config_json_head() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let config = ProxyConfig::default();
+
+        let json = gen_config_json_file(&config);
+        let etag = config_json_etag(&json);
+
+        let client = std::thread::spawn(move || {
+            let response = ureq::head(&format!("http://{addr}/index/config.json"))
+                .call()
+                .unwrap();
+            let len = response.header("Content-Length").map(str::to_owned);
+            let etag = response.header("ETag").map(str::to_owned);
+            (
+                response.status(),
+                len,
+                etag,
+                response.into_string().unwrap(),
+            )
+        });
+
+        send_config_json_response(server.recv().unwrap(), &config);
+
+        // The HEAD response has the GET response headers, but no body.
+        let (status, len, head_etag, body) = client.join().unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(len, Some(json.len().to_string()));
+        assert_eq!(head_etag, Some(etag));
+        assert_eq!(body, "");
+    }
+
+    #[test]
+    fn test_index_entry_head() {
+        let dir = TestDir::new("index-head");
+        let data = b"{\"name\":\"index-head-test\",\"vers\":\"0.1.0\"}\n";
+        let etag = "\"index-head-test\"";
+        let last_modified = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+        let upstream = Server::http("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.server_addr().to_ip().unwrap();
+
+        let upstream_thread = std::thread::spawn(move || {
+            let request = upstream.recv().unwrap();
+            let response = Response::from_data(data.as_slice())
+                .with_header(Header::from_bytes("ETag", etag).unwrap())
+                .with_header(Header::from_bytes("Last-Modified", last_modified).unwrap());
+            request.respond(response).unwrap();
+        });
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let url = format!("http://{addr}/index/in/de/index-head-test");
+
+        let config = ProxyConfig {
+            index_url: Url::parse(&format!("http://{upstream_addr}/")).unwrap(),
+            index_cache: Arc::new(FileIndexCache::new(dir.join("index"), false)),
+            ..ProxyConfig::default()
+        };
+
+        // Cache the index entry first.
+        let get_url = url.clone();
+        let client = std::thread::spawn(move || ureq::get(&get_url).call().unwrap().status());
+        handle_get_request(server.recv().unwrap(), RequestContext::new("get"), &config);
+        assert_eq!(client.join().unwrap(), 200);
+        upstream_thread.join().unwrap();
+
+        let client = std::thread::spawn(move || {
+            let response = ureq::head(&url).call().unwrap();
+            let header = |name| response.header(name).map(str::to_owned);
+            let headers = (
+                header("Content-Length"),
+                header("ETag"),
+                header("Last-Modified"),
+            );
+            (response.status(), headers, response.into_string().unwrap())
+        });
+        handle_get_request(server.recv().unwrap(), RequestContext::new("head"), &config);
+
+        // The HEAD response has the GET response headers, but no body.
+        let (status, (len, head_etag, head_last_modified), body) = client.join().unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(len, Some(data.len().to_string()));
+        assert_eq!(head_etag, Some(format!("W/{etag}")));
+        assert_eq!(head_last_modified.as_deref(), Some(last_modified));
+        assert_eq!(body, "");
+
+        metadata_invalidate_index_entry(&IndexEntry::new("index-head-test"));
+    }
+
+    #[test]
+    fn test_cold_crate_head() {
+        let dir = TestDir::new("crate-head");
+        let crate_info = CrateInfo::new("crate-head-test", "0.1.0");
+        let data = b"crate-head-test";
+
+        let upstream = Server::http("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.server_addr().to_ip().unwrap();
+
+        // The upstream server only sees the HEAD request.
+        let upstream_thread = std::thread::spawn(move || {
+            let request = upstream.recv().unwrap();
+            let method = request.method().clone();
+            request
+                .respond(Response::from_data(data.as_slice()))
+                .unwrap();
+            method
+        });
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+
+        let config = ProxyConfig {
+            upstream_url: Url::parse(&format!("http://{upstream_addr}/")).unwrap(),
+            crate_cache: Arc::new(FileCrateCache::new(dir.join("crates"), false, false)),
+            ..ProxyConfig::default()
+        };
+
+        let client = std::thread::spawn(move || {
+            let url = format!("http://{addr}/api/v1/crates/crate-head-test/0.1.0/download");
+            let response = ureq::head(&url).call().unwrap();
+            let len = response.header("Content-Length").map(str::to_owned);
+            (response.status(), len, response.into_string().unwrap())
+        });
+        handle_get_request(server.recv().unwrap(), RequestContext::new("head"), &config);
+
+        let (status, len, body) = client.join().unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(len, Some(data.len().to_string()));
+        assert_eq!(body, "");
+
+        assert_eq!(upstream_thread.join().unwrap(), Method::Head);
+
+        // The crate file is neither downloaded nor cached.
+        assert_eq!(config.crate_cache.size(&crate_info), None);
+    }
+
+    #[test]
+    fn test_gzip_index_entry_etag() {
+        let dir = TestDir::new("gzip");
+        let data = b"{\"name\":\"gzip-etag-test\",\"vers\":\"0.1.0\"}\n";
+        let etag = "\"gzip-etag-test\"";
+
+        // The index entry data above compressed by `gzip`.
+        let gzip_data: &[u8] = &[
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xab, 0x56, 0xca, 0x4b,
+            0xcc, 0x4d, 0x55, 0xb2, 0x52, 0x4a, 0xaf, 0xca, 0x2c, 0xd0, 0x4d, 0x2d, 0x49, 0x4c,
+            0xd7, 0x2d, 0x49, 0x2d, 0x2e, 0x51, 0xd2, 0x51, 0x2a, 0x4b, 0x2d, 0x2a, 0x06, 0x8a,
+            0x1b, 0xe8, 0x19, 0xea, 0x19, 0x28, 0xd5, 0x72, 0x01, 0x00, 0x9f, 0xea, 0x2c, 0xd7,
+            0x29, 0x00, 0x00, 0x00,
+        ];
+
+        let upstream = Server::http("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.server_addr().to_ip().unwrap();
+
+        // The upstream entity tag belongs to the gzip content encoding.
+        let upstream_thread = std::thread::spawn(move || {
+            let request = upstream.recv().unwrap();
+            let response = Response::from_data(gzip_data)
+                .with_header(Header::from_bytes("ETag", etag).unwrap())
+                .with_header(Header::from_bytes("Content-Encoding", "gzip").unwrap());
+            request.respond(response).unwrap();
+        });
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+
+        let get = move |if_none_match: Option<String>| {
+            let mut request = ureq::get(&format!("http://{addr}/index/gz/ip/gzip-etag-test"));
+            if let Some(tag) = &if_none_match {
+                request = request.set("If-None-Match", tag);
+            }
+            let response = request.call().unwrap();
+            let etag = response.header("ETag").map(str::to_owned);
+            (response.status(), etag, response.into_string().unwrap())
+        };
+
+        let config = ProxyConfig {
+            index_url: Url::parse(&format!("http://{upstream_addr}/")).unwrap(),
+            index_cache: Arc::new(FileIndexCache::new(dir.join("index"), false)),
+            ..ProxyConfig::default()
+        };
+
+        let client = std::thread::spawn(move || get(None));
+        handle_get_request(
+            server.recv().unwrap(),
+            RequestContext::new("first"),
+            &config,
+        );
+
+        // The decoded index entry is served with a weak entity tag.
+        let (status, weak_etag, body) = client.join().unwrap();
+        upstream_thread.join().unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(weak_etag, Some(format!("W/{etag}")));
+        assert_eq!(body.as_bytes(), data);
+
+        // The weak entity tag still validates the cached index entry.
+        let client = std::thread::spawn(move || get(weak_etag));
+        handle_get_request(
+            server.recv().unwrap(),
+            RequestContext::new("second"),
+            &config,
+        );
+
+        let (status, revalidated_etag, _) = client.join().unwrap();
+        assert_eq!(status, 304);
+        assert_eq!(revalidated_etag, Some(format!("W/{etag}")));
+
+        metadata_invalidate_index_entry(&IndexEntry::new("gzip-etag-test"));
+    }
+
+    #[test]
+    fn test_chained_proxy_download() {
+        let dir = TestDir::new("chain");
+        let crate_info = CrateInfo::new("chain-test", "0.1.0");
+
+        // Large enough to be sent chunked without an explicit Content-Length.
+        let data = vec![b'x'; 100_000];
+
+        let inner = Server::http("127.0.0.1:0").unwrap();
+        let inner_addr = inner.server_addr().to_ip().unwrap();
+        let outer = Server::http("127.0.0.1:0").unwrap();
+        let outer_addr = outer.server_addr().to_ip().unwrap();
+
+        let inner_config = ProxyConfig {
+            crate_cache: Arc::new(FileCrateCache::new(dir.join("inner"), false, false)),
+            ..ProxyConfig::default()
+        };
+        inner_config.crate_cache.store(&crate_info, &data);
+
+        // The outer proxy forwards its cache misses to the inner one.
+        let outer_config = ProxyConfig {
+            upstream_url: Url::parse(&format!("http://{inner_addr}/")).unwrap(),
+            crate_cache: Arc::new(FileCrateCache::new(dir.join("outer"), false, false)),
+            ..ProxyConfig::default()
+        };
+
+        let inner_thread = std::thread::spawn(move || {
+            let request = inner.recv().unwrap();
+            handle_get_request(request, RequestContext::new("inner"), &inner_config);
+        });
+
+        let client = std::thread::spawn(move || {
+            let url = format!("http://{outer_addr}/api/v1/crates/chain-test/0.1.0/download");
+            let response = ureq::get(&url).call().unwrap();
+            let len = response.header("Content-Length").map(str::to_owned);
+            let mut body = Vec::new();
+            response.into_reader().read_to_end(&mut body).unwrap();
+            (len, body)
+        });
+
+        let request = outer.recv().unwrap();
+        handle_get_request(request, RequestContext::new("outer"), &outer_config);
+
+        let (len, body) = client.join().unwrap();
+        inner_thread.join().unwrap();
+
+        assert_eq!(len.as_deref(), Some("100000"));
+        assert_eq!(body, data);
+    }
+
+    #[test]
+    fn test_coalesced_waiters_fetch_limit() {
+        let dir = TestDir::new("waiters");
+        let waited = CrateInfo::new("waiters-test", "0.1.0");
+        let other = CrateInfo::new("waiters-other-test", "0.1.0");
+
+        let upstream = Server::http("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.server_addr().to_ip().unwrap();
+
+        // Each crate file is downloaded once.
+        let upstream_thread = std::thread::spawn(move || {
+            for _ in 0..2 {
+                let request = upstream.recv().unwrap();
+                let data = if request.url().contains("waiters-other-test") {
+                    "other"
+                } else {
+                    "waited"
+                };
+                request.respond(Response::from_string(data)).unwrap();
+            }
+        });
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+
+        let get = move |crate_info: &CrateInfo| {
+            let url = format!(
+                "http://{addr}/api/v1/crates/{}/{}/download",
+                crate_info.name(),
+                crate_info.version()
+            );
+            ureq::get(&url).call().unwrap().into_string().unwrap()
+        };
+
+        let config = ProxyConfig {
+            upstream_url: Url::parse(&format!("http://{upstream_addr}/")).unwrap(),
+            crate_cache: Arc::new(FileCrateCache::new(dir.join("crates"), false, false)),
+            ..ProxyConfig::default()
+        };
+
+        // More requests wait for a slow download of the same crate file
+        // than there are crate fetch slots.
+        fetch_limit_init(FetchKind::Crate, 8);
+
+        let lead = CRATE_FETCHES.try_lead(&waited.to_file_path().to_string_lossy());
+        assert!(lead.is_some());
+
+        // The server connection threads are only added one at a time.
+        let waiters: Vec<_> = (0..16)
+            .map(|_| {
+                let waited = waited.clone();
+                let waiter = std::thread::spawn(move || get(&waited));
+                let request = server.recv().unwrap();
+                handle_get_request(request, RequestContext::new("waiter"), &config);
+                waiter
+            })
+            .collect();
+
+        // The waiting requests do not hold the fetch slots needed by other downloads.
+        let client = std::thread::spawn(move || get(&other));
+        handle_get_request(
+            server.recv().unwrap(),
+            RequestContext::new("other"),
+            &config,
+        );
+        assert_eq!(client.join().unwrap(), "other");
+
+        drop(lead);
+
+        for waiter in waiters {
+            assert_eq!(waiter.join().unwrap(), "waited");
+        }
+
+        upstream_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_client_request_limit() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+
+        let get = move |path: &str| match ureq::get(&format!("http://{addr}{path}")).call() {
+            Ok(response) => response.status(),
+            Err(ureq::Error::Status(status, _)) => status,
+            Err(e) => panic!("unexpected error: {e}"),
+        };
+
+        let config = ProxyConfig {
+            max_requests_per_client: Some(1),
+            ..ProxyConfig::default()
+        };
+
+        // The first request holds the only request slot of the client.
+        let first = std::thread::spawn(move || get("/client-limit-first"));
+        let request = server.recv().unwrap();
+        let mut ctx = RequestContext::new(request.url());
+        let request = acquire_client_slot(request, &mut ctx, &config).unwrap();
+
+        // The second request is refused while the first one is in progress.
+        let second = std::thread::spawn(move || get("/client-limit-second"));
+        let mut second_ctx = RequestContext::new("second");
+        assert!(acquire_client_slot(server.recv().unwrap(), &mut second_ctx, &config).is_none());
+        assert_eq!(second.join().unwrap(), 503);
+
+        handle_get_request(request, ctx, &config);
+        assert_eq!(first.join().unwrap(), 404);
+
+        // The request slot is released with the completed request.
+        let third = std::thread::spawn(move || get("/client-limit-third"));
+        let request = server.recv().unwrap();
+        let mut ctx = RequestContext::new(request.url());
+        let request = acquire_client_slot(request, &mut ctx, &config).unwrap();
+        handle_get_request(request, ctx, &config);
+        assert_eq!(third.join().unwrap(), 404);
+    }
+
+    #[test]
+    fn test_upstream_retries() {
+        let dir = TestDir::new("retries");
+        let retried = CrateInfo::new("retries-test", "0.1.0");
+        let missing = CrateInfo::new("retries-missing-test", "0.1.0");
+
+        let upstream = Server::http("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.server_addr().to_ip().unwrap();
+
+        // The first crate file download fails with a transient error once.
+        let upstream_thread = std::thread::spawn(move || {
+            let mut urls = Vec::new();
+
+            for _ in 0..3 {
+                let request = upstream.recv().unwrap();
+                let url = request.url().to_owned();
+
+                let response = if url.contains("retries-missing-test") {
+                    Response::from_string("not found").with_status_code(404)
+                } else if urls.contains(&url) {
+                    Response::from_string("retried")
+                } else {
+                    Response::from_string("unavailable").with_status_code(503)
+                };
+
+                request.respond(response).unwrap();
+                urls.push(url);
+            }
+
+            // The permanent failures are not retried.
+            let extra = upstream.recv_timeout(Duration::from_millis(200)).unwrap();
+            assert!(extra.is_none());
+
+            urls
+        });
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+
+        let get = move |crate_info: &CrateInfo| {
+            let url = format!(
+                "http://{addr}/api/v1/crates/{}/{}/download",
+                crate_info.name(),
+                crate_info.version()
+            );
+            match ureq::get(&url).call() {
+                Ok(response) => (200, response.into_string().unwrap()),
+                Err(ureq::Error::Status(status, _)) => (status, String::new()),
+                Err(e) => panic!("unexpected error: {e}"),
+            }
+        };
+
+        let config = ProxyConfig {
+            upstream_url: Url::parse(&format!("http://{upstream_addr}/")).unwrap(),
+            crate_cache: Arc::new(FileCrateCache::new(dir.join("crates"), false, false)),
+            ..ProxyConfig::default()
+        };
+
+        UPSTREAM_RETRIES.get_or_init(|| (1, Duration::from_millis(1)));
+
+        let client = std::thread::spawn(move || get(&retried));
+        handle_get_request(
+            server.recv().unwrap(),
+            RequestContext::new("retried"),
+            &config,
+        );
+        assert_eq!(client.join().unwrap(), (200, "retried".to_owned()));
+
+        let client = std::thread::spawn(move || get(&missing));
+        handle_get_request(
+            server.recv().unwrap(),
+            RequestContext::new("missing"),
+            &config,
+        );
+        assert_eq!(client.join().unwrap().0, 404);
+
+        let urls = upstream_thread.join().unwrap();
+        assert_eq!(urls[0], urls[1]);
+        assert!(urls[2].contains("retries-missing-test"));
+    }
+
+    #[test]
+    fn test_is_transient_error() {
+        let status = |code| ureq::Error::Status(code, ureq::Response::new(code, "", "").unwrap());
+
+        assert!(is_transient_error(&status(500)));
+        assert!(is_transient_error(&status(503)));
+        assert!(!is_transient_error(&status(404)));
+        assert!(!is_transient_error(&status(429)));
+
+        let error = ureq::get("http://127.0.0.1:1/").call().unwrap_err();
+        assert!(is_transient_error(&error));
+    }
+
+    #[test]
+    fn test_offline_mode() {
+        let dir = TestDir::new("offline");
+        let data = b"{\"name\":\"offline-test\",\"vers\":\"0.1.0\"}\n";
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+
+        // The upstream registry is never contacted, even for expired entries.
+        let config = ProxyConfig {
+            index_url: Url::parse("http://127.0.0.1:1/").unwrap(),
+            upstream_url: Url::parse("http://127.0.0.1:1/").unwrap(),
+            index_cache: Arc::new(FileIndexCache::new(dir.join("index"), false)),
+            cache_ttl: Duration::ZERO,
+            offline: true,
+            ..ProxyConfig::default()
+        };
+
+        config
+            .index_cache
+            .store(&IndexEntry::new("offline-test"), data);
+
+        for (path, status) in [
+            ("index/of/fl/offline-test", 200),
+            ("index/of/fl/offline-miss", 404),
+            ("index/config.json", 200),
+            ("api/v1/crates/offline-miss/0.1.0/download", 404),
+        ] {
+            let client = std::thread::spawn(move || {
+                match ureq::get(&format!("http://{addr}/{path}")).call() {
+                    Ok(response) => response.status(),
+                    Err(ureq::Error::Status(status, _)) => status,
+                    Err(e) => panic!("unexpected error: {e}"),
+                }
+            });
+
+            let request = server.recv().unwrap();
+            handle_get_request(request, RequestContext::new("offline"), &config);
+
+            assert_eq!(client.join().unwrap(), status, "{path}");
+        }
+    }
+
+    #[test]
+    fn test_snapshot_mixed_case_crate() {
+        let dir = TestDir::new("snapshot");
+        let data = b"{\"name\":\"Inflector\",\"vers\":\"0.11.4\"}\n";
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+
+        let config = ProxyConfig {
+            index_url: Url::parse("http://127.0.0.1:1/").unwrap(),
+            upstream_url: Url::parse("http://127.0.0.1:1/").unwrap(),
+            index_cache: Arc::new(FileIndexCache::new(dir.join("index"), false)),
+            crate_cache: Arc::new(FileCrateCache::new(dir.join("crates"), false, false)),
+            snapshot_time: Some(SystemTime::now() + Duration::from_secs(3600)),
+            ..ProxyConfig::default()
+        };
+
+        // The index entries are stored under the lowercase crate names.
+        config
+            .index_cache
+            .store(&IndexEntry::new("inflector"), data);
+        config
+            .crate_cache
+            .store(&CrateInfo::new("Inflector", "0.11.4"), b"crate");
+
+        for (path, status) in [
+            ("index/in/fl/inflector", 200),
+            ("api/v1/crates/Inflector/0.11.4/download", 200),
+            ("api/v1/crates/Inflector/0.12.0/download", 404),
+        ] {
+            let client = std::thread::spawn(move || {
+                match ureq::get(&format!("http://{addr}/{path}")).call() {
+                    Ok(response) => response.status(),
+                    Err(ureq::Error::Status(status, _)) => status,
+                    Err(e) => panic!("unexpected error: {e}"),
+                }
+            });
+
+            let request = server.recv().unwrap();
+            handle_get_request(request, RequestContext::new("snapshot"), &config);
+
+            assert_eq!(client.join().unwrap(), status, "{path}");
+        }
+    }
+
+    #[test]
+    fn test_accept_retry_delay() {
+        assert_eq!(accept_retry_delay(1), ACCEPT_RETRY_BASE_DELAY);
+        assert_eq!(accept_retry_delay(3), ACCEPT_RETRY_BASE_DELAY * 4);
+        assert_eq!(accept_retry_delay(100), ACCEPT_RETRY_MAX_DELAY);
+    }
+
+    #[test]
+    fn test_restart_server() {
+        let dir = TestDir::new("restart");
+        let path = dir.join("proxy.sock");
+
+        // The non-blocking listeners fail to accept the connections right away.
+        let tcp_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp_listener.local_addr().unwrap();
+        tcp_listener.set_nonblocking(true).unwrap();
+
+        let unix_listener = UnixListener::bind(&path).unwrap();
+        unix_listener.set_nonblocking(true).unwrap();
+
+        let tcp_addr = ListenAddress::SocketAddr(addr.to_string());
+        let unix_addr = ListenAddress::UnixPath(path.to_string_lossy().into_owned(), None);
+
+        for (listener, listen_addr) in [
+            (tiny_http::Listener::from(tcp_listener), &tcp_addr),
+            (tiny_http::Listener::from(unix_listener), &unix_addr),
+        ] {
+            let server = Server::from_listener(listener, None).unwrap();
+            assert!(server.recv_timeout(Duration::from_secs(5)).is_err());
+
+            let mut failures = 0;
+            let server =
+                restart_server(listen_addr, server, &mut failures, &AtomicBool::new(false))
+                    .unwrap();
+            assert_eq!(failures, 1);
+
+            // The restarted server accepts the new connections.
+            let request = b"GET /healthz HTTP/1.0\r\n\r\n";
+
+            match listen_addr {
+                ListenAddress::SocketAddr(_) => {
+                    std::net::TcpStream::connect(addr)
+                        .unwrap()
+                        .write_all(request)
+                        .unwrap();
+                }
+                _ => UnixStream::connect(&path)
+                    .unwrap()
+                    .write_all(request)
+                    .unwrap(),
+            }
+
+            let request = server
+                .recv_timeout(Duration::from_secs(5))
+                .unwrap()
+                .unwrap();
+            assert_eq!(request.url(), HEALTHZ_PATH);
+
+            // The server is never restarted during the shutdown.
+            assert!(
+                restart_server(listen_addr, server, &mut failures, &AtomicBool::new(true))
+                    .is_none()
+            );
+            assert_eq!(failures, 1);
+        }
+    }
+
+    #[test]
+    fn test_worker_panic() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+
+        let client =
+            std::thread::spawn(move || match ureq::get(&format!("http://{addr}/")).call() {
+                Err(ureq::Error::Status(status, response)) => {
+                    (status, response.into_string().unwrap())
+                }
+                other => panic!("unexpected response: {other:?}"),
+            });
+
+        let request = server.recv().unwrap();
+        spawn_worker(
+            request,
+            "worker-panic-test".to_owned(),
+            "test".to_owned(),
+            |request| {
+                assert_eq!(request.url(), "/");
+                panic!("worker panic test");
+            },
+        );
+
+        let (status, body) = client.join().unwrap();
+        assert_eq!(status, 500);
+        assert_eq!(
+            body,
+            format_json_error("internal error while processing the request")
+        );
+    }
+
+    #[test]
+    fn test_index_entry_request() {
+        let index_url = Url::parse(INDEX_CRATES_IO_URL).unwrap();
+        let last_modified = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+        let mut entry = IndexEntry::new("serde");
+        let request = index_entry_request(&index_url, &entry, None);
+        assert_eq!(request.url(), "https://index.crates.io/se/rd/serde");
+        assert_eq!(request.header("Accept-Encoding"), Some("gzip"));
+        assert_eq!(request.header("If-None-Match"), None);
+        assert_eq!(request.header("If-Modified-Since"), None);
+        assert_eq!(request.header("Authorization"), None);
+
+        entry.set_etag("\"abc\"");
+        entry.set_last_modified(last_modified);
+        let request = index_entry_request(&index_url, &entry, Some("secret"));
+        assert_eq!(request.header("If-None-Match"), Some("\"abc\""));
+        assert_eq!(request.header("If-Modified-Since"), Some(last_modified));
+        assert_eq!(request.header("Authorization"), Some("secret"));
+    }
 }
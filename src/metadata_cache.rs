@@ -1,26 +1,461 @@
 //! Index entry file metadata cache helpers
 
 use std::collections::BTreeMap;
-use std::sync::RwLock;
+use std::fmt::Write as _;
+use std::fs::{create_dir_all, read_to_string, rename, write};
+use std::io::Result;
+use std::path::Path;
+use std::sync::{
+    Mutex, MutexGuard, OnceLock, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::warn;
 
 use super::IndexEntry;
 
+/// Last upstream server access time updates closer than this are coalesced
+const ATIME_UPDATE_GRANULARITY: Duration = Duration::from_secs(1);
+
 /// Volatile registry index entry metadata cache
 static INDEX_CACHE: RwLock<BTreeMap<String, IndexEntry>> = RwLock::new(BTreeMap::new());
 
-/// Caches the index entry metadata in memory.
-pub fn metadata_store_index_entry(entry: &IndexEntry) {
-    let name = entry.name().to_owned();
+/// Recovers the metadata cache lock poisoned by a panicked thread.
+///
+/// The cached metadata entries are always replaced as a whole,
+/// so they remain consistent after a panic.
+fn recover_poisoned<T>(error: PoisonError<T>) -> T {
+    warn!("cache: recovering the index metadata cache after a thread panic");
+    INDEX_CACHE.clear_poison();
+    error.into_inner()
+}
+
+/// Locks the metadata cache for reading.
+fn index_cache_read() -> RwLockReadGuard<'static, BTreeMap<String, IndexEntry>> {
+    INDEX_CACHE.read().unwrap_or_else(recover_poisoned)
+}
+
+/// Locks the metadata cache for writing.
+fn index_cache_write() -> RwLockWriteGuard<'static, BTreeMap<String, IndexEntry>> {
+    INDEX_CACHE.write().unwrap_or_else(recover_poisoned)
+}
+
+/// Index entry metadata storage backend
+pub trait MetadataBackend: Send + Sync {
+    /// Caches the index entry metadata.
+    fn store(&self, entry: &IndexEntry);
+
+    /// Fetches the cached index entry metadata.
+    fn fetch(&self, name: &str) -> Option<IndexEntry>;
+
+    /// Erases the cached index entry metadata.
+    fn invalidate(&self, name: &str);
+}
+
+/// In-memory index entry metadata storage (default)
+pub struct MemoryMetadata;
+
+/// Checks if the cached entry access time is recent enough to skip updating it.
+fn is_atime_update_redundant(cached: &IndexEntry, entry: &IndexEntry) -> bool {
+    match (cached.last_updated_at(), entry.last_updated_at()) {
+        (Some(cached), Some(atime)) => {
+            atime.saturating_duration_since(cached) < ATIME_UPDATE_GRANULARITY
+        }
+        (_, None) => true,
+        (None, Some(_)) => false,
+    }
+}
+
+impl MetadataBackend for MemoryMetadata {
+    fn store(&self, entry: &IndexEntry) {
+        // Most upstream checks find the index entry unchanged,
+        // so avoid taking the write lock for them, if possible.
+        if let Some(cached) = index_cache_read().get(entry.name()) {
+            if cached.has_same_validators(entry) && is_atime_update_redundant(cached, entry) {
+                return;
+            }
+        }
+
+        let mut cache = index_cache_write();
+
+        match cache.get_mut(entry.name()) {
+            Some(cached) if cached.has_same_validators(entry) => {
+                // Update the access time in place without reallocating the entry.
+                if let Some(atime) = entry.last_updated_at() {
+                    cached.set_last_updated_at(atime);
+                }
+            }
+            Some(cached) => cached.clone_from(entry),
+            None => {
+                cache.insert(entry.name().to_owned(), entry.clone());
+            }
+        }
+    }
+
+    fn fetch(&self, name: &str) -> Option<IndexEntry> {
+        index_cache_read().get(name).map(ToOwned::to_owned)
+    }
+
+    fn invalidate(&self, name: &str) {
+        index_cache_write().remove(name);
+    }
+}
 
-    INDEX_CACHE.write().unwrap().insert(name, entry.clone());
+/// Shared index entry metadata storage backend (optional)
+static METADATA_BACKEND: OnceLock<Box<dyn MetadataBackend>> = OnceLock::new();
+
+/// Replaces the in-memory index entry metadata storage with a shared backend.
+pub fn metadata_backend_init(backend: Box<dyn MetadataBackend>) {
+    METADATA_BACKEND.get_or_init(|| backend);
+}
+
+/// Gets the configured index entry metadata storage backend.
+fn metadata_backend() -> &'static dyn MetadataBackend {
+    METADATA_BACKEND.get().map_or(&MemoryMetadata, Box::as_ref)
 }
 
-/// Fetches the cached index entry metadata from memory.
+/// Caches the index entry metadata.
+pub fn metadata_store_index_entry(entry: &IndexEntry) {
+    metadata_backend().store(entry);
+}
+
+/// Fetches the cached index entry metadata.
 pub fn metadata_fetch_index_entry(name: &str) -> Option<IndexEntry> {
-    INDEX_CACHE.read().unwrap().get(name).map(ToOwned::to_owned)
+    metadata_backend().fetch(name)
 }
 
-/// Erases the cached index entry metadata from memory.
+/// Erases the cached index entry metadata.
 pub fn metadata_invalidate_index_entry(entry: &IndexEntry) {
-    INDEX_CACHE.write().unwrap().remove(entry.name());
+    metadata_backend().invalidate(entry.name());
+}
+
+/// Upstream HTTP 404 response cache Time-to-Live (disabled by default)
+static NOT_FOUND_TTL: OnceLock<Duration> = OnceLock::new();
+
+/// Volatile upstream HTTP 404 response cache by request path
+static NOT_FOUND_CACHE: Mutex<BTreeMap<String, (Instant, String)>> = Mutex::new(BTreeMap::new());
+
+/// Locks the upstream HTTP 404 response cache.
+///
+/// The cached responses are always replaced as a whole,
+/// so they remain consistent after a panic.
+fn not_found_cache_lock() -> MutexGuard<'static, BTreeMap<String, (Instant, String)>> {
+    NOT_FOUND_CACHE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Enables caching the upstream HTTP 404 responses for `ttl`.
+pub fn metadata_not_found_init(ttl: Duration) {
+    NOT_FOUND_TTL.get_or_init(|| ttl);
+}
+
+/// Caches the upstream HTTP 404 response body for the request path.
+pub fn metadata_store_not_found(path: &str, body: &str) {
+    let Some(ttl) = NOT_FOUND_TTL.get() else {
+        return;
+    };
+
+    let now = Instant::now();
+    let mut cache = not_found_cache_lock();
+
+    // Drop the expired responses, so speculative requests can not grow the cache forever.
+    cache.retain(|_, (time, _)| now.saturating_duration_since(*time) < *ttl);
+    cache.insert(path.to_owned(), (now, body.to_owned()));
+}
+
+/// Fetches the unexpired cached upstream HTTP 404 response body for the request path.
+pub fn metadata_fetch_not_found(path: &str) -> Option<String> {
+    let ttl = NOT_FOUND_TTL.get()?;
+
+    not_found_cache_lock()
+        .get(path)
+        .filter(|(time, _)| time.elapsed() < *ttl)
+        .map(|(_, body)| body.clone())
+}
+
+/// Erases the cached upstream HTTP 404 response for the request path.
+pub fn metadata_invalidate_not_found(path: &str) {
+    if NOT_FOUND_TTL.get().is_some() {
+        not_found_cache_lock().remove(path);
+    }
+}
+
+/// Erases the cached upstream HTTP 404 responses for the matching request paths.
+///
+/// Returns the number of cached responses erased.
+pub fn metadata_invalidate_not_found_matching(matches: impl Fn(&str) -> bool) -> usize {
+    let mut cache = not_found_cache_lock();
+    let len = cache.len();
+
+    cache.retain(|path, _| !matches(path));
+    len - cache.len()
+}
+
+/// Counts the index entries in the volatile metadata cache.
+pub fn metadata_cache_len() -> usize {
+    index_cache_read().len()
+}
+
+/// Converts the system time into the UNIX timestamp in seconds.
+fn to_unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Formats one index entry metadata record for the persistent storage.
+///
+/// The record fields are: crate name, ETag, Last-Modified time and
+/// the last upstream server access time, separated by tabs.
+/// Missing optional fields are stored as `-`.
+pub fn format_metadata_record(entry: &IndexEntry, now: SystemTime) -> Option<String> {
+    let etag = entry.etag().unwrap_or("-");
+
+    // Entity tags containing the field separators can not be stored.
+    if etag.contains(['\t', '\n']) {
+        return None;
+    }
+
+    let mtime = entry
+        .mtime()
+        .map_or_else(|| "-".to_owned(), |mtime| to_unix_secs(mtime).to_string());
+
+    let atime = match entry.last_updated_age() {
+        Some(age) => to_unix_secs(now - age).to_string(),
+        // Keep the expired entries expired when restored.
+        None if entry.is_expired() => "0".to_owned(),
+        None => "-".to_owned(),
+    };
+
+    Some(format!("{}\t{etag}\t{mtime}\t{atime}", entry.name()))
+}
+
+/// Parses one index entry metadata record from the persistent storage.
+pub fn parse_metadata_record(record: &str, now: SystemTime) -> Option<IndexEntry> {
+    let mut fields = record.split('\t');
+
+    let (Some(name), Some(etag), Some(mtime), Some(atime), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return None;
+    };
+
+    let mut entry = IndexEntry::new(name);
+
+    if etag != "-" {
+        entry.set_etag(etag);
+    }
+
+    if mtime != "-" {
+        entry.set_mtime(UNIX_EPOCH + Duration::from_secs(mtime.parse().ok()?));
+    }
+
+    if atime != "-" {
+        let atime = UNIX_EPOCH + Duration::from_secs(atime.parse().ok()?);
+        let age = now.duration_since(atime).unwrap_or_default();
+
+        // The entries older than the monotonic clock origin are expired.
+        match Instant::now().checked_sub(age) {
+            Some(atime) => entry.set_last_updated_at(atime),
+            None => entry.set_expired(),
+        }
+    }
+
+    Some(entry)
+}
+
+/// Serializes the index entry metadata cache file saves
+static METADATA_SAVE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Saves the index entry metadata cache into a file.
+///
+/// The file is replaced atomically, so it is never left half-written.
+/// The periodic and the shutdown saves share the temporary file,
+/// so they are serialized.
+pub fn metadata_save(path: &Path) -> Result<usize> {
+    let _guard = METADATA_SAVE_LOCK
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+
+    let now = SystemTime::now();
+    let mut text = String::new();
+    let mut count = 0;
+
+    for entry in index_cache_read().values() {
+        if let Some(record) = format_metadata_record(entry, now) {
+            writeln!(text, "{record}").unwrap();
+            count += 1;
+        }
+    }
+
+    let tmp_path = path.with_extension("tmp");
+
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir)?;
+    }
+
+    write(&tmp_path, text)?;
+    rename(tmp_path, path)?;
+
+    Ok(count)
+}
+
+/// Loads the index entry metadata cache from a file.
+pub fn metadata_load(path: &Path) -> Result<usize> {
+    let now = SystemTime::now();
+    let text = read_to_string(path)?;
+
+    let mut cache = index_cache_write();
+    let mut count = 0;
+
+    for entry in text.lines().filter_map(|r| parse_metadata_record(r, now)) {
+        cache.insert(entry.name().to_owned(), entry);
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_dir::TestDir;
+    use super::*;
+
+    #[test]
+    fn test_metadata_record() {
+        let now = SystemTime::now();
+
+        let mut entry = IndexEntry::new("serde");
+        entry.set_etag("\"abc\"");
+        entry.set_last_modified("Sun, 06 Nov 1994 08:49:37 GMT");
+        entry.set_last_updated();
+
+        let record = format_metadata_record(&entry, now).unwrap();
+        let parsed = parse_metadata_record(&record, now).unwrap();
+
+        assert_eq!(parsed.name(), "serde");
+        assert_eq!(parsed.etag(), entry.etag());
+        assert_eq!(parsed.last_modified(), entry.last_modified());
+        assert!(parsed.last_updated_age().unwrap() < Duration::from_secs(2));
+
+        let parsed = parse_metadata_record("a\t-\t-\t-", now).unwrap();
+        assert_eq!(parsed, IndexEntry::new("a"));
+
+        let mut entry = IndexEntry::new("b");
+        entry.set_expired();
+
+        let record = format_metadata_record(&entry, now).unwrap();
+        assert_eq!(record, "b\t-\t-\t0");
+        let parsed = parse_metadata_record(&record, now).unwrap();
+        assert!(parsed.is_expired_with_ttl(&Duration::from_secs(365 * 86400)));
+
+        assert_eq!(parse_metadata_record("a\t-\t-", now), None);
+        assert_eq!(parse_metadata_record("a\t-\tx\t-", now), None);
+    }
+
+    #[test]
+    fn test_store_unchanged_entry() {
+        let mut entry = IndexEntry::new("store-unchanged-test");
+        entry.set_etag("\"abc\"");
+
+        let old_atime = Instant::now() - Duration::from_secs(10);
+        entry.set_last_updated_at(old_atime);
+        MemoryMetadata.store(&entry);
+
+        // Close access time updates are coalesced.
+        entry.set_last_updated_at(old_atime + Duration::from_millis(100));
+        MemoryMetadata.store(&entry);
+        let cached = MemoryMetadata.fetch(entry.name()).unwrap();
+        assert_eq!(cached.last_updated_at(), Some(old_atime));
+
+        entry.set_last_updated();
+        MemoryMetadata.store(&entry);
+        assert_eq!(MemoryMetadata.fetch(entry.name()), Some(entry.clone()));
+
+        // Changed validators always replace the entry.
+        entry.set_etag("\"def\"");
+        MemoryMetadata.store(&entry);
+        assert_eq!(MemoryMetadata.fetch(entry.name()), Some(entry.clone()));
+
+        MemoryMetadata.invalidate(entry.name());
+    }
+
+    #[test]
+    fn test_not_found_cache() {
+        let path = "/index/no/t-/not-found-test";
+
+        metadata_not_found_init(Duration::from_secs(60));
+        assert_eq!(metadata_fetch_not_found(path), None);
+
+        metadata_store_not_found(path, "{}");
+        assert_eq!(metadata_fetch_not_found(path).as_deref(), Some("{}"));
+
+        metadata_invalidate_not_found(path);
+        assert_eq!(metadata_fetch_not_found(path), None);
+
+        // Expired responses are never served.
+        let expired = Instant::now() - Duration::from_secs(61);
+        not_found_cache_lock().insert(path.to_owned(), (expired, "{}".to_owned()));
+        assert_eq!(metadata_fetch_not_found(path), None);
+
+        metadata_invalidate_not_found(path);
+
+        metadata_store_not_found(path, "{}");
+        assert_eq!(metadata_invalidate_not_found_matching(|p| p == path), 1);
+        assert_eq!(metadata_fetch_not_found(path), None);
+    }
+
+    #[test]
+    fn test_concurrent_save() {
+        let dir = TestDir::new("metadata-save");
+        let path = dir.join("index-metadata");
+
+        let names: Vec<_> = (0..1000)
+            .map(|i| format!("concurrent-save-test-{i}"))
+            .collect();
+        for name in &names {
+            MemoryMetadata.store(&IndexEntry::new(name));
+        }
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let path = path.clone();
+                std::thread::spawn(move || (0..20).try_for_each(|_| metadata_save(&path).map(drop)))
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap().unwrap();
+        }
+
+        assert!(path.exists());
+        assert!(!path.with_extension("tmp").exists());
+
+        for name in &names {
+            MemoryMetadata.invalidate(name);
+        }
+    }
+
+    #[test]
+    fn test_poisoned_lock() {
+        let entry = IndexEntry::new("poisoned-lock-test");
+
+        std::thread::spawn(|| {
+            let _guard = index_cache_write();
+            panic!("poisoning the index metadata cache");
+        })
+        .join()
+        .unwrap_err();
+
+        MemoryMetadata.store(&entry);
+        assert_eq!(MemoryMetadata.fetch(entry.name()), Some(entry.clone()));
+        assert!(!INDEX_CACHE.is_poisoned());
+
+        MemoryMetadata.invalidate(entry.name());
+        assert_eq!(MemoryMetadata.fetch(entry.name()), None);
+    }
 }
@@ -0,0 +1,187 @@
+//! Upstream request rate limiting helpers
+
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Token bucket rate limiter state
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Token refill rate per second
+    rate: f64,
+    /// Maximum number of tokens stored
+    burst: f64,
+    /// Available tokens + last refill time
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// Creates a full token bucket allowing `qps` requests per second.
+    ///
+    /// The rate must be at least 1 request per second.
+    #[must_use]
+    pub fn new(qps: u32) -> Self {
+        assert!(qps > 0, "zero token bucket rate");
+        let rate = f64::from(qps);
+
+        TokenBucket {
+            rate,
+            burst: rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// Tries to take one token, waiting for at most `max_wait` for a refill.
+    pub fn acquire(&self, max_wait: Duration) -> bool {
+        let deadline = Instant::now() + max_wait;
+
+        loop {
+            let delay = {
+                let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+                let (tokens, last) = &mut *state;
+
+                let now = Instant::now();
+                let elapsed = now.duration_since(*last).as_secs_f64();
+                *tokens = (*tokens + elapsed * self.rate).min(self.burst);
+                *last = now;
+
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    return true;
+                }
+
+                Duration::from_secs_f64((1.0 - *tokens) / self.rate)
+            };
+
+            if Instant::now() + delay > deadline {
+                return false;
+            }
+
+            sleep(delay);
+        }
+    }
+}
+
+/// Counting semaphore limiting concurrent requests
+#[derive(Debug)]
+pub struct Semaphore {
+    /// Number of permits available
+    permits: Mutex<usize>,
+    /// Signaled when a permit is released
+    released: Condvar,
+}
+
+/// Upstream request permit released when dropped
+#[derive(Debug)]
+pub struct UpstreamPermit {
+    /// Semaphore the permit was taken from (none if unlimited)
+    semaphore: Option<&'static Semaphore>,
+}
+
+impl Semaphore {
+    /// Creates a semaphore with `permits` permits available.
+    #[must_use]
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Tries to take one permit, waiting for at most `max_wait` for a release.
+    pub fn acquire(&'static self, max_wait: Duration) -> Option<UpstreamPermit> {
+        let permits = self.permits.lock().unwrap_or_else(|e| e.into_inner());
+
+        let (mut permits, _) = self
+            .released
+            .wait_timeout_while(permits, max_wait, |permits| *permits == 0)
+            .unwrap_or_else(|e| e.into_inner());
+
+        if *permits == 0 {
+            return None;
+        }
+
+        *permits -= 1;
+
+        Some(UpstreamPermit {
+            semaphore: Some(self),
+        })
+    }
+}
+
+impl Drop for UpstreamPermit {
+    fn drop(&mut self) {
+        if let Some(semaphore) = self.semaphore {
+            *semaphore.permits.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+            semaphore.released.notify_one();
+        }
+    }
+}
+
+/// Global upstream request rate limiter
+static UPSTREAM_BUCKET: OnceLock<TokenBucket> = OnceLock::new();
+
+/// Global upstream request concurrency limiter
+static UPSTREAM_PERMITS: OnceLock<Semaphore> = OnceLock::new();
+
+/// Enables the global upstream request rate limit.
+pub fn upstream_rate_limit_init(qps: u32) {
+    UPSTREAM_BUCKET.get_or_init(|| TokenBucket::new(qps));
+}
+
+/// Takes one upstream request token, waiting for at most `max_wait`.
+///
+/// Always succeeds if the upstream rate limit is not enabled.
+pub fn upstream_rate_limit_acquire(max_wait: Duration) -> bool {
+    UPSTREAM_BUCKET
+        .get()
+        .is_none_or(|bucket| bucket.acquire(max_wait))
+}
+
+/// Enables the global upstream request concurrency limit.
+pub fn upstream_concurrency_init(max: usize) {
+    UPSTREAM_PERMITS.get_or_init(|| Semaphore::new(max));
+}
+
+/// Takes one upstream request permit, waiting for at most `max_wait`.
+///
+/// Always succeeds if the upstream concurrency limit is not enabled.
+pub fn upstream_permit_acquire(max_wait: Duration) -> Option<UpstreamPermit> {
+    match UPSTREAM_PERMITS.get() {
+        Some(semaphore) => semaphore.acquire(max_wait),
+        None => Some(UpstreamPermit { semaphore: None }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(2);
+
+        assert!(bucket.acquire(Duration::ZERO));
+        assert!(bucket.acquire(Duration::ZERO));
+        assert!(!bucket.acquire(Duration::ZERO));
+
+        // One token is refilled every 500 ms.
+        assert!(bucket.acquire(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_semaphore() {
+        static SEMAPHORE: Semaphore = Semaphore::new(1);
+
+        let permit = SEMAPHORE.acquire(Duration::ZERO).unwrap();
+        assert!(SEMAPHORE.acquire(Duration::from_millis(10)).is_none());
+
+        // The queued request gets the permit as soon as it is released.
+        let waiter = std::thread::spawn(|| SEMAPHORE.acquire(Duration::from_secs(10)).is_some());
+        std::thread::sleep(Duration::from_millis(50));
+        drop(permit);
+
+        assert!(waiter.join().unwrap());
+        assert!(SEMAPHORE.acquire(Duration::ZERO).is_some());
+    }
+}
@@ -0,0 +1,334 @@
+//! Redis shared index entry metadata backend
+
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{debug, warn};
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+use super::metadata_cache::{
+    format_metadata_record, parse_metadata_record, MemoryMetadata, MetadataBackend,
+};
+use super::IndexEntry;
+
+/// Redis server default port
+const REDIS_DEFAULT_PORT: u16 = 6379;
+
+/// Redis server connect and I/O timeout
+const REDIS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Delay before reconnecting to the Redis server after a failure
+const REDIS_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// Index entry metadata key prefix
+const REDIS_KEY_PREFIX: &str = "crates-io-proxy:index:";
+
+/// Redis server reply value
+#[derive(Debug, PartialEq, Eq)]
+enum Reply {
+    /// Simple string or integer reply
+    Status(String),
+    /// Bulk string reply (`None` for nil)
+    Bulk(Option<Vec<u8>>),
+}
+
+/// Encodes the Redis command as an array of bulk strings.
+#[must_use]
+fn encode_command(args: &[&[u8]]) -> Vec<u8> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+
+    buf
+}
+
+/// Reads one Redis server reply from the stream.
+fn read_reply(reader: &mut impl BufRead) -> Result<Reply> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+
+    let Some(line) = line.strip_suffix("\r\n") else {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "truncated Redis reply",
+        ));
+    };
+
+    let (kind, value) = line.split_at(line.len().min(1));
+
+    match kind {
+        "+" | ":" => Ok(Reply::Status(value.to_owned())),
+        "-" => Err(Error::other(format!("Redis error: {value}"))),
+        "$" => {
+            let Ok(len) = value.parse::<i64>() else {
+                return Err(Error::new(ErrorKind::InvalidData, "bad Redis bulk length"));
+            };
+
+            let Ok(len) = usize::try_from(len) else {
+                return Ok(Reply::Bulk(None));
+            };
+
+            let mut data = vec![0; len + 2];
+            reader.read_exact(&mut data)?;
+            data.truncate(len);
+
+            Ok(Reply::Bulk(Some(data)))
+        }
+        _ => Err(Error::new(ErrorKind::InvalidData, "unexpected Redis reply")),
+    }
+}
+
+/// Redis server connection state
+#[derive(Default)]
+struct RedisConnection {
+    /// Lazily (re)established server connection
+    stream: Option<BufReader<TcpStream>>,
+    /// Time to reconnect at after a failure (optional)
+    retry_at: Option<Instant>,
+}
+
+/// Redis shared index entry metadata storage
+///
+/// Falls back to the in-memory metadata storage while
+/// the Redis server is unavailable.
+pub struct RedisMetadata {
+    /// Redis server URL (`redis://[:password@]host[:port][/db]`)
+    url: Url,
+    /// Server connection state
+    conn: Mutex<RedisConnection>,
+}
+
+impl RedisMetadata {
+    /// Creates a new Redis metadata backend for the server URL.
+    pub fn new(url: Url) -> std::result::Result<Self, String> {
+        if url.scheme() != "redis" || url.host_str().is_none() {
+            return Err(format!("unsupported Redis URL: {url}"));
+        }
+
+        Ok(RedisMetadata {
+            url,
+            conn: Mutex::new(RedisConnection::default()),
+        })
+    }
+
+    /// Connects to the Redis server, authenticates and selects the database.
+    fn connect(&self) -> Result<BufReader<TcpStream>> {
+        let host = self.url.host_str().unwrap_or_default();
+        let port = self.url.port().unwrap_or(REDIS_DEFAULT_PORT);
+
+        let mut last_err = Error::new(ErrorKind::NotFound, "no Redis server address");
+
+        for addr in (host, port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, REDIS_TIMEOUT) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(REDIS_TIMEOUT))?;
+                    stream.set_write_timeout(Some(REDIS_TIMEOUT))?;
+
+                    let mut conn = BufReader::new(stream);
+
+                    if let Some(password) = self.url.password() {
+                        let password: Vec<u8> = percent_decode_str(password).collect();
+                        Self::call(&mut conn, &[b"AUTH", &password])?;
+                    }
+
+                    let db = self.url.path().trim_matches('/');
+                    if !db.is_empty() {
+                        Self::call(&mut conn, &[b"SELECT", db.as_bytes()])?;
+                    }
+
+                    debug!("cache: connected to the Redis server at {addr}");
+                    return Ok(conn);
+                }
+                Err(e) => last_err = e,
+            }
+        }
+
+        Err(last_err)
+    }
+
+    /// Sends one command and reads its reply.
+    fn call(conn: &mut BufReader<TcpStream>, args: &[&[u8]]) -> Result<Reply> {
+        conn.get_mut().write_all(&encode_command(args))?;
+        read_reply(conn)
+    }
+
+    /// Runs one command, reconnecting to the server if required.
+    ///
+    /// The connection is dropped on any error, and the server is not
+    /// contacted again until the reconnect delay expires.
+    /// Returns `None` if the server is unavailable.
+    fn command(&self, args: &[&[u8]]) -> Option<Reply> {
+        let mut guard = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        let conn = &mut *guard;
+
+        if conn
+            .retry_at
+            .is_some_and(|retry_at| Instant::now() < retry_at)
+        {
+            return None;
+        }
+
+        let result = match conn.stream.as_mut() {
+            Some(stream) => Self::call(stream, args),
+            None => self
+                .connect()
+                .and_then(|stream| Self::call(conn.stream.insert(stream), args)),
+        };
+
+        match result {
+            Ok(reply) => {
+                conn.retry_at = None;
+                Some(reply)
+            }
+            Err(e) => {
+                warn!(
+                    "cache: Redis metadata backend request failed: {e}, \
+                     using in-memory metadata for {} seconds",
+                    REDIS_RECONNECT_DELAY.as_secs()
+                );
+                conn.stream = None;
+                conn.retry_at = Some(Instant::now() + REDIS_RECONNECT_DELAY);
+                None
+            }
+        }
+    }
+}
+
+impl MetadataBackend for RedisMetadata {
+    fn store(&self, entry: &IndexEntry) {
+        let Some(record) = format_metadata_record(entry, SystemTime::now()) else {
+            return;
+        };
+
+        let key = format!("{REDIS_KEY_PREFIX}{}", entry.name());
+
+        if self
+            .command(&[b"SET", key.as_bytes(), record.as_bytes()])
+            .is_none()
+        {
+            MemoryMetadata.store(entry);
+        }
+    }
+
+    fn fetch(&self, name: &str) -> Option<IndexEntry> {
+        let key = format!("{REDIS_KEY_PREFIX}{name}");
+
+        let Some(reply) = self.command(&[b"GET", key.as_bytes()]) else {
+            return MemoryMetadata.fetch(name);
+        };
+
+        let Reply::Bulk(Some(record)) = reply else {
+            return None;
+        };
+
+        parse_metadata_record(&String::from_utf8(record).ok()?, SystemTime::now())
+    }
+
+    fn invalidate(&self, name: &str) {
+        let key = format!("{REDIS_KEY_PREFIX}{name}");
+        self.command(&[b"DEL", key.as_bytes()]);
+
+        // Never serve the metadata stored during an outage after invalidation.
+        MemoryMetadata.invalidate(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    /// Reads one Redis command sent by the client.
+    fn read_command(reader: &mut impl BufRead) -> Vec<String> {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let count: usize = line.trim_start_matches('*').trim_end().parse().unwrap();
+
+        (0..count)
+            .map(|_| {
+                let mut len = String::new();
+                reader.read_line(&mut len).unwrap();
+
+                let mut arg = String::new();
+                reader.read_line(&mut arg).unwrap();
+                arg.trim_end().to_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_redis_protocol() {
+        assert_eq!(
+            encode_command(&[b"GET", b"key"]),
+            b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n"
+        );
+
+        let mut replies = &b"+OK\r\n:1\r\n$5\r\nab\r\nc\r\n$-1\r\n-ERR bad\r\n"[..];
+        assert_eq!(
+            read_reply(&mut replies).unwrap(),
+            Reply::Status("OK".to_owned())
+        );
+        assert_eq!(
+            read_reply(&mut replies).unwrap(),
+            Reply::Status("1".to_owned())
+        );
+        assert_eq!(
+            read_reply(&mut replies).unwrap(),
+            Reply::Bulk(Some(b"ab\r\nc".to_vec()))
+        );
+        assert_eq!(read_reply(&mut replies).unwrap(), Reply::Bulk(None));
+        assert!(read_reply(&mut replies).is_err());
+        assert!(read_reply(&mut replies).is_err());
+    }
+
+    #[test]
+    fn test_redis_auth() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+
+            let auth = read_command(&mut reader);
+            reader.get_mut().write_all(b"+OK\r\n").unwrap();
+            let get = read_command(&mut reader);
+            reader.get_mut().write_all(b"$-1\r\n").unwrap();
+
+            (auth, get)
+        });
+
+        let url = Url::parse(&format!("redis://:p%40ss%3Aword@127.0.0.1:{port}")).unwrap();
+        let backend = RedisMetadata::new(url).unwrap();
+        assert_eq!(backend.fetch("serde"), None);
+
+        let (auth, get) = server.join().unwrap();
+        assert_eq!(auth, ["AUTH", "p@ss:word"]);
+        assert_eq!(get, ["GET", "crates-io-proxy:index:serde"]);
+    }
+
+    #[test]
+    fn test_redis_fallback() {
+        let url = Url::parse("redis://127.0.0.1:1").unwrap();
+        let backend = RedisMetadata::new(url).unwrap();
+
+        let mut entry = IndexEntry::new("redis-fallback-test");
+        entry.set_etag("\"abc\"");
+
+        // The metadata is kept in memory while the server is unavailable.
+        backend.store(&entry);
+        assert!(backend.conn.lock().unwrap().retry_at.is_some());
+        assert_eq!(backend.fetch(entry.name()), Some(entry.clone()));
+
+        backend.invalidate(entry.name());
+        assert_eq!(backend.fetch(entry.name()), None);
+    }
+}
@@ -0,0 +1,115 @@
+//! Per-request processing context
+
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::client_limit::ClientSlot;
+use crate::stats::{stats_observe_request_latency, RequestKind};
+
+/// Request processing context
+///
+/// The context is kept alive until the response is sent to the client.
+#[derive(Debug)]
+pub struct RequestContext {
+    /// Request processing start time
+    start: Instant,
+    /// Requested crate name or URL path
+    subject: String,
+    /// Served request kind for the latency metrics (optional)
+    kind: Option<RequestKind>,
+    /// Cache lookup result
+    cache_result: &'static str,
+    /// Slow request logging threshold (optional)
+    slow_threshold: Option<Duration>,
+    /// Concurrent request slot taken by the client
+    client_slot: Option<ClientSlot>,
+}
+
+/// Checks if the cache lookup result means the response came from upstream.
+///
+/// Returns `None` for the responses neither served from the cache
+/// nor fetched from the upstream server, e.g. redirects.
+fn is_upstream_result(cache_result: &str) -> Option<bool> {
+    match cache_result {
+        "miss"
+        | "client-no-cache"
+        | "client-no-store"
+        | "cold-reconstruct"
+        | "full-forward"
+        | "max-age-revalidate"
+        | "ttl-expired-refresh" => Some(true),
+        result if result.ends_with("-hit") => Some(false),
+        _ => None,
+    }
+}
+
+impl Drop for RequestContext {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+
+        if let (Some(kind), Some(upstream)) = (self.kind, is_upstream_result(self.cache_result)) {
+            stats_observe_request_latency(kind, upstream, elapsed);
+        }
+
+        if self.slow_threshold.is_some_and(|limit| elapsed > limit) {
+            warn!(
+                "proxy: slow request for {}: cache={} took {} ms",
+                self.subject,
+                self.cache_result,
+                elapsed.as_millis()
+            );
+        }
+    }
+}
+
+impl RequestContext {
+    /// Creates a new request context for the URL path.
+    #[must_use]
+    pub fn new(url: &str) -> Self {
+        RequestContext {
+            start: Instant::now(),
+            subject: url.to_owned(),
+            kind: None,
+            cache_result: "none",
+            slow_threshold: None,
+            client_slot: None,
+        }
+    }
+
+    /// Sets the requested crate name and the request kind.
+    pub fn set_crate_name(&mut self, kind: RequestKind, name: &str) {
+        name.clone_into(&mut self.subject);
+        self.kind = Some(kind);
+    }
+
+    /// Records the cache lookup result.
+    pub fn set_cache_result(&mut self, result: &'static str) {
+        self.cache_result = result;
+    }
+
+    /// Enables logging the request if it takes longer than `threshold`.
+    pub fn set_slow_threshold(&mut self, threshold: Duration) {
+        self.slow_threshold = Some(threshold);
+    }
+
+    /// Attaches the concurrent request slot taken by the client.
+    pub fn set_client_slot(&mut self, slot: ClientSlot) {
+        self.client_slot = Some(slot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_upstream_result() {
+        assert_eq!(is_upstream_result("miss"), Some(true));
+        assert_eq!(is_upstream_result("ttl-expired-refresh"), Some(true));
+        assert_eq!(is_upstream_result("cache-hit"), Some(false));
+        assert_eq!(is_upstream_result("stale-metadata-hit"), Some(false));
+        assert_eq!(is_upstream_result("redirect"), None);
+        assert_eq!(is_upstream_result("none"), None);
+    }
+}
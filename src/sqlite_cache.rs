@@ -0,0 +1,148 @@
+//! SQLite index entry cache backend
+
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use log::error;
+use rusqlite::{params, Connection, OptionalExtension, Result};
+
+use super::index_cache::IndexCache;
+use super::IndexEntry;
+
+/// Index entry cache database schema
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS index_entries (
+    name TEXT PRIMARY KEY NOT NULL,
+    etag TEXT,
+    mtime INTEGER,
+    data BLOB NOT NULL
+)";
+
+/// Single file SQLite index entry cache
+#[derive(Debug)]
+pub struct SqliteIndexCache {
+    /// Database connection shared by all request threads
+    conn: Mutex<Connection>,
+}
+
+impl SqliteIndexCache {
+    /// Opens (or creates) the index entry cache database file.
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    /// Initializes the index entry cache database schema.
+    fn with_connection(conn: Connection) -> Result<Self> {
+        conn.execute(SCHEMA, [])?;
+
+        Ok(SqliteIndexCache {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Runs a database operation with the shared connection locked.
+    fn with_conn<T>(&self, f: impl FnOnce(&Connection) -> Result<T>) -> Result<T> {
+        f(&self.conn.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+impl IndexCache for SqliteIndexCache {
+    fn store(&self, entry: &IndexEntry, data: &[u8]) {
+        let mtime = entry
+            .mtime()
+            .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs());
+
+        self.with_conn(|conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO index_entries (name, etag, mtime, data) \
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![entry.name(), entry.etag(), mtime, data],
+            )
+        })
+        .map(|_| ())
+        .unwrap_or_else(|e| error!("cache: failed to store index entry in database: {e}"));
+    }
+
+    fn fetch(&self, entry: &IndexEntry) -> Option<Vec<u8>> {
+        self.with_conn(|conn| {
+            conn.query_row(
+                "SELECT data FROM index_entries WHERE name = ?1",
+                [entry.name()],
+                |row| row.get(0),
+            )
+            .optional()
+        })
+        .unwrap_or_else(|e| {
+            error!("cache: failed to fetch index entry from database: {e}");
+            None
+        })
+    }
+
+    fn try_find(&self, name: &str) -> Option<IndexEntry> {
+        let (etag, mtime): (Option<String>, Option<u64>) = self
+            .with_conn(|conn| {
+                conn.query_row(
+                    "SELECT etag, mtime FROM index_entries WHERE name = ?1",
+                    [name],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()
+            })
+            .unwrap_or_else(|e| {
+                error!("cache: failed to find index entry in database: {e}");
+                None
+            })?;
+
+        let mut entry = IndexEntry::new(name);
+
+        if let Some(etag) = etag {
+            entry.set_etag(&etag);
+        }
+
+        // Index entries without any metadata can not be revalidated.
+        entry.set_mtime(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime?));
+
+        Some(entry)
+    }
+
+    fn remove(&self, entry: &IndexEntry) -> bool {
+        self.with_conn(|conn| {
+            conn.execute("DELETE FROM index_entries WHERE name = ?1", [entry.name()])
+        })
+        .map(|count| count > 0)
+        .unwrap_or_else(|e| {
+            error!("cache: failed to remove index entry from database: {e}");
+            false
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sqlite_index_cache() {
+        let cache =
+            SqliteIndexCache::with_connection(Connection::open_in_memory().unwrap()).unwrap();
+
+        let mut entry = IndexEntry::new("serde");
+        assert!(cache.fetch(&entry).is_none());
+        assert!(cache.try_find("serde").is_none());
+
+        entry.set_etag("\"abc\"");
+        entry.set_last_modified("Thu, 01 Jan 2015 00:00:00 GMT");
+        cache.store(&entry, b"{}\n");
+
+        assert_eq!(cache.fetch(&entry).unwrap(), b"{}\n");
+
+        let found = cache.try_find("serde").unwrap();
+        assert_eq!(found.etag(), Some("\"abc\""));
+        assert_eq!(found.mtime(), entry.mtime());
+
+        assert!(cache.remove(&entry));
+        assert!(!cache.remove(&entry));
+        assert!(cache.fetch(&entry).is_none());
+    }
+}
@@ -0,0 +1,615 @@
+//! Cache activity statistics counters
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result as FmtResult, Write as _};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Upstream response latency histogram bucket upper bounds in milliseconds
+const UPSTREAM_LATENCY_BUCKETS_MS: [u64; 8] = [50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Request latency histogram bucket upper bounds in milliseconds
+const REQUEST_LATENCY_BUCKETS_MS: [u64; 12] =
+    [1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Prometheus metric name prefix
+const METRICS_PREFIX: &str = "crates_io_proxy";
+
+/// Number of the most downloaded crates exposed with their own download counters
+const CRATE_DOWNLOADS_TOP: usize = 50;
+
+/// Maximum number of distinct crate names to count the downloads of
+const CRATE_DOWNLOADS_MAX_TRACKED: usize = 10_000;
+
+/// Download counter label value for the crates not in the top list
+const CRATE_DOWNLOADS_OTHER: &str = "other";
+
+/// Served request kind for the request latency histograms
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    /// Registry index entry request
+    Index,
+    /// Crate file download request
+    Crate,
+}
+
+impl RequestKind {
+    /// Gets the request kind metric label value.
+    fn label(self) -> &'static str {
+        match self {
+            RequestKind::Index => "index",
+            RequestKind::Crate => "crate",
+        }
+    }
+}
+
+/// Latency histogram with fixed bucket bounds
+#[derive(Debug)]
+struct LatencyHistogram<const N: usize> {
+    /// Bucket upper bounds in milliseconds
+    bounds: &'static [u64; N],
+    /// Observations per bucket (not cumulative)
+    buckets: [AtomicU64; N],
+    /// Observations recorded
+    count: AtomicU64,
+    /// Observed latency sum in microseconds
+    sum_us: AtomicU64,
+}
+
+impl<const N: usize> LatencyHistogram<N> {
+    /// Creates a new empty histogram with the bucket bounds given.
+    const fn new(bounds: &'static [u64; N]) -> Self {
+        LatencyHistogram {
+            bounds,
+            buckets: [const { AtomicU64::new(0) }; N],
+            count: AtomicU64::new(0),
+            sum_us: AtomicU64::new(0),
+        }
+    }
+
+    /// Records the latency observation.
+    fn observe(&self, latency: Duration) {
+        let latency_ms = u64::try_from(latency.as_millis()).unwrap_or(u64::MAX);
+        let latency_us = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+
+        // Latencies above the last bucket bound only count towards `+Inf`.
+        if let Some(index) = self.bounds.iter().position(|&bound| latency_ms <= bound) {
+            stats_count(&self.buckets[index], 1);
+        }
+
+        stats_count(&self.count, 1);
+        stats_count(&self.sum_us, latency_us);
+    }
+
+    /// Renders the histogram samples with the labels given, e.g. `kind="index"`.
+    fn format_samples(&self, text: &mut String, name: &str, labels: &str) {
+        let bucket_labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{labels},")
+        };
+
+        let mut cumulative = 0;
+
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = Duration::from_millis(*bound).as_secs_f64();
+            writeln!(
+                text,
+                "{name}_bucket{{{bucket_labels}le=\"{le}\"}} {cumulative}"
+            )
+            .unwrap();
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = Duration::from_micros(self.sum_us.load(Ordering::Relaxed)).as_secs_f64();
+        writeln!(text, "{name}_bucket{{{bucket_labels}le=\"+Inf\"}} {count}").unwrap();
+
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        };
+
+        writeln!(text, "{name}_sum{labels} {sum}").unwrap();
+        writeln!(text, "{name}_count{labels} {count}").unwrap();
+    }
+}
+
+/// Cumulative cache activity counters
+#[derive(Debug)]
+pub struct Stats {
+    /// Index requests served from the cache
+    pub index_hits: AtomicU64,
+    /// Index requests forwarded to the upstream registry
+    pub index_misses: AtomicU64,
+    /// Crate downloads served from the cache
+    pub crate_hits: AtomicU64,
+    /// Crate downloads forwarded or redirected to the upstream server
+    pub crate_misses: AtomicU64,
+    /// Response body bytes sent to the clients
+    pub bytes_served: AtomicU64,
+    /// Response body bytes received from the upstream servers
+    pub bytes_fetched: AtomicU64,
+    /// Upstream errors forwarded to the clients
+    pub upstream_errors: AtomicU64,
+    /// Upstream requests allowed by the upstream request limits
+    pub upstream_requests: AtomicU64,
+    /// Upstream requests refused by the upstream request limits
+    pub upstream_throttled: AtomicU64,
+    /// Upstream response latency histogram
+    upstream_latency: LatencyHistogram<{ UPSTREAM_LATENCY_BUCKETS_MS.len() }>,
+    /// Served request latency histograms by the request kind and source
+    request_latency: [RequestLatencyHistogram; 4],
+}
+
+/// Served request latency histogram
+type RequestLatencyHistogram = LatencyHistogram<{ REQUEST_LATENCY_BUCKETS_MS.len() }>;
+
+/// Global cache activity counters
+pub static STATS: Stats = Stats {
+    index_hits: AtomicU64::new(0),
+    index_misses: AtomicU64::new(0),
+    crate_hits: AtomicU64::new(0),
+    crate_misses: AtomicU64::new(0),
+    bytes_served: AtomicU64::new(0),
+    bytes_fetched: AtomicU64::new(0),
+    upstream_errors: AtomicU64::new(0),
+    upstream_requests: AtomicU64::new(0),
+    upstream_throttled: AtomicU64::new(0),
+    upstream_latency: LatencyHistogram::new(&UPSTREAM_LATENCY_BUCKETS_MS),
+    request_latency: [const { LatencyHistogram::new(&REQUEST_LATENCY_BUCKETS_MS) }; 4],
+};
+
+/// Per-crate download counters (only if enabled)
+static CRATE_DOWNLOADS: OnceLock<Mutex<CrateDownloads>> = OnceLock::new();
+
+/// Server start time
+static START_TIME: OnceLock<(Instant, SystemTime)> = OnceLock::new();
+
+/// Server start count persisted in the cache directory (optional)
+static START_COUNT: OnceLock<u64> = OnceLock::new();
+
+/// Records the server start time and the persisted server start count.
+pub fn stats_start_init(start: Instant, start_time: SystemTime, start_count: Option<u64>) {
+    START_TIME.get_or_init(|| (start, start_time));
+
+    if let Some(count) = start_count {
+        START_COUNT.get_or_init(|| count);
+    }
+}
+
+/// Adds `n` to the cache activity counter.
+pub fn stats_count(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+/// Enables the per-crate download counters.
+pub fn stats_crate_downloads_init() {
+    CRATE_DOWNLOADS.get_or_init(Mutex::default);
+}
+
+/// Checks if the per-crate download counters are enabled.
+#[must_use]
+pub fn stats_crate_downloads_enabled() -> bool {
+    CRATE_DOWNLOADS.get().is_some()
+}
+
+/// Counts the crate download by the crate name, if the per-crate counters are enabled.
+pub fn stats_count_crate_download(name: &str) {
+    if let Some(downloads) = CRATE_DOWNLOADS.get() {
+        downloads
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .count(name);
+    }
+}
+
+/// Records the upstream server response latency.
+pub fn stats_observe_upstream_latency(latency: Duration) {
+    STATS.upstream_latency.observe(latency);
+}
+
+/// Records the served request latency by the request kind,
+/// and whether the response came from the upstream server or the cache.
+pub fn stats_observe_request_latency(kind: RequestKind, upstream: bool, latency: Duration) {
+    STATS.request_latency(kind, upstream).observe(latency);
+}
+
+/// Crate download counters by the crate name
+#[derive(Debug, Default)]
+struct CrateDownloads {
+    /// Download counts by the crate name (bounded)
+    counts: HashMap<String, u64>,
+    /// Total crate downloads, including the crates not tracked by name
+    total: u64,
+}
+
+impl CrateDownloads {
+    /// Counts the crate download.
+    ///
+    /// The downloads of the crates seen after the tracked crate names limit
+    /// is reached are only counted in the total.
+    fn count(&mut self, name: &str) {
+        self.total += 1;
+
+        if let Some(count) = self.counts.get_mut(name) {
+            *count += 1;
+        } else if self.counts.len() < CRATE_DOWNLOADS_MAX_TRACKED {
+            self.counts.insert(name.to_owned(), 1);
+        }
+    }
+
+    /// Renders the download counters of the `top` most downloaded crates,
+    /// and the rest of the downloads as the `crate="other"` sample.
+    fn format_samples(&self, text: &mut String, name: &str, top: usize) {
+        // A crate really named "other" is always lumped together with the rest.
+        let mut counts: Vec<(&str, u64)> = self
+            .counts
+            .iter()
+            .filter(|(crate_name, _)| *crate_name != CRATE_DOWNLOADS_OTHER)
+            .map(|(crate_name, &count)| (crate_name.as_str(), count))
+            .collect();
+
+        counts.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        counts.truncate(top);
+
+        let mut other = self.total;
+
+        for (crate_name, count) in counts {
+            writeln!(text, "{name}{{crate=\"{crate_name}\"}} {count}").unwrap();
+            other -= count;
+        }
+
+        writeln!(text, "{name}{{crate=\"{CRATE_DOWNLOADS_OTHER}\"}} {other}").unwrap();
+    }
+}
+
+/// Point-in-time copy of the cache activity counters
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// Index requests served from the cache
+    pub index_hits: u64,
+    /// Index requests forwarded to the upstream registry
+    pub index_misses: u64,
+    /// Crate downloads served from the cache
+    pub crate_hits: u64,
+    /// Crate downloads forwarded or redirected to the upstream server
+    pub crate_misses: u64,
+    /// Response body bytes sent to the clients
+    pub bytes_served: u64,
+    /// Response body bytes received from the upstream servers
+    pub bytes_fetched: u64,
+    /// Upstream errors forwarded to the clients
+    pub upstream_errors: u64,
+    /// Upstream requests allowed by the upstream request limits
+    pub upstream_requests: u64,
+    /// Upstream requests refused by the upstream request limits
+    pub upstream_throttled: u64,
+}
+
+impl Stats {
+    /// Gets the served request latency histogram by the request kind and source.
+    fn request_latency(&self, kind: RequestKind, upstream: bool) -> &RequestLatencyHistogram {
+        &self.request_latency[kind as usize * 2 + usize::from(upstream)]
+    }
+
+    /// Copies the current counter values.
+    #[must_use]
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            index_hits: self.index_hits.load(Ordering::Relaxed),
+            index_misses: self.index_misses.load(Ordering::Relaxed),
+            crate_hits: self.crate_hits.load(Ordering::Relaxed),
+            crate_misses: self.crate_misses.load(Ordering::Relaxed),
+            bytes_served: self.bytes_served.load(Ordering::Relaxed),
+            bytes_fetched: self.bytes_fetched.load(Ordering::Relaxed),
+            upstream_errors: self.upstream_errors.load(Ordering::Relaxed),
+            upstream_requests: self.upstream_requests.load(Ordering::Relaxed),
+            upstream_throttled: self.upstream_throttled.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Renders the counters in the Prometheus text exposition format.
+    #[must_use]
+    pub fn format_metrics(
+        &self,
+        active_workers: usize,
+        index_fetches: usize,
+        crate_fetches: usize,
+    ) -> String {
+        let stats = self.snapshot();
+        let mut text = String::new();
+
+        for (name, help, value) in [
+            (
+                "index_hits_total",
+                "Index requests served from the cache.",
+                stats.index_hits,
+            ),
+            (
+                "index_misses_total",
+                "Index requests forwarded upstream.",
+                stats.index_misses,
+            ),
+            (
+                "crate_hits_total",
+                "Crate downloads served from the cache.",
+                stats.crate_hits,
+            ),
+            (
+                "crate_misses_total",
+                "Crate downloads forwarded upstream.",
+                stats.crate_misses,
+            ),
+            (
+                "served_bytes_total",
+                "Response bytes sent to the clients.",
+                stats.bytes_served,
+            ),
+            (
+                "fetched_bytes_total",
+                "Response bytes received from upstream.",
+                stats.bytes_fetched,
+            ),
+            (
+                "upstream_errors_total",
+                "Upstream errors forwarded to the clients.",
+                stats.upstream_errors,
+            ),
+            (
+                "upstream_requests_total",
+                "Upstream requests allowed by the rate and concurrency limits.",
+                stats.upstream_requests,
+            ),
+            (
+                "upstream_throttled_total",
+                "Upstream requests refused by the rate and concurrency limits.",
+                stats.upstream_throttled,
+            ),
+        ] {
+            writeln!(text, "# HELP {METRICS_PREFIX}_{name} {help}").unwrap();
+            writeln!(text, "# TYPE {METRICS_PREFIX}_{name} counter").unwrap();
+            writeln!(text, "{METRICS_PREFIX}_{name} {value}").unwrap();
+        }
+
+        for (name, help, value) in [
+            (
+                "active_workers",
+                "Worker threads currently running.",
+                active_workers,
+            ),
+            (
+                "inflight_index_fetches",
+                "Index fetches currently in progress.",
+                index_fetches,
+            ),
+            (
+                "inflight_crate_fetches",
+                "Crate fetches currently in progress.",
+                crate_fetches,
+            ),
+        ] {
+            writeln!(text, "# HELP {METRICS_PREFIX}_{name} {help}").unwrap();
+            writeln!(text, "# TYPE {METRICS_PREFIX}_{name} gauge").unwrap();
+            writeln!(text, "{METRICS_PREFIX}_{name} {value}").unwrap();
+        }
+
+        if let Some((start, start_time)) = START_TIME.get() {
+            let since_epoch = start_time.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+            for (name, help, value) in [
+                (
+                    "start_time_seconds",
+                    "Server start time since the Unix epoch.",
+                    since_epoch.as_secs_f64(),
+                ),
+                (
+                    "uptime_seconds",
+                    "Time since the server start.",
+                    start.elapsed().as_secs_f64(),
+                ),
+            ] {
+                writeln!(text, "# HELP {METRICS_PREFIX}_{name} {help}").unwrap();
+                writeln!(text, "# TYPE {METRICS_PREFIX}_{name} gauge").unwrap();
+                writeln!(text, "{METRICS_PREFIX}_{name} {value}").unwrap();
+            }
+        }
+
+        if let Some(count) = START_COUNT.get() {
+            let name = format!("{METRICS_PREFIX}_starts_total");
+            writeln!(
+                text,
+                "# HELP {name} Server starts with the same cache directory."
+            )
+            .unwrap();
+            writeln!(text, "# TYPE {name} counter").unwrap();
+            writeln!(text, "{name} {count}").unwrap();
+        }
+
+        let name = format!("{METRICS_PREFIX}_upstream_latency_seconds");
+        writeln!(text, "# HELP {name} Upstream server response latency.").unwrap();
+        writeln!(text, "# TYPE {name} histogram").unwrap();
+        self.upstream_latency.format_samples(&mut text, &name, "");
+
+        let name = format!("{METRICS_PREFIX}_request_duration_seconds");
+        writeln!(text, "# HELP {name} Served request duration.").unwrap();
+        writeln!(text, "# TYPE {name} histogram").unwrap();
+
+        for kind in [RequestKind::Index, RequestKind::Crate] {
+            for (upstream, source) in [(false, "hit"), (true, "upstream")] {
+                let labels = format!("kind=\"{}\",source=\"{source}\"", kind.label());
+                self.request_latency(kind, upstream)
+                    .format_samples(&mut text, &name, &labels);
+            }
+        }
+
+        if let Some(downloads) = CRATE_DOWNLOADS.get() {
+            let name = format!("{METRICS_PREFIX}_crate_downloads_total");
+            writeln!(
+                text,
+                "# HELP {name} Crate downloads of the most downloaded crates."
+            )
+            .unwrap();
+            writeln!(text, "# TYPE {name} counter").unwrap();
+            downloads
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .format_samples(&mut text, &name, CRATE_DOWNLOADS_TOP);
+        }
+
+        text
+    }
+}
+
+impl StatsSnapshot {
+    /// Computes the counter increments since the `earlier` snapshot.
+    #[must_use]
+    pub fn since(&self, earlier: &StatsSnapshot) -> StatsSnapshot {
+        StatsSnapshot {
+            index_hits: self.index_hits.saturating_sub(earlier.index_hits),
+            index_misses: self.index_misses.saturating_sub(earlier.index_misses),
+            crate_hits: self.crate_hits.saturating_sub(earlier.crate_hits),
+            crate_misses: self.crate_misses.saturating_sub(earlier.crate_misses),
+            bytes_served: self.bytes_served.saturating_sub(earlier.bytes_served),
+            bytes_fetched: self.bytes_fetched.saturating_sub(earlier.bytes_fetched),
+            upstream_errors: self.upstream_errors.saturating_sub(earlier.upstream_errors),
+            upstream_requests: self
+                .upstream_requests
+                .saturating_sub(earlier.upstream_requests),
+            upstream_throttled: self
+                .upstream_throttled
+                .saturating_sub(earlier.upstream_throttled),
+        }
+    }
+}
+
+impl Display for StatsSnapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(
+            f,
+            "index hits={} misses={}, crate hits={} misses={}, served={} B, fetched={} B, \
+             upstream errors={} requests={} throttled={}",
+            self.index_hits,
+            self.index_misses,
+            self.crate_hits,
+            self.crate_misses,
+            self.bytes_served,
+            self.bytes_fetched,
+            self.upstream_errors,
+            self.upstream_requests,
+            self.upstream_throttled
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a new set of counters for testing.
+    fn test_stats() -> Stats {
+        Stats {
+            index_hits: AtomicU64::new(3),
+            index_misses: AtomicU64::new(1),
+            crate_hits: AtomicU64::new(0),
+            crate_misses: AtomicU64::new(2),
+            bytes_served: AtomicU64::new(100),
+            bytes_fetched: AtomicU64::new(50),
+            upstream_errors: AtomicU64::new(0),
+            upstream_requests: AtomicU64::new(5),
+            upstream_throttled: AtomicU64::new(1),
+            upstream_latency: LatencyHistogram::new(&UPSTREAM_LATENCY_BUCKETS_MS),
+            request_latency: [const { LatencyHistogram::new(&REQUEST_LATENCY_BUCKETS_MS) }; 4],
+        }
+    }
+
+    #[test]
+    fn test_stats_snapshot() {
+        let stats = test_stats();
+
+        let earlier = stats.snapshot();
+        stats_count(&stats.index_hits, 1);
+        stats_count(&stats.bytes_served, 10);
+
+        let recent = stats.snapshot().since(&earlier);
+        assert_eq!(recent.index_hits, 1);
+        assert_eq!(recent.bytes_served, 10);
+        assert_eq!(recent.crate_misses, 0);
+
+        assert_eq!(
+            earlier.to_string(),
+            "index hits=3 misses=1, crate hits=0 misses=2, served=100 B, fetched=50 B, \
+             upstream errors=0 requests=5 throttled=1"
+        );
+    }
+
+    #[test]
+    fn test_format_metrics() {
+        let stats = test_stats();
+        stats.upstream_latency.observe(Duration::from_millis(100));
+        stats.upstream_latency.observe(Duration::from_secs(20));
+        stats
+            .request_latency(RequestKind::Crate, false)
+            .observe(Duration::from_millis(3));
+
+        let start_time = UNIX_EPOCH + Duration::from_secs(1000);
+        stats_start_init(Instant::now(), start_time, Some(7));
+
+        let text = stats.format_metrics(4, 3, 1);
+
+        assert!(text.contains("# TYPE crates_io_proxy_index_hits_total counter\n"));
+        assert!(text.contains("\ncrates_io_proxy_index_hits_total 3\n"));
+        assert!(text.contains("\ncrates_io_proxy_upstream_requests_total 5\n"));
+        assert!(text.contains("\ncrates_io_proxy_upstream_throttled_total 1\n"));
+        assert!(text.contains("\ncrates_io_proxy_active_workers 4\n"));
+        assert!(text.contains("# TYPE crates_io_proxy_inflight_index_fetches gauge\n"));
+        assert!(text.contains("\ncrates_io_proxy_inflight_crate_fetches 1\n"));
+        assert!(text.contains("\ncrates_io_proxy_upstream_latency_seconds_bucket{le=\"0.05\"} 0\n"));
+        assert!(text.contains("\ncrates_io_proxy_upstream_latency_seconds_bucket{le=\"0.1\"} 1\n"));
+        assert!(text.contains("\ncrates_io_proxy_upstream_latency_seconds_bucket{le=\"10\"} 1\n"));
+        assert!(text.contains("\ncrates_io_proxy_upstream_latency_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("\ncrates_io_proxy_upstream_latency_seconds_sum 20.1\n"));
+        assert!(text.contains("\ncrates_io_proxy_upstream_latency_seconds_count 2\n"));
+        assert!(text.contains("# TYPE crates_io_proxy_request_duration_seconds histogram\n"));
+        assert!(text.contains(
+            "\ncrates_io_proxy_request_duration_seconds_bucket{kind=\"crate\",source=\"hit\",le=\"0.001\"} 0\n"
+        ));
+        assert!(text.contains(
+            "\ncrates_io_proxy_request_duration_seconds_bucket{kind=\"crate\",source=\"hit\",le=\"0.005\"} 1\n"
+        ));
+        assert!(text.contains(
+            "\ncrates_io_proxy_request_duration_seconds_bucket{kind=\"crate\",source=\"hit\",le=\"+Inf\"} 1\n"
+        ));
+        assert!(text.contains(
+            "\ncrates_io_proxy_request_duration_seconds_count{kind=\"crate\",source=\"hit\"} 1\n"
+        ));
+        assert!(text.contains(
+            "\ncrates_io_proxy_request_duration_seconds_count{kind=\"index\",source=\"upstream\"} 0\n"
+        ));
+        assert!(text.contains("\ncrates_io_proxy_start_time_seconds 1000\n"));
+        assert!(text.contains("# TYPE crates_io_proxy_uptime_seconds gauge\n"));
+        assert!(text.contains("\ncrates_io_proxy_starts_total 7\n"));
+    }
+
+    #[test]
+    fn test_crate_downloads() {
+        let mut downloads = CrateDownloads::default();
+
+        for name in ["serde", "syn", "serde", "libc", "other", "serde", "syn"] {
+            downloads.count(name);
+        }
+
+        let mut text = String::new();
+        downloads.format_samples(&mut text, "downloads", 2);
+
+        assert_eq!(
+            text,
+            "downloads{crate=\"serde\"} 3\n\
+             downloads{crate=\"syn\"} 2\n\
+             downloads{crate=\"other\"} 2\n"
+        );
+        assert_eq!(downloads.counts.len(), 4);
+    }
+}
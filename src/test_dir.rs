@@ -0,0 +1,53 @@
+//! Temporary directory helpers for the tests
+
+use std::fs::{create_dir_all, remove_dir_all};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counter making the test directory names unique within the test process
+static TEST_DIR_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Temporary test directory removed with all its contents when dropped
+#[derive(Debug)]
+pub struct TestDir {
+    /// Test directory path
+    path: PathBuf,
+}
+
+impl TestDir {
+    /// Creates a new empty temporary directory unique to the test.
+    #[must_use]
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "crates-io-proxy-{name}-{}-{}",
+            std::process::id(),
+            TEST_DIR_COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        create_dir_all(&path).unwrap();
+
+        TestDir { path }
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        // The tests may have removed the directory already.
+        let _ = remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,131 @@
+//! Upstream TLS client configuration helpers
+
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::path::Path;
+use std::sync::Arc;
+
+use log::info;
+#[cfg(feature = "native-certs")]
+use log::warn;
+use rustls::{ClientConfig, RootCertStore};
+
+/// Loads the default trusted root certificates.
+///
+/// These are the same roots ureq uses by default.
+#[cfg(feature = "native-certs")]
+fn default_root_certs() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+
+    let native_certs = rustls_native_certs::load_native_certs().unwrap_or_else(|e| {
+        warn!("fetch: failed to load the system root certificates: {e}");
+        Vec::new()
+    });
+
+    let (valid_count, invalid_count) = roots.add_parsable_certificates(native_certs);
+
+    if invalid_count > 0 {
+        warn!("fetch: ignored {invalid_count} invalid system root certificates");
+    }
+
+    info!("fetch: loaded {valid_count} system root certificates");
+    roots
+}
+
+/// Loads the default trusted root certificates.
+///
+/// These are the same roots ureq uses by default.
+#[cfg(not(feature = "native-certs"))]
+fn default_root_certs() -> RootCertStore {
+    RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    }
+}
+
+/// Adds the PEM encoded root certificates from the file to the root store.
+///
+/// Returns the number of certificates added.
+fn add_pem_root_certs(roots: &mut RootCertStore, path: &Path) -> Result<usize> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut count = 0;
+
+    for cert in rustls_pemfile::certs(&mut reader) {
+        roots
+            .add(cert?)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        count += 1;
+    }
+
+    if count == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "no PEM certificates found",
+        ));
+    }
+
+    Ok(count)
+}
+
+/// Builds the upstream TLS client configuration trusting the extra
+/// root certificates from the PEM file in addition to the default ones.
+pub fn tls_config_with_ca_cert(path: &Path) -> Result<Arc<ClientConfig>> {
+    let mut roots = default_root_certs();
+    let count = add_pem_root_certs(&mut roots, path)?;
+
+    info!(
+        "fetch: added {count} extra root certificates from {}",
+        path.to_string_lossy()
+    );
+
+    // Use the same crypto provider and protocol versions as ureq does.
+    let config =
+        ClientConfig::builder_with_provider(rustls::crypto::ring::default_provider().into())
+            .with_protocol_versions(&[&rustls::version::TLS12, &rustls::version::TLS13])
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{remove_file, write};
+
+    use super::super::test_dir::TestDir;
+    use super::*;
+
+    /// Self-signed test CA certificate
+    const TEST_CA_CERT: &str = "\
+-----BEGIN CERTIFICATE-----\n\
+MIIBmzCCAUGgAwIBAgIUT52NcC55kTwrccJZSPoqS80JKlMwCgYIKoZIzj0EAwIw\n\
+IjEgMB4GA1UEAwwXY3JhdGVzLWlvLXByb3h5IHRlc3QgQ0EwIBcNMjYxMDE2MTEw\n\
+MjEyWhgPMjEyNjA5MjIxMTAyMTJaMCIxIDAeBgNVBAMMF2NyYXRlcy1pby1wcm94\n\
+eSB0ZXN0IENBMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAE7oNaGzVzc+MMocJt\n\
+Yz5iQFcGEbMzUkWGdY+nnUytuEzDWXQZMDQjyFF759Ucctik7ptCt2MqSaRgV/ZX\n\
+NK51ZKNTMFEwHQYDVR0OBBYEFF7BMg1aEF71CoV0Hq0KTnmMBkPrMB8GA1UdIwQY\n\
+MBaAFF7BMg1aEF71CoV0Hq0KTnmMBkPrMA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZI\n\
+zj0EAwIDSAAwRQIgHs+5YWnOD84/E40cRTz/w97BScg+MGgxvwiw0ATEA+4CIQCh\n\
+YdVw1QwGmMUYY66Pk4DZ1D2mtnzgDttoGhfELcGKxQ==\n\
+-----END CERTIFICATE-----\n\
+";
+
+    #[test]
+    fn test_add_pem_root_certs() {
+        let dir = TestDir::new("ca");
+        let path = dir.join("ca.pem");
+        let mut roots = RootCertStore::empty();
+
+        write(&path, TEST_CA_CERT).unwrap();
+        assert_eq!(add_pem_root_certs(&mut roots, &path).unwrap(), 1);
+        assert_eq!(roots.len(), 1);
+
+        write(&path, "not a certificate\n").unwrap();
+        let error = add_pem_root_certs(&mut roots, &path).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        remove_file(&path).unwrap();
+        let error = add_pem_root_certs(&mut roots, &path).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+}
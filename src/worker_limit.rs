@@ -0,0 +1,150 @@
+//! Worker thread limit helpers
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+/// Number of worker threads currently running
+static ACTIVE_WORKERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Maximum number of worker threads running at once (optional)
+static MAX_WORKERS: OnceLock<usize> = OnceLock::new();
+
+/// Number of index fetch worker threads currently running
+static ACTIVE_INDEX_FETCHES: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of crate fetch worker threads currently running
+static ACTIVE_CRATE_FETCHES: AtomicUsize = AtomicUsize::new(0);
+
+/// Maximum number of index fetch worker threads running at once (optional)
+static MAX_INDEX_FETCHES: OnceLock<usize> = OnceLock::new();
+
+/// Maximum number of crate fetch worker threads running at once (optional)
+static MAX_CRATE_FETCHES: OnceLock<usize> = OnceLock::new();
+
+/// Upstream fetch kind limited separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchKind {
+    /// Registry index entry or config file fetch
+    Index,
+    /// Crate file download
+    Crate,
+}
+
+impl FetchKind {
+    /// Gets the worker thread counter for this fetch kind.
+    fn counter(self) -> &'static AtomicUsize {
+        match self {
+            FetchKind::Index => &ACTIVE_INDEX_FETCHES,
+            FetchKind::Crate => &ACTIVE_CRATE_FETCHES,
+        }
+    }
+
+    /// Gets the worker thread limit for this fetch kind.
+    fn max(self) -> Option<usize> {
+        match self {
+            FetchKind::Index => MAX_INDEX_FETCHES.get().copied(),
+            FetchKind::Crate => MAX_CRATE_FETCHES.get().copied(),
+        }
+    }
+}
+
+impl Display for FetchKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_str(match self {
+            FetchKind::Index => "index",
+            FetchKind::Crate => "crate",
+        })
+    }
+}
+
+/// Enables the worker thread limit.
+pub fn worker_limit_init(max: usize) {
+    MAX_WORKERS.get_or_init(|| max);
+}
+
+/// Enables the worker thread limit for the upstream fetch kind.
+pub fn fetch_limit_init(kind: FetchKind, max: usize) {
+    match kind {
+        FetchKind::Index => MAX_INDEX_FETCHES.get_or_init(|| max),
+        FetchKind::Crate => MAX_CRATE_FETCHES.get_or_init(|| max),
+    };
+}
+
+/// Counts the worker threads currently running.
+pub fn active_workers() -> usize {
+    ACTIVE_WORKERS.load(Ordering::Acquire)
+}
+
+/// Counts the worker threads of the upstream fetch kind currently running.
+pub fn active_fetches(kind: FetchKind) -> usize {
+    kind.counter().load(Ordering::Acquire)
+}
+
+/// Worker thread slot held while the worker thread is running
+#[derive(Debug)]
+pub struct WorkerSlot {
+    /// Worker thread counter the slot was taken from
+    counter: &'static AtomicUsize,
+}
+
+impl WorkerSlot {
+    /// Tries to take a worker thread slot, unless the worker thread limit
+    /// is reached.
+    #[must_use]
+    pub fn acquire() -> Option<Self> {
+        Self::acquire_from(&ACTIVE_WORKERS, MAX_WORKERS.get().copied())
+    }
+
+    /// Tries to take a worker thread slot for the upstream fetch kind,
+    /// unless the fetch kind worker thread limit is reached.
+    #[must_use]
+    pub fn acquire_fetch(kind: FetchKind) -> Option<Self> {
+        Self::acquire_from(kind.counter(), kind.max())
+    }
+
+    /// Tries to take a worker thread slot from the counter given, unless
+    /// `max` worker threads are already running.
+    fn acquire_from(counter: &'static AtomicUsize, max: Option<usize>) -> Option<Self> {
+        counter
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                max.is_none_or(|max| count < max).then_some(count + 1)
+            })
+            .ok()?;
+
+        Some(WorkerSlot { counter })
+    }
+}
+
+impl Drop for WorkerSlot {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worker_slots() {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let first = WorkerSlot::acquire_from(&COUNTER, Some(2));
+        let second = WorkerSlot::acquire_from(&COUNTER, Some(2));
+
+        assert!(first.is_some());
+        assert!(second.is_some());
+        assert!(WorkerSlot::acquire_from(&COUNTER, Some(2)).is_none());
+
+        drop(first);
+        assert!(WorkerSlot::acquire_from(&COUNTER, Some(2)).is_some());
+
+        let unlimited = WorkerSlot::acquire_from(&COUNTER, None);
+        assert_eq!(COUNTER.load(Ordering::Acquire), 2);
+
+        drop(unlimited);
+        drop(second);
+        assert_eq!(COUNTER.load(Ordering::Acquire), 0);
+    }
+}